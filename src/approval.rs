@@ -0,0 +1,67 @@
+use std::process::Stdio;
+use anyhow::{ anyhow, Result, Context };
+use serde_json::json;
+use tokio::process::Command;
+use vault_inject::Shell;
+
+/// Something which must approve the secret paths that we'd like to
+/// access before we fetch anything from them.
+#[derive(Debug,Clone)]
+pub enum Approver {
+    /// POST the paths to this URL as JSON; any 2xx response approves them.
+    Webhook(url::Url),
+    /// Run this shell command with the paths in '$VAULT_INJECT_PATHS' (one
+    /// per line); a zero exit code approves them. The command inherits our
+    /// stdin/stdout/stderr so that it can prompt somebody if it needs to.
+    Command(String)
+}
+
+impl Approver {
+
+    /// Ask for approval to access the paths given, returning an error if
+    /// they were not approved. Approval commands are run via the shell given.
+    pub async fn approve(&self, paths: &[String], command: Option<&str>, shell: &Shell) -> Result<()> {
+        match self {
+            Approver::Webhook(url) => approve_via_webhook(url, paths, command).await,
+            Approver::Command(cmd) => approve_via_command(cmd, paths, shell).await
+        }
+    }
+
+}
+
+async fn approve_via_webhook(url: &url::Url, paths: &[String], command: Option<&str>) -> Result<()> {
+    let body = json!({
+        "paths": paths,
+        "command": command
+    });
+    let res = reqwest::Client::new()
+        .post(url.clone())
+        .json(&body)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach the approval webhook '{}'", url))?;
+
+    let status = res.status();
+    if !status.is_success() {
+        let reason = res.text().await.unwrap_or_default();
+        return Err(anyhow!("Access to the secrets was not approved by the webhook '{}' ({}): {}"
+            , url, status, reason.trim()));
+    }
+    Ok(())
+}
+
+async fn approve_via_command(cmd: &str, paths: &[String], shell: &Shell) -> Result<()> {
+    let status = Command::from(shell.command(cmd))
+        .env("VAULT_INJECT_PATHS", paths.join("\n"))
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .await
+        .with_context(|| format!("Failed to run the approval command '{}'", cmd))?;
+
+    if !status.success() {
+        return Err(anyhow!("Access to the secrets was not approved by the command '{}' ({})", cmd, status));
+    }
+    Ok(())
+}
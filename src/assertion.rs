@@ -0,0 +1,128 @@
+use std::fmt;
+use std::str::FromStr;
+use regex::Regex;
+use anyhow::{ anyhow, Result };
+
+/// A check that a secret must pass (after any processors have run) before
+/// it's handed out. This is written at the end of a mapping like
+/// '| @assert:url', or '| @assert:/^[a-z]+$/' to match a regex.
+#[derive(Debug,Clone)]
+pub enum Assertion {
+    /// One of the named formats below.
+    Format(Format),
+    /// A regex that the secret must match.
+    Regex(Box<Regex>)
+}
+
+/// Well known formats that a secret can be asserted to have.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Format {
+    Url,
+    Uuid,
+    Base64,
+    Hex,
+    Number
+}
+
+static FORMATS: &[(&str, Format)] = &[
+    ("url", Format::Url),
+    ("uuid", Format::Uuid),
+    ("base64", Format::Base64),
+    ("hex", Format::Hex),
+    ("number", Format::Number),
+];
+
+impl Assertion {
+
+    /// Does the secret given pass this assertion?
+    pub fn check(&self, secret: &str) -> bool {
+        match self {
+            Assertion::Regex(re) => re.is_match(secret),
+            Assertion::Format(Format::Url) => url::Url::parse(secret).is_ok(),
+            Assertion::Format(Format::Uuid) => {
+                let lengths: Vec<usize> = secret.split('-').map(|s| s.len()).collect();
+                lengths == [8, 4, 4, 4, 12] && secret.chars().all(|c| c == '-' || c.is_ascii_hexdigit())
+            },
+            Assertion::Format(Format::Base64) => !secret.is_empty() && base64::decode(secret).is_ok(),
+            Assertion::Format(Format::Hex) => !secret.is_empty() && secret.chars().all(|c| c.is_ascii_hexdigit()),
+            Assertion::Format(Format::Number) => secret.parse::<f64>().is_ok()
+        }
+    }
+
+}
+
+impl fmt::Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Assertion::Regex(re) => write!(f, "@assert:/{}/", re.as_str()),
+            Assertion::Format(format) => {
+                let name = FORMATS.iter().find(|(_, f)| f == format).map(|(n, _)| *n).unwrap_or_default();
+                write!(f, "@assert:{}", name)
+            }
+        }
+    }
+}
+
+impl FromStr for Assertion {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Assertion> {
+        let s = s.trim();
+        let arg = s.strip_prefix("@assert:")
+            .ok_or_else(|| anyhow!("Expected an assertion like '@assert:url' or '@assert:/^regex$/' but got '{}'", s))?
+            .trim();
+
+        if arg.len() > 1 && arg.starts_with('/') && arg.ends_with('/') {
+            let re = Regex::new(&arg[1..arg.len()-1])
+                .map_err(|e| anyhow!("Invalid regex '{}': {}", arg, e))?;
+            return Ok(Assertion::Regex(Box::new(re)));
+        }
+
+        FORMATS.iter()
+            .find(|(name, _)| *name == arg)
+            .map(|&(_, format)| Assertion::Format(format))
+            .ok_or_else(|| {
+                let names: Vec<&str> = FORMATS.iter().map(|(n, _)| *n).collect();
+                anyhow!("'{}' is not a known format to assert (try one of {}, or a regex like '/^[a-z]+$/')", arg, names.join(", "))
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn check_assertions() {
+
+        let cases = vec![
+            ("@assert:url", "https://example.com/foo", true),
+            ("@assert:url", "CHANGEME", false),
+            ("@assert:uuid", "6f1c2b3a-9d4e-4f5a-8b6c-7d8e9f0a1b2c", true),
+            ("@assert:uuid", "6f1c2b3a", false),
+            ("@assert:base64", "aHVudGVyMg==", true),
+            ("@assert:base64", "not base64!", false),
+            ("@assert:hex", "deadBEEF", true),
+            ("@assert:hex", "", false),
+            ("@assert:number", "42", true),
+            ("@assert:number", "forty two", false),
+            ("@assert: /^[A-Za-z0-9+/=]{8,}$/", "aHVudGVyMg==", true),
+            ("@assert:/^[A-Za-z0-9+/=]{8,}$/", "CHANGEME!", false),
+            ("@assert:/^(foo|bar)$/", "bar", true),
+        ];
+
+        for (assertion_str, secret, expected) in cases {
+            let assertion = Assertion::from_str(assertion_str).unwrap();
+            assert_eq!(assertion.check(secret), expected, "Unexpected result checking '{}' against '{}'", secret, assertion_str);
+        }
+
+    }
+
+    #[test]
+    fn invalid_assertions() {
+        for s in &["@assert:nope", "@assert:/[a-z/", "@nope:url"] {
+            assert!(Assertion::from_str(s).is_err(), "Did not expect '{}' to be a valid assertion", s);
+        }
+    }
+
+}
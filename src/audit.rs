@@ -0,0 +1,98 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+use anyhow::{ Result, Context };
+use serde::Serialize;
+use serde_json::Value;
+use vault_inject::client::Client;
+use vault_inject::duration;
+
+/// What we record about each run in the audit log: who read which secrets,
+/// when, and what for. This never contains any secret values.
+#[derive(Debug,Clone,Serialize)]
+pub struct AuditRecord {
+    pub time: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub vault_url: String,
+    pub vault_identity: Option<String>,
+    pub paths: Vec<String>,
+    pub command: Option<String>
+}
+
+impl AuditRecord {
+
+    /// A record of reading the secret paths given (for the command given) now.
+    pub async fn new(client: &Client, vault_url: &url::Url, paths: Vec<String>, command: Option<String>) -> AuditRecord {
+        AuditRecord {
+            time: duration::utc_timestamp(SystemTime::now()),
+            user: local_user(),
+            host: local_host(),
+            vault_url: vault_url.to_string(),
+            vault_identity: vault_identity(client).await,
+            paths,
+            command
+        }
+    }
+
+    /// Append this record to the audit log as a single line of JSON. The line is
+    /// written in one go, so records from runs at the same time aren't interleaved.
+    pub fn append_to(&self, path: &Path) -> Result<()> {
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+        let mut options = std::fs::OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("Failed to open the audit log '{}'", path.display()))?;
+        file.write_all(line.as_bytes())
+            .with_context(|| format!("Failed to write to the audit log '{}'", path.display()))
+    }
+
+}
+
+/// The name of the user that we're running as.
+#[cfg(unix)]
+fn local_user() -> Option<String> {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .ok()
+        // Safety: getuid is always successful.
+        .or_else(|| Some(format!("uid:{}", unsafe { libc::getuid() })))
+}
+
+#[cfg(not(unix))]
+fn local_user() -> Option<String> {
+    std::env::var("USERNAME").ok()
+}
+
+#[cfg(unix)]
+fn local_host() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // Safety: the buffer is valid for its length, which we leave room to null terminate.
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len() - 1) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(not(unix))]
+fn local_host() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// Who Vault thinks we are: the display name of our token, and its entity if it has one.
+async fn vault_identity(client: &Client) -> Option<String> {
+    let res: Value = client.get("auth/token/lookup-self").await.ok()?;
+    let display_name = res["data"]["display_name"].as_str()?;
+    match res["data"]["entity_id"].as_str() {
+        Some(entity_id) if !entity_id.is_empty() => Some(format!("{} (entity {})", display_name, entity_id)),
+        _ => Some(display_name.to_owned())
+    }
+}
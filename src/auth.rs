@@ -1,13 +1,34 @@
 use anyhow::{ anyhow, Result, Context };
 use serde_json::{ Value, json };
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::str::FromStr;
-use tokio::io::{ self, AsyncWriteExt, AsyncBufReadExt };
-use tokio::task;
+use std::time::{ Duration, SystemTime };
+use tokio::io::{ self, AsyncReadExt, AsyncWriteExt, AsyncBufReadExt };
+use tokio::net::TcpListener;
+use tokio::{ task, time };
+use ring::rand::{ SecureRandom, SystemRandom };
+use crate::aws;
 use crate::client::Client;
 
 pub struct Auth {
     // Client to make requests with:
-    client: Client
+    client: Client,
+    // The passcode to give if logging in needs MFA (rather than prompting for one):
+    mfa_passcode: Option<String>
+}
+
+/// A token that we've logged in with, and its lease if we know about it.
+pub struct LoginToken {
+    pub token: String,
+    pub lease: Option<TokenLease>
+}
+
+/// How long a token lasts for (a TTL of zero means forever), and whether it can be renewed.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct TokenLease {
+    pub ttl: Duration,
+    pub renewable: bool
 }
 
 impl Auth {
@@ -15,100 +36,485 @@ impl Auth {
     /// Create a new Auth instance that knows about the
     /// available auth capabilities
     pub fn new(client: Client) -> Auth {
-        Auth { client }
+        Auth { client, mfa_passcode: None }
+    }
+
+    /// Give this passcode (eg a TOTP code) if logging in needs MFA, rather than prompting for one.
+    pub fn with_mfa_passcode(mut self, passcode: String) -> Auth {
+        self.mfa_passcode = Some(passcode);
+        self
     }
 
     /// Check the validity of a token
     pub async fn is_token_valid(&self, token: &str) -> bool {
+        matches!(self.lookup_token(token).await, Some(lease) if lease.ttl > MIN_TOKEN_TTL)
+    }
+
+    /// Find out how long a token has left, and whether it can be renewed.
+    pub async fn lookup_token(&self, token: &str) -> Option<TokenLease> {
+        let c = self.client.with_token(token.to_owned());
+        let res: Value = c.get("/auth/token/lookup-self").await.ok()?;
+        Some(TokenLease {
+            ttl: Duration::from_secs(res["data"]["ttl"].as_u64()?),
+            renewable: res["data"]["renewable"].as_bool().unwrap_or(false)
+        })
+    }
+
+    /// Renew a token, extending its lease (up to the maximum TTL that it's allowed).
+    pub async fn renew_token(&self, token: &str) -> Result<LoginToken> {
+        let c = self.client.with_token(token.to_owned());
+        let res: Value = c.post("/auth/token/renew-self", &json!({}))
+            .await
+            .context("Could not renew the Vault token")?;
+        login_token(&res, "token renewal")
+    }
+
+    /// Revoke a token (and any child tokens and leases that it has created).
+    pub async fn revoke_token(&self, token: &str) -> Result<()> {
         let c = self.client.with_token(token.to_owned());
-        let res: Value = c.get("/auth/token/lookup-self").await.unwrap_or(Value::Null);
-        let ttl = res["data"]["ttl"].as_i64().unwrap_or(0);
-        ttl > 120
+        c.post::<Value,_,_>("/auth/token/revoke-self", &json!({}))
+            .await
+            .context("Could not revoke the Vault token")?;
+        Ok(())
     }
 
     /// Authenticate a user given the AuthDetails provided and return a token
-    pub async fn login(&self, opts: AuthDetails) -> Result<String> {
+    pub async fn login(&self, opts: AuthDetails) -> Result<LoginToken> {
         match opts {
-            AuthDetails::Ldap { path, mut username, mut password } => {
-                if username.is_empty() {
-                    username = prompt_for_input("Please enter Vault LDAP username: ").await?;
-                }
-                if password.is_empty() {
-                    password = prompt_for_hidden_input("Please enter Vault LDAP password: ").await?;
-                }
+            AuthDetails::Ldap { path, username, password } => {
                 let path = path.unwrap_or_else(|| "ldap".to_owned());
-                self.login_ldap(&path, &username, &password).await
+                self.login_password(PasswordLogin::LDAP, &path, username, password).await
+            },
+            AuthDetails::UserPass { path, username, password } => {
+                let path = path.unwrap_or_else(|| "userpass".to_owned());
+                self.login_password(PasswordLogin::USERPASS, &path, username, password).await
+            },
+            AuthDetails::Okta { path, username, password } => {
+                let path = path.unwrap_or_else(|| "okta".to_owned());
+                self.login_password(PasswordLogin::OKTA, &path, username, password).await
+            },
+            AuthDetails::Radius { path, username, password } => {
+                let path = path.unwrap_or_else(|| "radius".to_owned());
+                self.login_password(PasswordLogin::RADIUS, &path, username, password).await
+            },
+            AuthDetails::Oidc { path, role, port } => {
+                let path = path.unwrap_or_else(|| "oidc".to_owned());
+                self.login_oidc(&path, role.as_deref(), port).await
             },
-            AuthDetails::UserPass { path, mut username, mut password } => {
-                if username.is_empty() {
-                    username = prompt_for_input("Please enter Vault username: ").await?;
+            AuthDetails::Aws { path, role } => {
+                let path = path.unwrap_or_else(|| "aws".to_owned());
+                self.login_aws(&path, role.as_deref()).await
+            },
+            AuthDetails::Github { path, mut token } => {
+                if token.is_empty() {
+                    token = prompt_for_hidden_input("Please enter GitHub personal access token: ").await?;
+                }
+                let path = path.unwrap_or_else(|| "github".to_owned());
+                self.login_github(&path, &token).await
+            },
+            AuthDetails::Cert { path, role } => {
+                let path = path.unwrap_or_else(|| "cert".to_owned());
+                self.login_cert(&path, role.as_deref()).await
+            },
+            AuthDetails::Jwt { path, role, mut jwt, jwt_file } => {
+                if let (true, Some(jwt_file)) = (jwt.is_empty(), &jwt_file) {
+                    jwt = tokio::fs::read_to_string(jwt_file)
+                        .await
+                        .with_context(|| format!("Failed to read the JWT from '{}'", jwt_file.display()))?;
                 }
-                if password.is_empty() {
-                    password = prompt_for_hidden_input("Please enter Vault password: ").await?;
+                if jwt.is_empty() {
+                    jwt = prompt_for_hidden_input("Please enter JWT: ").await?;
                 }
-                let path = path.unwrap_or_else(|| "userpass".to_owned());
-                self.login_userpass(&path, &username, &password).await
+                let path = path.unwrap_or_else(|| "jwt".to_owned());
+                self.login_jwt(&path, role.as_deref(), jwt.trim()).await
             },
             AuthDetails::Token { mut token } => {
                 if token.is_empty() {
                     token = prompt_for_hidden_input("Please enter Vault token: ").await?;
                 }
-                Ok(token)
+                Ok(LoginToken { token, lease: None })
             }
         }
     }
 
-    /// Login via LDAP (if configured in Vault)
-    async fn login_ldap(&self, mount_path: &str, username: &str, password: &str)  -> Result<String> {
+    /// Login via one of the auth methods which takes a username and password
+    /// (if configured in Vault), prompting for them if they aren't provided.
+    async fn login_password(&self, kind: PasswordLogin, mount_path: &str, mut username: String, mut password: String) -> Result<LoginToken> {
+        if username.is_empty() {
+            username = prompt_for_input(&format!("Please enter {} username: ", kind.prompt)).await?;
+        }
+        if password.is_empty() {
+            password = prompt_for_hidden_input(&format!("Please enter {} password: ", kind.prompt)).await?;
+        }
+
         let auth_path = format!("auth/{mount}/login/{username}"
             , mount = mount_path.trim_matches('/')
             , username = username );
 
         let res: Value = self.client.post(auth_path, &json!({ "password": password }))
             .await
-            .with_context(|| format!("Could not complete LDAP login request to vault API"))?;
+            .with_context(|| format!("Could not complete {} login request to vault API", kind.name))?;
+
+        self.complete_login(res, &format!("{} login", kind.name)).await
+    }
+
+    /// Login via OIDC (if configured in Vault). We ask Vault for the provider's
+    /// URL, open it in a browser, and wait for the provider to redirect back to
+    /// a local listener with the code that Vault exchanges for a token.
+    async fn login_oidc(&self, mount_path: &str, role: Option<&str>, port: u16) -> Result<LoginToken> {
+        let mount_path = mount_path.trim_matches('/');
+        let redirect_uri = format!("http://{}:{}/oidc/callback", OIDC_CALLBACK_HOST, port);
+        let mut listener = TcpListener::bind((OIDC_CALLBACK_HOST, port))
+            .await
+            .with_context(|| format!("Failed to listen for the OIDC callback on port {}", port))?;
 
-        let token = res["auth"]["client_token"]
+        let client_nonce = random_hex(16)?;
+        let auth_url_path = format!("auth/{mount}/oidc/auth_url", mount = mount_path);
+        let res: Value = self.client.post(auth_url_path, &json!({
+                "role": role.unwrap_or_default(),
+                "redirect_uri": redirect_uri,
+                "client_nonce": client_nonce
+            }))
+            .await
+            .context("Could not obtain the OIDC provider URL from vault API")?;
+        let auth_url = res["data"]["auth_url"]
             .as_str()
-            .ok_or_else(|| anyhow!("Could not find the client token in the LDAP login response"))?;
-        Ok(token.to_string())
+            .filter(|url| !url.is_empty())
+            .ok_or_else(|| anyhow!("Could not find the OIDC provider URL in the response (is the role and redirect URI '{}' allowed?)", redirect_uri))?;
+
+        eprintln!("Complete the login in your browser. If it doesn't open, visit:\n\n    {}\n", auth_url);
+        open_in_browser(auth_url);
+
+        let query = time::timeout(OIDC_CALLBACK_TIMEOUT, wait_for_oidc_callback(&mut listener))
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for the OIDC login to complete in the browser"))??;
+
+        let callback_path = format!("auth/{mount}/oidc/callback?{query}&client_nonce={nonce}"
+            , mount = mount_path
+            , query = query
+            , nonce = client_nonce );
+        let res: Value = self.client.get(callback_path)
+            .await
+            .context("Could not complete OIDC login request to vault API")?;
+
+        self.complete_login(res, "OIDC login").await
     }
 
-    /// Login via Username-Password (if configured in Vault)
-    async fn login_userpass(&self, mount_path: &str, username: &str, password: &str)  -> Result<String> {
-        let auth_path = format!("auth/{mount}/login/{username}"
-            , mount = mount_path.trim_matches('/')
-            , username = username );
+    /// Login via AWS IAM (if configured in Vault). We sign an sts:GetCallerIdentity
+    /// request using whatever AWS credentials we can find, and Vault makes that
+    /// request to find out who we are.
+    async fn login_aws(&self, mount_path: &str, role: Option<&str>) -> Result<LoginToken> {
+        let auth_path = format!("auth/{mount}/login", mount = mount_path.trim_matches('/'));
 
-        let res: Value = self.client.post(auth_path, &json!({ "password": password }))
+        let creds = aws::Credentials::find().await?;
+        let req = creds.sign_get_caller_identity(SystemTime::now());
+        let headers: HashMap<String,Vec<&str>> = req.headers
+            .iter()
+            .map(|(k, v)| (canonical_header_name(k), vec![&**v]))
+            .collect();
+
+        let mut body = json!({
+            "iam_http_request_method": req.method,
+            "iam_request_url": base64::encode(req.url),
+            "iam_request_body": base64::encode(req.body),
+            "iam_request_headers": base64::encode(serde_json::to_string(&headers)?)
+        });
+        if let Some(role) = role {
+            body["role"] = json!(role);
+        }
+
+        let res: Value = self.client.post(auth_path, &body)
+            .await
+            .context("Could not complete AWS login request to vault API")?;
+
+        self.complete_login(res, "AWS login").await
+    }
+
+    /// Login via a GitHub personal access token (if configured in Vault)
+    async fn login_github(&self, mount_path: &str, token: &str) -> Result<LoginToken> {
+        let auth_path = format!("auth/{mount}/login", mount = mount_path.trim_matches('/'));
+
+        let res: Value = self.client.post(auth_path, &json!({ "token": token }))
+            .await
+            .context("Could not complete GitHub login request to vault API")?;
+
+        self.complete_login(res, "GitHub login").await
+    }
+
+    /// Login via a signed JWT, eg from a CI provider (if configured in Vault)
+    async fn login_jwt(&self, mount_path: &str, role: Option<&str>, jwt: &str) -> Result<LoginToken> {
+        let auth_path = format!("auth/{mount}/login", mount = mount_path.trim_matches('/'));
+
+        let mut body = json!({ "jwt": jwt });
+        if let Some(role) = role {
+            body["role"] = json!(role);
+        }
+
+        let res: Value = self.client.post(auth_path, &body)
             .await
-            .with_context(|| format!("Could not complete Username-Password login request to vault API"))?;
+            .context("Could not complete JWT login request to vault API")?;
 
-        let token = res["auth"]["client_token"]
+        self.complete_login(res, "JWT login").await
+    }
+
+    /// Login via the TLS client certificate that the client was configured
+    /// with (if configured in Vault)
+    async fn login_cert(&self, mount_path: &str, role: Option<&str>) -> Result<LoginToken> {
+        let auth_path = format!("auth/{mount}/login", mount = mount_path.trim_matches('/'));
+
+        let res: Value = self.client.post(auth_path, &json!({ "name": role.unwrap_or_default() }))
+            .await
+            .context("Could not complete certificate login request to vault API")?;
+
+        self.complete_login(res, "certificate login").await
+    }
+
+}
+
+impl Auth {
+
+    /// Find the token in the response to a login request. If Vault requires MFA to
+    /// complete the login, the response asks for it instead, so we satisfy each MFA
+    /// constraint (with a passcode, or by waiting for a push to be approved) first.
+    async fn complete_login(&self, res: Value, name: &str) -> Result<LoginToken> {
+        let requirement = &res["auth"]["mfa_requirement"];
+        if requirement.is_null() {
+            return login_token(&res, name);
+        }
+        let request_id = requirement["mfa_request_id"]
             .as_str()
-            .ok_or_else(|| anyhow!("Could not find the client token in the Username-Password login response"))?;
-        Ok(token.to_string())
+            .ok_or_else(|| anyhow!("Could not find the MFA request ID in the {} response", name))?;
+
+        let mut payload = serde_json::Map::new();
+        for method in mfa_methods(requirement)? {
+            let passcodes = if method.uses_passcode {
+                let passcode = match &self.mfa_passcode {
+                    Some(passcode) => passcode.clone(),
+                    None => prompt_for_hidden_input(&format!("Please enter the {} passcode for '{}': ", method.method_type, method.constraint)).await?
+                };
+                vec![passcode.trim().to_owned()]
+            } else {
+                // Vault waits for push notifications (eg from Duo) to be approved:
+                eprintln!("Approve the {} login request for '{}' to continue", method.method_type, method.constraint);
+                Vec::new()
+            };
+            payload.insert(method.id, json!(passcodes));
+        }
+
+        let res: Value = self.client.post("sys/mfa/validate", &json!({ "mfa_request_id": request_id, "mfa_payload": payload }))
+            .await
+            .with_context(|| format!("Could not complete MFA for the {}", name))?;
+        login_token(&res, name)
     }
 
 }
 
+/// An MFA method that we use to satisfy one of the MFA constraints on a login.
+#[derive(Debug,Clone,PartialEq,Eq)]
+struct MfaMethod {
+    constraint: String,
+    id: String,
+    method_type: String,
+    uses_passcode: bool
+}
+
+/// The MFA methods to use to satisfy each constraint in an MFA requirement. Any
+/// of the methods for a constraint will do; we prefer those that use a passcode,
+/// since they don't need anything else to happen.
+fn mfa_methods(requirement: &Value) -> Result<Vec<MfaMethod>> {
+    let constraints = requirement["mfa_constraints"]
+        .as_object()
+        .ok_or_else(|| anyhow!("Could not find the MFA constraints to satisfy"))?;
+    let mut constraint_names: Vec<&String> = constraints.keys().collect();
+    constraint_names.sort();
+    constraint_names.into_iter().map(|constraint| {
+        let methods = constraints[constraint]["any"].as_array().map(|m| &**m).unwrap_or_default();
+        let method = methods.iter()
+            .find(|m| m["uses_passcode"].as_bool() == Some(true))
+            .or_else(|| methods.first())
+            .ok_or_else(|| anyhow!("Could not find a way to satisfy the MFA constraint '{}'", constraint))?;
+        Ok(MfaMethod {
+            constraint: constraint.clone(),
+            id: method["id"].as_str()
+                .ok_or_else(|| anyhow!("Could not find the ID of the MFA method for '{}'", constraint))?
+                .to_owned(),
+            method_type: method["type"].as_str().unwrap_or("MFA").to_owned(),
+            uses_passcode: method["uses_passcode"].as_bool().unwrap_or(false)
+        })
+    }).collect()
+}
+
+/// Tokens with less than this long left aren't worth using.
+const MIN_TOKEN_TTL: Duration = Duration::from_secs(120);
+
+/// Find the token (and its lease) in the response to a login (or token renewal) request.
+fn login_token(res: &Value, name: &str) -> Result<LoginToken> {
+    let auth = &res["auth"];
+    let token = auth["client_token"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Could not find the client token in the {} response", name))?;
+    let lease = auth["lease_duration"].as_u64().map(|secs| TokenLease {
+        ttl: Duration::from_secs(secs),
+        renewable: auth["renewable"].as_bool().unwrap_or(false)
+    });
+    Ok(LoginToken { token: token.to_owned(), lease })
+}
+
 /// The details we need for each auth type in order to get a token
 #[derive(PartialEq,Eq,Clone)]
 pub enum AuthDetails {
     Ldap { path: Option<String>, username: String, password: String },
     UserPass { path: Option<String>, username: String, password: String },
+    Okta { path: Option<String>, username: String, password: String },
+    Radius { path: Option<String>, username: String, password: String },
+    Oidc { path: Option<String>, role: Option<String>, port: u16 },
+    Aws { path: Option<String>, role: Option<String> },
+    Github { path: Option<String>, token: String },
+    Cert { path: Option<String>, role: Option<String> },
+    Jwt { path: Option<String>, role: Option<String>, jwt: String, jwt_file: Option<PathBuf> },
     Token { token: String }
 }
 
+impl AuthDetails {
+
+    /// The type of authentication that these details are for.
+    pub fn auth_type(&self) -> AuthType {
+        match self {
+            AuthDetails::Ldap { .. } => AuthType::Ldap,
+            AuthDetails::UserPass { .. } => AuthType::UserPass,
+            AuthDetails::Okta { .. } => AuthType::Okta,
+            AuthDetails::Radius { .. } => AuthType::Radius,
+            AuthDetails::Oidc { .. } => AuthType::Oidc,
+            AuthDetails::Aws { .. } => AuthType::Aws,
+            AuthDetails::Github { .. } => AuthType::Github,
+            AuthDetails::Cert { .. } => AuthType::Cert,
+            AuthDetails::Jwt { .. } => AuthType::Jwt,
+            AuthDetails::Token { .. } => AuthType::Token
+        }
+    }
+
+    /// Can we login with these details without prompting for anything?
+    pub fn is_complete(&self) -> bool {
+        match self {
+            AuthDetails::Ldap { username, password, .. } |
+            AuthDetails::UserPass { username, password, .. } |
+            AuthDetails::Okta { username, password, .. } |
+            AuthDetails::Radius { username, password, .. } => !username.is_empty() && !password.is_empty(),
+            AuthDetails::Github { token, .. } |
+            AuthDetails::Token { token } => !token.is_empty(),
+            AuthDetails::Jwt { jwt, jwt_file, .. } => !jwt.is_empty() || jwt_file.is_some(),
+            // The AWS credentials are found when logging in, and
+            // the client certificate is provided by the client:
+            AuthDetails::Aws { .. } |
+            AuthDetails::Cert { .. } => true,
+            // This always needs somebody to login via their browser:
+            AuthDetails::Oidc { .. } => false
+        }
+    }
+
+}
+
+/// How to describe each of the auth methods that takes a username and password.
+struct PasswordLogin {
+    name: &'static str,
+    prompt: &'static str
+}
+
+impl PasswordLogin {
+    const LDAP: PasswordLogin = PasswordLogin { name: "LDAP", prompt: "Vault LDAP" };
+    const USERPASS: PasswordLogin = PasswordLogin { name: "Username-Password", prompt: "Vault" };
+    const OKTA: PasswordLogin = PasswordLogin { name: "Okta", prompt: "Okta" };
+    const RADIUS: PasswordLogin = PasswordLogin { name: "RADIUS", prompt: "RADIUS" };
+}
+
+/// The host that we listen for the OIDC callback on. This is named in the
+/// redirect URI too, so it must match what's allowed by the role in Vault.
+const OIDC_CALLBACK_HOST: &str = "localhost";
+
+/// How long to wait for somebody to login via their browser.
+const OIDC_CALLBACK_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Wait for the OIDC provider to redirect the browser to our listener,
+/// returning the query string that it was given.
+async fn wait_for_oidc_callback(listener: &mut TcpListener) -> Result<String> {
+    loop {
+        let (mut socket, _) = listener.accept()
+            .await
+            .context("Failed to accept the OIDC callback")?;
+
+        // We only need the request line, eg 'GET /oidc/callback?code=.. HTTP/1.1':
+        let mut buf = vec![0u8; 8192];
+        let n = socket.read(&mut buf).await.unwrap_or(0);
+        let request = String::from_utf8_lossy(&buf[..n]);
+        let target = request.split_whitespace().nth(1).unwrap_or_default();
+        let (path, query) = match target.find('?') {
+            Some(idx) => (&target[..idx], &target[idx+1..]),
+            None => (target, "")
+        };
+
+        if path != "/oidc/callback" || query.is_empty() {
+            let _ = socket.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+            continue
+        }
+        let body = "Login complete; you can close this window.";
+        let res = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}"
+            , body.len(), body);
+        let _ = socket.write_all(res.as_bytes()).await;
+        return Ok(query.to_owned())
+    }
+}
+
+/// Try to open a URL in the user's browser. It's fine if this doesn't work,
+/// since we print the URL out as well.
+fn open_in_browser(url: &str) {
+    let mut opener = if cfg!(windows) {
+        let mut cmd = std::process::Command::new("cmd");
+        cmd.args(["/C", "start", ""]);
+        cmd
+    } else if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    let _ = opener
+        .arg(url)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn();
+}
+
+/// Header names like 'Content-Type' rather than 'content-type'.
+fn canonical_header_name(name: &str) -> String {
+    name.split('-')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(c) => c.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Some random bytes, hex encoded.
+fn random_hex(num_bytes: usize) -> Result<String> {
+    let mut bytes = vec![0u8; num_bytes];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| anyhow!("Failed to generate random bytes"))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
 /// Prompt for input from stdin
 async fn prompt_for_input(msg: &str) -> Result<String> {
     io::stderr().write_all(msg.as_bytes())
         .await
-        .with_context(|| format!("Could not write to stdout"))?;
+        .context("Could not write to stdout")?;
     let mut username = String::new();
     io::BufReader::new(io::stdin()).read_line(&mut username)
         .await
-        .with_context(|| format!("Failed to read username from stdin"))?;
+        .context("Failed to read username from stdin")?;
     Ok(username)
 }
 
@@ -117,7 +523,7 @@ async fn prompt_for_hidden_input(msg: &str) -> Result<String> {
     let msg = msg.to_owned();
     task::spawn_blocking(move || {
         rpassword::prompt_password_stderr(&msg)
-            .with_context(|| format!("Failed to read password from stdin"))
+            .context("Failed to read password from stdin")
     }).await?
 }
 
@@ -126,22 +532,85 @@ async fn prompt_for_hidden_input(msg: &str) -> Result<String> {
 pub enum AuthType {
     Ldap,
     UserPass,
+    Okta,
+    Radius,
+    Oidc,
+    Aws,
+    Github,
+    Cert,
+    Jwt,
     Token
 }
 
+impl std::fmt::Display for AuthType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            AuthType::Ldap => "ldap",
+            AuthType::UserPass => "userpass",
+            AuthType::Okta => "okta",
+            AuthType::Radius => "radius",
+            AuthType::Oidc => "oidc",
+            AuthType::Aws => "aws",
+            AuthType::Github => "github",
+            AuthType::Cert => "cert",
+            AuthType::Jwt => "jwt",
+            AuthType::Token => "token"
+        };
+        write!(f, "{}", s)
+    }
+}
+
 // How to convert a string into the desired auth type
 impl FromStr for AuthType {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_ascii_lowercase().as_str() {
             "ldap" => Ok(AuthType::Ldap),
+            "aws" => Ok(AuthType::Aws),
+            "github" => Ok(AuthType::Github),
+            "cert" => Ok(AuthType::Cert),
+            "jwt" => Ok(AuthType::Jwt),
+            "oidc" => Ok(AuthType::Oidc),
+            "okta" => Ok(AuthType::Okta),
+            "radius" => Ok(AuthType::Radius),
             "token" => Ok(AuthType::Token),
             "userpass" |
             "user-pass" |
             "username-password" |
             "username" |
             "user" => Ok(AuthType::UserPass),
-            _ => Err(anyhow!("'{}' is not a valid authentication type (try 'ldap', 'token' or 'userpass').", s))
+            _ => Err(anyhow!("'{}' is not a valid authentication type (try 'aws', 'cert', 'github', 'jwt', 'ldap', 'oidc', 'okta', 'radius', 'token' or 'userpass').", s))
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn find_mfa_methods() {
+        let method = |constraint: &str, id: &str, method_type: &str, uses_passcode| MfaMethod {
+            constraint: constraint.to_owned(), id: id.to_owned(), method_type: method_type.to_owned(), uses_passcode
+        };
+        let cases = vec![
+            (json!({ "mfa_request_id": "r1", "mfa_constraints": { "corp-totp": { "any": [
+                { "type": "totp", "id": "m1", "uses_passcode": true }
+            ] } } }), Some(vec![method("corp-totp", "m1", "totp", true)])),
+            // Methods which need a passcode are preferred, and each constraint is satisfied:
+            (json!({ "mfa_request_id": "r1", "mfa_constraints": {
+                "b-duo": { "any": [ { "type": "duo", "id": "m2", "uses_passcode": false } ] },
+                "a-either": { "any": [
+                    { "type": "duo", "id": "m3", "uses_passcode": false },
+                    { "type": "totp", "id": "m4", "uses_passcode": true }
+                ] }
+            } }), Some(vec![method("a-either", "m4", "totp", true), method("b-duo", "m2", "duo", false)])),
+            (json!({ "mfa_request_id": "r1", "mfa_constraints": { "empty": { "any": [] } } }), None),
+            (json!({ "mfa_request_id": "r1" }), None),
+        ];
+        for (requirement, expected) in cases {
+            assert_eq!(mfa_methods(&requirement).ok(), expected, "Unexpected MFA methods for {}", requirement);
+        }
+    }
+
+}
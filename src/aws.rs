@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::time::{ Duration, SystemTime };
+use anyhow::{ anyhow, Result, Context };
+use directories::BaseDirs;
+use ring::{ digest, hmac };
+use serde::Deserialize;
+use tokio::fs;
+use crate::duration;
+
+/// Vault checks the signed request against the global STS endpoint by default.
+const STS_URL: &str = "https://sts.amazonaws.com/";
+const STS_HOST: &str = "sts.amazonaws.com";
+const STS_REGION: &str = "us-east-1";
+const GET_CALLER_IDENTITY: &str = "Action=GetCallerIdentity&Version=2011-06-15";
+
+/// How long to wait for the ECS/EC2 metadata endpoints, which
+/// won't exist at all if we aren't running in AWS.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// AWS credentials, found using the same chain that the AWS CLI and SDKs use.
+#[derive(Debug,Clone,PartialEq,Eq,Deserialize)]
+pub struct Credentials {
+    #[serde(rename = "AccessKeyId")]
+    pub access_key_id: String,
+    #[serde(rename = "SecretAccessKey")]
+    pub secret_access_key: String,
+    #[serde(rename = "Token", default)]
+    pub session_token: Option<String>
+}
+
+/// A signed sts:GetCallerIdentity request, which Vault makes on our
+/// behalf to find out who we are.
+pub struct SignedRequest {
+    pub method: &'static str,
+    pub url: &'static str,
+    pub body: &'static str,
+    pub headers: BTreeMap<String,String>
+}
+
+impl Credentials {
+
+    /// Find credentials from (in order) environment variables, the shared credentials
+    /// file, the ECS container credentials endpoint, or the EC2 instance metadata service.
+    pub async fn find() -> Result<Credentials> {
+        if let Some(creds) = from_env() {
+            return Ok(creds)
+        }
+        if let Some(creds) = from_credentials_file().await? {
+            return Ok(creds)
+        }
+        let client = reqwest::Client::builder()
+            .timeout(METADATA_TIMEOUT)
+            .build()?;
+        if let Some(creds) = from_container(&client).await? {
+            return Ok(creds)
+        }
+        from_instance_metadata(&client)
+            .await
+            .context("Could not find any AWS credentials (looked in the environment, the shared credentials file, and the ECS and EC2 metadata endpoints)")
+    }
+
+    /// Sign an sts:GetCallerIdentity request (AWS signature version 4) at the time given.
+    pub fn sign_get_caller_identity(&self, now: SystemTime) -> SignedRequest {
+        let amz_date = amz_date(now);
+        let date = &amz_date[..8];
+
+        let mut headers = BTreeMap::new();
+        headers.insert("content-type".to_owned(), "application/x-www-form-urlencoded; charset=utf-8".to_owned());
+        headers.insert("host".to_owned(), STS_HOST.to_owned());
+        headers.insert("x-amz-date".to_owned(), amz_date.clone());
+        if let Some(token) = &self.session_token {
+            headers.insert("x-amz-security-token".to_owned(), token.clone());
+        }
+
+        let signed_headers = headers.keys().map(|k| &**k).collect::<Vec<_>>().join(";");
+        let canonical_headers: String = headers.iter().map(|(k, v)| format!("{}:{}\n", k, v)).collect();
+        let canonical_request = format!("POST\n/\n\n{}\n{}\n{}"
+            , canonical_headers
+            , signed_headers
+            , sha256_hex(GET_CALLER_IDENTITY));
+
+        let scope = format!("{}/{}/sts/aws4_request", date, STS_REGION);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}"
+            , amz_date
+            , scope
+            , sha256_hex(&canonical_request));
+
+        let mut key = format!("AWS4{}", self.secret_access_key).into_bytes();
+        for part in &[date, STS_REGION, "sts", "aws4_request"] {
+            key = hmac_sha256(&key, part);
+        }
+        let signature = to_hex(&hmac_sha256(&key, &string_to_sign));
+
+        headers.insert("authorization".to_owned(), format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}"
+            , self.access_key_id
+            , scope
+            , signed_headers
+            , signature));
+
+        SignedRequest {
+            method: "POST",
+            url: STS_URL,
+            body: GET_CALLER_IDENTITY,
+            headers
+        }
+    }
+
+}
+
+fn from_env() -> Option<Credentials> {
+    let access_key_id = std::env::var("AWS_ACCESS_KEY_ID").ok().filter(|s| !s.is_empty())?;
+    let secret_access_key = std::env::var("AWS_SECRET_ACCESS_KEY").ok().filter(|s| !s.is_empty())?;
+    let session_token = std::env::var("AWS_SESSION_TOKEN").ok().filter(|s| !s.is_empty());
+    Some(Credentials { access_key_id, secret_access_key, session_token })
+}
+
+async fn from_credentials_file() -> Result<Option<Credentials>> {
+    let path = match std::env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+        Some(path) => path.into(),
+        None => match BaseDirs::new() {
+            Some(dirs) => dirs.home_dir().join(".aws").join("credentials"),
+            None => return Ok(None)
+        }
+    };
+    let contents = match fs::read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(_) => return Ok(None)
+    };
+    let profile = std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_owned());
+    Ok(parse_credentials_file(&contents, &profile))
+}
+
+/// Find the credentials for a profile in an INI formatted credentials file.
+fn parse_credentials_file(contents: &str, profile: &str) -> Option<Credentials> {
+    let mut in_profile = false;
+    let mut values = BTreeMap::new();
+    for line in contents.lines().map(|l| l.trim()) {
+        if line.starts_with('[') && line.ends_with(']') {
+            in_profile = line[1..line.len()-1].trim() == profile;
+        } else if in_profile {
+            if let Some(idx) = line.find('=') {
+                values.insert(line[..idx].trim(), line[idx+1..].trim());
+            }
+        }
+    }
+    Some(Credentials {
+        access_key_id: values.get("aws_access_key_id")?.to_string(),
+        secret_access_key: values.get("aws_secret_access_key")?.to_string(),
+        session_token: values.get("aws_session_token").map(|s| s.to_string())
+    })
+}
+
+async fn from_container(client: &reqwest::Client) -> Result<Option<Credentials>> {
+    let url = match (std::env::var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"), std::env::var("AWS_CONTAINER_CREDENTIALS_FULL_URI")) {
+        (Ok(relative), _) => format!("http://169.254.170.2{}", relative),
+        (_, Ok(full)) => full,
+        _ => return Ok(None)
+    };
+    let mut req = client.get(&url);
+    if let Ok(token) = std::env::var("AWS_CONTAINER_AUTHORIZATION_TOKEN") {
+        req = req.header("Authorization", token);
+    }
+    let creds = req.send().await
+        .and_then(|res| res.error_for_status())
+        .context("Failed to get AWS credentials from the container credentials endpoint")?
+        .json()
+        .await
+        .context("Failed to parse AWS credentials from the container credentials endpoint")?;
+    Ok(Some(creds))
+}
+
+async fn from_instance_metadata(client: &reqwest::Client) -> Result<Credentials> {
+    let base = "http://169.254.169.254/latest";
+    let token = client.put(&format!("{}/api/token", base))
+        .header("X-aws-ec2-metadata-token-ttl-seconds", "60")
+        .send().await
+        .and_then(|res| res.error_for_status())
+        .context("Failed to get a token from the EC2 instance metadata service")?
+        .text().await?;
+
+    let creds_url = format!("{}/meta-data/iam/security-credentials/", base);
+    let role = client.get(&creds_url)
+        .header("X-aws-ec2-metadata-token", &token)
+        .send().await
+        .and_then(|res| res.error_for_status())
+        .context("Failed to find the IAM role from the EC2 instance metadata service")?
+        .text().await?;
+    let role = role.lines().next().ok_or_else(|| anyhow!("The EC2 instance does not have an IAM role"))?;
+
+    client.get(&format!("{}{}", creds_url, role))
+        .header("X-aws-ec2-metadata-token", &token)
+        .send().await
+        .and_then(|res| res.error_for_status())
+        .context("Failed to get AWS credentials from the EC2 instance metadata service")?
+        .json()
+        .await
+        .context("Failed to parse AWS credentials from the EC2 instance metadata service")
+}
+
+/// A time formatted like '20200315T094502Z'.
+fn amz_date(time: SystemTime) -> String {
+    duration::utc_timestamp(time).replace(&['-', ':'][..], "")
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data.as_bytes()).as_ref().to_vec()
+}
+
+fn sha256_hex(data: &str) -> String {
+    to_hex(digest::digest(&digest::SHA256, data.as_bytes()).as_ref())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn format_amz_date() {
+        let cases = vec![
+            (0, "19700101T000000Z"),
+            (951_782_400, "20000229T000000Z"),
+            (1_584_265_502, "20200315T094502Z"),
+        ];
+        for (secs, expected) in cases {
+            assert_eq!(amz_date(UNIX_EPOCH + Duration::from_secs(secs)), expected);
+        }
+    }
+
+    #[test]
+    fn sign_request() {
+        let creds = Credentials {
+            access_key_id: "AKIDEXAMPLE".to_owned(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_owned(),
+            session_token: None
+        };
+        let req = creds.sign_get_caller_identity(UNIX_EPOCH + Duration::from_secs(1_584_265_502));
+        assert_eq!(req.headers["x-amz-date"], "20200315T094502Z");
+        assert_eq!(req.headers["authorization"], "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20200315/us-east-1/sts/aws4_request, \
+            SignedHeaders=content-type;host;x-amz-date, \
+            Signature=94d06d0d29f17150bc1cb8bec7676f4123675af3380058ac0e67c3a74001c954");
+    }
+
+    #[test]
+    fn parse_credentials() {
+        let contents = "
+            [default]
+            aws_access_key_id = AKIDDEFAULT
+            aws_secret_access_key = secret1
+
+            [work]
+            aws_access_key_id=AKIDWORK
+            aws_secret_access_key=secret2
+            aws_session_token=token2
+        ";
+        let cases = vec![
+            ("default", Some(("AKIDDEFAULT", "secret1", None))),
+            ("work", Some(("AKIDWORK", "secret2", Some("token2")))),
+            ("nope", None),
+        ];
+        for (profile, expected) in cases {
+            let expected = expected.map(|(id, secret, token): (&str, &str, Option<&str>)| Credentials {
+                access_key_id: id.to_owned(),
+                secret_access_key: secret.to_owned(),
+                session_token: token.map(|t| t.to_owned())
+            });
+            assert_eq!(parse_credentials_file(contents, profile), expected, "Unexpected credentials for profile '{}'", profile);
+        }
+    }
+
+}
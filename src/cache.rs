@@ -1,33 +1,158 @@
 use directories::BaseDirs;
+use std::collections::{ BTreeMap, BTreeSet, HashMap };
 use std::path::{ Path, PathBuf };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use std::io::Write;
 use anyhow::{ anyhow, Result, Context };
 use serde::{ Deserialize, Serialize };
 use tokio::fs;
+use tracing::warn;
+use fs2::FileExt;
+use vault_inject::auth::TokenLease;
+use crate::crypto::{ self, Encrypted, Key };
+use crate::files::{ self, FileMode, FileOptions };
 
-#[derive(Debug)]
 pub struct Cache {
     dir: PathBuf,
-    data: CacheData
+    key_path: PathBuf,
+    protection: Protection,
+    // The key (and the salt it was derived with) that the cache was
+    // last encrypted with using the passphrase we've been given:
+    passphrase_key: Option<(Key, Vec<u8>)>,
+    data: CacheData,
+    // The tokens, secrets and mount tables that we've changed since loading the cache:
+    changed: BTreeSet<String>,
+    changed_secrets: BTreeSet<String>,
+    changed_mounts: BTreeSet<String>
 }
 
-#[derive(Debug,Serialize,Deserialize)]
+/// How the cache is protected on disk.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Protection {
+    /// It isn't; the cache is plain JSON.
+    Plaintext,
+    /// It's encrypted with a random key that's kept in a separate file.
+    KeyFile,
+    /// It's encrypted with a key derived from this passphrase.
+    Passphrase(String)
+}
+
+/// What's actually written to disk. Plaintext caches from before we
+/// encrypted them are still read (and encrypted when next saved).
+#[derive(Serialize,Deserialize)]
+#[serde(untagged)]
+enum CacheFile {
+    Encrypted {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        salt: Option<String>,
+        encrypted: Encrypted
+    },
+    Plaintext(CacheData)
+}
+
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
 struct CacheData {
-    last_token: Option<CachedToken>
+    // Tokens keyed by what they were obtained for (see CacheKey):
+    #[serde(default)]
+    tokens: BTreeMap<String,CachedToken>,
+    // Secret values keyed by what the token used to fetch them was obtained
+    // for, and their path (see CacheKey::secret_id):
+    #[serde(default)]
+    secrets: BTreeMap<String,CachedSecret>,
+    // The secret stores mounted in each Vault instance (and namespace), keyed
+    // by CacheKey::mounts_id:
+    #[serde(default)]
+    mounts: BTreeMap<String,CachedMounts>
 }
 
-#[derive(Debug,Serialize,Deserialize)]
+/// What a cached token was obtained for. Tokens are only reused with the
+/// same Vault instance (and namespace), the same auth types and path, and the
+/// same identity (eg username or role) that they were obtained with.
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct CacheKey {
+    pub vault_url: String,
+    pub namespace: Option<String>,
+    pub auth_types: Vec<String>,
+    pub auth_path: Option<String>,
+    pub identity: Vec<String>
+}
+
+impl CacheKey {
+    fn id(&self) -> String {
+        // JSON encoding keeps the parts apart whatever characters they contain:
+        serde_json::json!([
+            self.vault_url,
+            self.namespace,
+            self.auth_types,
+            self.auth_path,
+            self.identity
+        ]).to_string()
+    }
+    fn secret_id(&self, path: &str) -> String {
+        serde_json::json!([self.id(), path]).to_string()
+    }
+    fn mounts_id(&self) -> String {
+        serde_json::json!([self.vault_url, self.namespace]).to_string()
+    }
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
 struct CachedToken {
-    token: String
+    token: String,
+    // When the token expires (in seconds since the epoch), how long it lasted
+    // for when we got it, and whether it can be renewed (if we know):
+    #[serde(default)]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    ttl: Option<u64>,
+    #[serde(default)]
+    renewable: bool
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+struct CachedSecret {
+    path: String,
+    values: Vec<(String,String)>,
+    // When we should stop using the cached values (in seconds since the epoch):
+    expires_at: u64
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+struct CachedMounts {
+    // The type of store (as an engine prefix, eg 'kv2') and path of each mount:
+    mounts: Vec<(String,String)>,
+    // When we should stop using the cached mounts (in seconds since the epoch):
+    expires_at: u64
+}
+
+impl CachedMounts {
+    fn has_expired(&self, now: SystemTime) -> bool {
+        self.expires_at <= to_secs(now)
+    }
+}
+
+impl CachedSecret {
+    fn has_expired(&self, now: SystemTime) -> bool {
+        self.expires_at <= to_secs(now)
+    }
+}
+
+impl CachedToken {
+    fn has_expired(&self, now: SystemTime) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= to_secs(now))
+    }
 }
 
 static FILENAME: &str = "cache";
+static KEY_FILENAME: &str = "cache.key";
+static LOCK_FILENAME: &str = "cache.lock";
 
 impl Cache {
 
     /// Load the user specific cache from file system,
-    /// returning defaults if no such cache exists or an
-    /// error if we don't know where to look.
-    pub async fn load() -> Result<Cache> {
+    /// returning defaults if no such cache exists (or it can't be
+    /// decrypted) or an error if we don't know where to look.
+    pub async fn load(protection: Protection) -> Result<Cache> {
 
         let base_dirs = BaseDirs::new().ok_or_else(||
             anyhow!("Could not resolve a path to the cache"))?;
@@ -35,78 +160,560 @@ impl Cache {
         let mut cache_dir = base_dirs.cache_dir().to_owned();
         cache_dir.push("vault_inject");
 
-        let cache_data = load_data(cache_dir.clone(), FILENAME).await;
+        // The key is kept away from the cache that it encrypts:
+        let mut key_path = base_dirs.data_local_dir().to_owned();
+        key_path.push("vault_inject");
+        key_path.push(KEY_FILENAME);
+
+        Ok(Cache::load_from(cache_dir, key_path, protection).await)
+    }
 
-        Ok(Cache {
-            dir: cache_dir,
-            data: cache_data
-         })
+    async fn load_from(dir: PathBuf, key_path: PathBuf, protection: Protection) -> Cache {
+        let mut cache = Cache {
+            dir,
+            key_path,
+            protection,
+            passphrase_key: None,
+            data: CacheData::default(),
+            changed: BTreeSet::new(),
+            changed_secrets: BTreeSet::new(),
+            changed_mounts: BTreeSet::new()
+        };
+        cache.data = match cache.read_data().await {
+            Ok(data) => data.unwrap_or_default(),
+            Err(e) => {
+                warn!("Ignoring the token cache: {:#}", e);
+                CacheData::default()
+            }
+        };
+        cache
     }
 
-    /// Write the cache data back to disk.
-    pub async fn save(&self) -> Result<()> {
-        save_data(self.dir.clone(), FILENAME, &self.data).await
+    /// Write the tokens we've changed back to disk. Other runs may have saved
+    /// the cache since we loaded it, so we lock it and merge our changes into
+    /// what's there now rather than overwriting it.
+    pub async fn save(&mut self) -> Result<()> {
+        create_private_dir(&self.dir).await?;
+        let _lock = CacheLock::acquire(self.dir.join(LOCK_FILENAME)).await?;
+
+        let mut data = self.read_data().await.ok().flatten().unwrap_or_default();
+        let now = SystemTime::now();
+        data.tokens.retain(|_, cached| !cached.has_expired(now));
+        data.secrets.retain(|_, cached| !cached.has_expired(now));
+        data.mounts.retain(|_, cached| !cached.has_expired(now));
+        for id in &self.changed {
+            if let Some(cached) = self.data.tokens.get(id) {
+                data.tokens.insert(id.clone(), cached.clone());
+            }
+        }
+        for id in &self.changed_secrets {
+            if let Some(cached) = self.data.secrets.get(id) {
+                data.secrets.insert(id.clone(), cached.clone());
+            }
+        }
+        // Mount tables that we've forgotten about are forgotten on disk too:
+        for id in &self.changed_mounts {
+            match self.data.mounts.get(id) {
+                Some(cached) => data.mounts.insert(id.clone(), cached.clone()),
+                None => data.mounts.remove(id)
+            };
+        }
+        self.data = data;
+
+        let cache_file = self.to_cache_file().await?;
+        let contents = serde_json::to_vec(&cache_file)
+            .context("Failed to serialize cache data for writing")?;
+        let file_opts = FileOptions { mode: Some(FileMode(0o600)), owner: None };
+        files::write_file(&self.dir.join(FILENAME), &contents, &file_opts)
+            .await
+            .context("Failed to update cached data")?;
+        Ok(())
+    }
+
+    /// Read the cache from disk, if there is one. Caches that other users can
+    /// access, or that can't be decrypted, aren't used.
+    async fn read_data(&mut self) -> Result<Option<CacheData>> {
+        let path = self.dir.join(FILENAME);
+        let contents = match fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read '{}'", path.display()))
+        };
+        check_private(&path).await?;
+        match serde_json::from_slice(&contents) {
+            Ok(CacheFile::Plaintext(data)) => Ok(Some(data)),
+            Ok(CacheFile::Encrypted { salt, encrypted }) => self.decrypt(salt, &encrypted)
+                .await
+                .map(Some)
+                .context("it could not be decrypted (has the passphrase or key changed?)"),
+            // A cache we can't make sense of is replaced next time we save:
+            Err(_) => Ok(None)
+        }
+    }
+
+    /// Prepare the cache data to be written, encrypting it if we've been asked to.
+    async fn to_cache_file(&self) -> Result<CacheFile> {
+        let cache_file = match &self.protection {
+            Protection::Plaintext => CacheFile::Plaintext(self.data.clone()),
+            Protection::KeyFile => {
+                let key = load_or_create_key(&self.key_path).await?;
+                CacheFile::Encrypted { salt: None, encrypted: key.encrypt(&serde_json::to_vec(&self.data)?)? }
+            },
+            Protection::Passphrase(passphrase) => {
+                let (key, salt) = match &self.passphrase_key {
+                    Some((key, salt)) => (key.clone(), salt.clone()),
+                    None => {
+                        let salt: [u8; 16] = crypto::random_bytes()?;
+                        (Key::from_passphrase(passphrase, &salt), salt.to_vec())
+                    }
+                };
+                let encrypted = key.encrypt(&serde_json::to_vec(&self.data)?)?;
+                CacheFile::Encrypted { salt: Some(base64::encode(salt)), encrypted }
+            }
+        };
+        Ok(cache_file)
+    }
+
+    /// Decrypt cache data using the key we've been told to use.
+    async fn decrypt(&mut self, salt: Option<String>, encrypted: &Encrypted) -> Result<CacheData> {
+        let key = match (&self.protection, salt) {
+            (Protection::Passphrase(passphrase), Some(salt)) => {
+                let salt = base64::decode(salt)?;
+                let key = Key::from_passphrase(passphrase, &salt);
+                self.passphrase_key = Some((key.clone(), salt));
+                key
+            },
+            (Protection::KeyFile, None) => read_key(&self.key_path).await?,
+            _ => return Err(anyhow!("The cache was not encrypted the way we've been asked to decrypt it"))
+        };
+        Ok(serde_json::from_slice(&key.decrypt(encrypted)?)?)
     }
 
     /// Store a token against some auth details, so it will be reused if
-    /// the auth details are reused.
-    pub fn set_token(&mut self, token: String) {
-        self.data.last_token = Some(CachedToken {
-            token: token
-        })
-    }
-
-    /// Get a token back given some auth details if one is cached.
-    pub fn get_token(&self) -> Option<String> {
-        if let Some(cached) = &self.data.last_token {
-            Some(cached.token.to_owned())
-        } else {
-            None
+    /// the auth details are reused. If we know how long the token lasts for,
+    /// we remember when it expires so that it can be renewed beforehand.
+    /// Any other tokens that have expired are forgotten about.
+    pub fn set_token(&mut self, key: &CacheKey, token: String, lease: Option<TokenLease>, now: SystemTime) {
+        let lease = lease.filter(|lease| lease.ttl > Duration::from_secs(0));
+        self.data.tokens.retain(|_, cached| !cached.has_expired(now));
+        self.changed.insert(key.id());
+        self.data.tokens.insert(key.id(), CachedToken {
+            token,
+            expires_at: lease.map(|lease| to_secs(now) + lease.ttl.as_secs()),
+            ttl: lease.map(|lease| lease.ttl.as_secs()),
+            renewable: matches!(lease, Some(lease) if lease.renewable)
+        });
+    }
+
+    /// Should the cached token be renewed? It should if it can be, and less than
+    /// half of the time that it lasted for when we got it is left.
+    pub fn token_needs_renewing(&self, key: &CacheKey, now: SystemTime) -> bool {
+        match self.data.tokens.get(&key.id()) {
+            Some(CachedToken { expires_at: Some(expires_at), ttl: Some(ttl), renewable: true, .. }) => {
+                expires_at.saturating_sub(to_secs(now)) < ttl / 2
+            },
+            _ => false
         }
     }
 
-}
+    /// Store the values of a secret, obtained using a token for the auth details
+    /// given, for as long as the TTL given.
+    pub fn set_secret(&mut self, key: &CacheKey, path: &str, values: Vec<(String,String)>, ttl: Duration, now: SystemTime) {
+        let id = key.secret_id(path);
+        self.data.secrets.retain(|_, cached| !cached.has_expired(now));
+        self.changed_secrets.insert(id.clone());
+        self.data.secrets.insert(id, CachedSecret {
+            path: path.to_owned(),
+            values,
+            expires_at: to_secs(now) + ttl.as_secs()
+        });
+    }
+
+    /// Get back the secrets (keyed by path) that were cached using a token
+    /// for the auth details given, and which haven't expired.
+    pub fn get_secrets(&self, key: &CacheKey, now: SystemTime) -> HashMap<String,Vec<(String,String)>> {
+        self.data.secrets.iter()
+            .filter(|(id, cached)| **id == key.secret_id(&cached.path) && !cached.has_expired(now))
+            .map(|(_, cached)| (cached.path.clone(), cached.values.clone()))
+            .collect()
+    }
 
-async fn load_data(mut path: PathBuf, filename: &str) -> CacheData {
-    path.push(filename);
+    /// Store the secret stores (their types and paths) that are mounted in the
+    /// Vault instance that some auth details are for, for as long as the TTL given.
+    pub fn set_mounts(&mut self, key: &CacheKey, mounts: Vec<(String,String)>, ttl: Duration, now: SystemTime) {
+        let id = key.mounts_id();
+        self.data.mounts.retain(|_, cached| !cached.has_expired(now));
+        self.changed_mounts.insert(id.clone());
+        self.data.mounts.insert(id, CachedMounts { mounts, expires_at: to_secs(now) + ttl.as_secs() });
+    }
 
-    async fn try_load_from_file(path: &Path) -> Result<CacheData> {
-        use tokio::io::AsyncReadExt;
-        let mut file = fs::File::open(path).await?;
-        let mut contents = vec![];
-        file.read_to_end(&mut contents).await?;
-        serde_json::from_slice(&contents).map_err(|_| anyhow!("Cannot deserialize"))
+    /// Get back the secret stores that were cached as being mounted in the Vault
+    /// instance that some auth details are for, if they haven't expired.
+    pub fn get_mounts(&self, key: &CacheKey, now: SystemTime) -> Option<Vec<(String,String)>> {
+        self.data.mounts.get(&key.mounts_id())
+            .filter(|cached| !cached.has_expired(now))
+            .map(|cached| cached.mounts.clone())
     }
 
-    // Try to load the cache from disk:
-    if let Ok(cache_data) = try_load_from_file(&path).await {
-        return cache_data;
+    /// Forget the secret stores cached as being mounted in the Vault instance
+    /// that some auth details are for (eg because they're out of date).
+    pub fn forget_mounts(&mut self, key: &CacheKey) {
+        let id = key.mounts_id();
+        self.data.mounts.remove(&id);
+        self.changed_mounts.insert(id);
     }
 
-    // Failing that, return a default empty cache:
-    CacheData {
-        last_token: None
+    /// Get a token back given some auth details if one is cached (and
+    /// hasn't expired).
+    pub fn get_token(&self, key: &CacheKey, now: SystemTime) -> Option<String> {
+        self.data.tokens.get(&key.id())
+            .filter(|cached| !cached.has_expired(now))
+            .map(|cached| cached.token.to_owned())
     }
+
+}
+
+fn to_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
 }
 
-async fn save_data(mut path: PathBuf, filename: &str, data: &CacheData) -> Result<()> {
-    fs::create_dir_all(&path).await?;
-    path.push(filename);
+/// Load the key that the cache is encrypted with, creating it (readable only
+/// by us) if it doesn't exist yet.
+async fn load_or_create_key(path: &Path) -> Result<Key> {
+    if fs::metadata(path).await.is_ok() {
+        return read_key(path).await;
+    }
+    if let Some(dir) = path.parent() {
+        create_private_dir(dir).await?;
+    }
+    let key = Key::random()?;
+    let mut file = private_file_options()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("Failed to create the cache key '{}'", path.display()))?;
+    file.write_all(key.to_base64().as_bytes())
+        .with_context(|| format!("Failed to write the cache key '{}'", path.display()))?;
+    Ok(key)
+}
 
-    let mut file = fs::File::create(path)
+/// Read the key that the cache is encrypted with, refusing to use it if
+/// other users can access it.
+async fn read_key(path: &Path) -> Result<Key> {
+    check_private(path).await?;
+    let key = fs::read_to_string(path)
         .await
-        .with_context(|| format!("Failed to update cached data"))?;
+        .with_context(|| format!("Failed to read the cache key '{}'", path.display()))?;
+    Key::from_base64(&key)
+        .with_context(|| format!("The cache key in '{}' is not valid", path.display()))
+}
 
-    let data = serde_json::to_vec(data)
-        .with_context(|| format!("Failed to serialize cache data for writing"))?;
+/// Options to open a file with which, if they create it, make sure that only we
+/// can access it. Windows has no file modes, so there we rely on the permissions
+/// inherited from the (per user) cache directory.
+fn private_file_options() -> std::fs::OpenOptions {
+    #[allow(unused_mut)]
+    let mut options = std::fs::OpenOptions::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
 
-    use tokio::io::AsyncWriteExt;
-    file.write_all(&data)
-        .await
-        .with_context(|| format!("Failed to write cache data"))?;
-    file.sync_data()
+/// Fail if anybody but us can access the file given.
+#[cfg(unix)]
+async fn check_private(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path)
         .await
-        .with_context(|| format!("Failed to sync cache data to disk"))?;
+        .with_context(|| format!("Failed to read the permissions of '{}'", path.display()))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(anyhow!("'{}' can be accessed by other users (its mode is {:o}, but should be 600)"
+            , path.display(), mode & 0o777));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn check_private(_path: &Path) -> Result<()> {
+    Ok(())
+}
 
+/// Create a directory (and any parents) if needed, making sure that only
+/// we can access it.
+async fn create_private_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create the directory '{}'", dir.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))
+            .await
+            .with_context(|| format!("Failed to set the permissions of '{}'", dir.display()))?;
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// An exclusive lock on the cache, held while it's being updated so that
+/// concurrent runs don't trample on each other's changes. It's released
+/// when this is dropped.
+struct CacheLock {
+    _file: std::fs::File
+}
+
+impl CacheLock {
+    async fn acquire(path: PathBuf) -> Result<CacheLock> {
+        tokio::task::spawn_blocking(move || {
+            let file = private_file_options()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .with_context(|| format!("Failed to open the cache lock '{}'", path.display()))?;
+            file.lock_exclusive()
+                .with_context(|| format!("Failed to lock '{}'", path.display()))?;
+            Ok(CacheLock { _file: file })
+        }).await?
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn test_cache(protection: Protection) -> Cache {
+        Cache {
+            dir: PathBuf::new(),
+            key_path: PathBuf::new(),
+            protection,
+            passphrase_key: None,
+            data: CacheData::default(),
+            changed: BTreeSet::new(),
+            changed_secrets: BTreeSet::new(),
+            changed_mounts: BTreeSet::new()
+        }
+    }
+
+    #[test]
+    fn tokens_needing_renewal() {
+
+        let got_at = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let lease = |secs, renewable| Some(TokenLease { ttl: Duration::from_secs(secs), renewable });
+
+        let cases = vec![
+            // Renew once less than half of the TTL is left:
+            (lease(3600, true), 1000, false),
+            (lease(3600, true), 1801, true),
+            (lease(3600, true), 5000, true),
+            // Unless we can't, or the token never expires, or we don't know:
+            (lease(3600, false), 3000, false),
+            (lease(0, true), 3000, false),
+            (None, 3000, false),
+        ];
+
+        for (lease, secs_later, expected) in cases {
+            let mut cache = test_cache(Protection::Plaintext);
+            let key = CacheKey::default();
+            cache.set_token(&key, "s.TOKEN".to_owned(), lease, got_at);
+            let now = got_at + Duration::from_secs(secs_later);
+            assert_eq!(cache.token_needs_renewing(&key, now), expected, "Unexpected result for {:?} after {}s", lease, secs_later);
+        }
+
+    }
+
+    #[test]
+    fn tokens_per_key() {
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let lease = Some(TokenLease { ttl: Duration::from_secs(3600), renewable: true });
+        let key = |vault_url: &str, auth_type: &str, identity: &str| CacheKey {
+            vault_url: vault_url.to_owned(),
+            auth_types: vec![auth_type.to_owned()],
+            identity: vec![identity.to_owned()],
+            ..CacheKey::default()
+        };
+
+        let mut cache = test_cache(Protection::Plaintext);
+        cache.set_token(&key("https://a", "userpass", "alice"), "s.ALICE".to_owned(), lease, now);
+        cache.set_token(&key("https://a", "userpass", "bob"), "s.BOB".to_owned(), lease, now);
+        cache.set_token(&key("https://b", "userpass", "alice"), "s.ALICE_B".to_owned(), None, now);
+
+        let cases = vec![
+            (key("https://a", "userpass", "alice"), 0, Some("s.ALICE")),
+            (key("https://a", "userpass", "bob"), 0, Some("s.BOB")),
+            (key("https://b", "userpass", "alice"), 0, Some("s.ALICE_B")),
+            (key("https://a", "ldap", "alice"), 0, None),
+            (key("https://c", "userpass", "alice"), 0, None),
+            // Expired tokens aren't handed back (but those without an expiry are):
+            (key("https://a", "userpass", "alice"), 3600, None),
+            (key("https://b", "userpass", "alice"), 3600, Some("s.ALICE_B")),
+        ];
+
+        for (key, secs_later, expected) in cases {
+            let now = now + Duration::from_secs(secs_later);
+            assert_eq!(cache.get_token(&key, now).as_deref(), expected, "Unexpected token for {:?} after {}s", key, secs_later);
+        }
+
+        // Expired tokens are forgotten about when a new one is cached:
+        let later = now + Duration::from_secs(7200);
+        cache.set_token(&key("https://c", "userpass", "carol"), "s.CAROL".to_owned(), lease, later);
+        assert_eq!(cache.data.tokens.len(), 2);
+
+    }
+
+    #[test]
+    fn secrets_per_key() {
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let key = |identity: &str| CacheKey { identity: vec![identity.to_owned()], ..CacheKey::default() };
+        let values = |v: &str| vec![("password".to_owned(), v.to_owned())];
+
+        let mut cache = test_cache(Protection::KeyFile);
+        cache.set_secret(&key("alice"), "secret/app/db", values("a1"), Duration::from_secs(60), now);
+        cache.set_secret(&key("alice"), "secret/app/other", values("a2"), Duration::from_secs(10), now);
+        cache.set_secret(&key("bob"), "secret/app/db", values("b1"), Duration::from_secs(60), now);
+
+        let cases = vec![
+            ("alice", 0, vec![("secret/app/db", "a1"), ("secret/app/other", "a2")]),
+            ("alice", 30, vec![("secret/app/db", "a1")]),
+            ("alice", 60, vec![]),
+            ("bob", 0, vec![("secret/app/db", "b1")]),
+            ("carol", 0, vec![]),
+        ];
+
+        for (identity, secs_later, expected) in cases {
+            let secrets = cache.get_secrets(&key(identity), now + Duration::from_secs(secs_later));
+            let mut actual: Vec<(&str,&str)> = secrets.iter().map(|(path, v)| (&**path, &*v[0].1)).collect();
+            actual.sort();
+            assert_eq!(actual, expected, "Unexpected secrets for {} after {}s", identity, secs_later);
+        }
+
+    }
+
+    #[test]
+    fn mounts_per_vault() {
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let key = |vault_url: &str, identity: &str| CacheKey { vault_url: vault_url.to_owned(), identity: vec![identity.to_owned()], ..CacheKey::default() };
+        let mounts = |mount: &str| vec![("kv2".to_owned(), mount.to_owned())];
+
+        let mut cache = test_cache(Protection::KeyFile);
+        cache.set_mounts(&key("https://a", "alice"), mounts("secret"), Duration::from_secs(60), now);
+        cache.set_mounts(&key("https://b", "alice"), mounts("other"), Duration::from_secs(60), now);
+        cache.set_mounts(&key("https://c", "alice"), mounts("gone"), Duration::from_secs(60), now);
+        cache.forget_mounts(&key("https://c", "alice"));
+
+        let cases = vec![
+            ("https://a", "alice", 0, Some("secret")),
+            // Mounts are the same whoever is asking:
+            ("https://a", "bob", 0, Some("secret")),
+            ("https://a", "alice", 60, None),
+            ("https://b", "alice", 30, Some("other")),
+            ("https://c", "alice", 0, None),
+            ("https://d", "alice", 0, None),
+        ];
+
+        for (vault_url, identity, secs_later, expected) in cases {
+            let actual = cache.get_mounts(&key(vault_url, identity), now + Duration::from_secs(secs_later));
+            assert_eq!(actual, expected.map(mounts), "Unexpected mounts for {} after {}s", vault_url, secs_later);
+        }
+
+    }
+
+    #[tokio::test]
+    async fn encrypted_caches() {
+
+        let dir = std::env::temp_dir().join(format!("vault_inject_cache_test_{}", std::process::id()));
+        let key_path = dir.join("key").join(KEY_FILENAME);
+        let key = CacheKey::default();
+        let passphrase = |p: &str| Protection::Passphrase(p.to_owned());
+
+        let cases = vec![
+            // How the cache is saved, then loaded, and whether the token is still there:
+            (Protection::Plaintext, Protection::Plaintext, true),
+            (Protection::KeyFile, Protection::KeyFile, true),
+            (passphrase("hunter2"), passphrase("hunter2"), true),
+            // Plaintext caches from before are still read:
+            (Protection::Plaintext, Protection::KeyFile, true),
+            (Protection::Plaintext, passphrase("hunter2"), true),
+            // Encrypted caches can't be read any other way:
+            (passphrase("hunter2"), passphrase("wrong"), false),
+            (passphrase("hunter2"), Protection::KeyFile, false),
+            (Protection::KeyFile, passphrase("hunter2"), false),
+            (Protection::KeyFile, Protection::Plaintext, false),
+        ];
+
+        for (saved_with, loaded_with, readable) in cases {
+            let mut cache = Cache::load_from(dir.clone(), key_path.clone(), saved_with.clone()).await;
+            cache.set_token(&key, "s.TOKEN".to_owned(), None, SystemTime::now());
+            cache.save().await.unwrap();
+
+            let contents = std::fs::read_to_string(dir.join(FILENAME)).unwrap();
+            assert_eq!(contents.contains("s.TOKEN"), saved_with == Protection::Plaintext, "Unexpected cache contents: {}", contents);
+
+            let cache = Cache::load_from(dir.clone(), key_path.clone(), loaded_with.clone()).await;
+            let token = cache.get_token(&key, SystemTime::now());
+            assert_eq!(token.is_some(), readable, "Unexpected result saving with {:?} and loading with {:?}", saved_with, loaded_with);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+    }
+
+    #[tokio::test]
+    async fn saving_caches() {
+
+        let dir = std::env::temp_dir().join(format!("vault_inject_cache_save_test_{}", std::process::id()));
+        let key_path = dir.join("key").join(KEY_FILENAME);
+        let key = |identity: &str| CacheKey { identity: vec![identity.to_owned()], ..CacheKey::default() };
+        let now = SystemTime::now();
+
+        // Two runs load the cache at the same time and then each save a token;
+        // neither should lose the other's:
+        let mut first = Cache::load_from(dir.clone(), key_path.clone(), Protection::KeyFile).await;
+        let mut second = Cache::load_from(dir.clone(), key_path.clone(), Protection::KeyFile).await;
+        first.set_token(&key("alice"), "s.ALICE".to_owned(), None, now);
+        second.set_token(&key("bob"), "s.BOB".to_owned(), None, now);
+        first.save().await.unwrap();
+        second.save().await.unwrap();
+
+        let cache = Cache::load_from(dir.clone(), key_path.clone(), Protection::KeyFile).await;
+        assert_eq!(cache.get_token(&key("alice"), now).as_deref(), Some("s.ALICE"));
+        assert_eq!(cache.get_token(&key("bob"), now).as_deref(), Some("s.BOB"));
+
+        // Only we can access the cache and its key (Windows has no file modes):
+        #[cfg(unix)]
+        {
+            use std::fs::Permissions;
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&dir), 0o700);
+            assert_eq!(mode(&dir.join(FILENAME)), 0o600);
+            assert_eq!(mode(&key_path), 0o600);
+
+            // If others can access it, the cache is ignored:
+            let cases = vec![
+                (dir.join(FILENAME), 0o644),
+                (dir.join(FILENAME), 0o660),
+                (key_path.clone(), 0o604),
+            ];
+            for (path, loose_mode) in cases {
+                std::fs::set_permissions(&path, Permissions::from_mode(loose_mode)).unwrap();
+                let cache = Cache::load_from(dir.clone(), key_path.clone(), Protection::KeyFile).await;
+                assert_eq!(cache.get_token(&key("alice"), now), None, "Cache should be ignored with {} at mode {:o}", path.display(), loose_mode);
+                std::fs::set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+            }
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+    }
+
+}
@@ -1,69 +1,311 @@
-use reqwest::{ Method };
+use reqwest::{ Method, StatusCode };
 use serde::{ Deserialize, Serialize, de::DeserializeOwned };
 use url::Url;
 use anyhow::{ anyhow, Result, Context };
 use std::fmt;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::Duration;
+use tokio::sync::{ Semaphore, SemaphorePermit };
+use tokio::time::Instant;
+use tracing::{ debug, warn };
+use crate::logging;
+use crate::size::ByteSize;
+use crate::transport::{ Response, Transport };
+
+/// The longest we'll wait before retrying a request when Vault asks us to wait.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// How many times we'll follow a redirect (eg from a standby node to the active one).
+const MAX_REDIRECTS: usize = 5;
+
+/// How long to wait before the first retry of a request (if Vault doesn't
+/// say). This doubles for each retry after that, up to some maximum.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct Client {
-    vault_url: Url,
-    client: reqwest::Client,
-    token: Option<String>
+    // Each Vault address we can talk to, and the index of the one we're using,
+    // which is shared between clones so that failing over lasts for the whole run:
+    transports: Vec<(Url, Transport)>,
+    current: Arc<AtomicUsize>,
+    token: Option<String>,
+    namespace: Option<String>,
+    max_response_size: Option<ByteSize>,
+    wrap_ttl: Option<Duration>,
+    rate_limit_retries: usize,
+    retries: usize,
+    retry_max_delay: Duration,
+    request_timeout: Option<Duration>,
+    // Shared between clones, so that the limits apply to every request we make:
+    max_concurrency: Option<Arc<Semaphore>>,
+    rate_limit: Option<Arc<Pacer>>
 }
 
 impl Client {
 
-    pub fn new(vault_url: Url) -> Client {
-        Client {
-            vault_url,
-            client: reqwest::Client::new(),
-            token: None
+    /// Create a client which talks to Vault using a reqwest client built from
+    /// the builder given, so that things like client certificates can be configured.
+    /// If the URL is a 'unix://' one, we talk to Vault over that socket instead.
+    pub fn new(vault_url: Url, builder: reqwest::ClientBuilder) -> Result<Client> {
+        Client::new_with_failover(vec![vault_url], builder)
+    }
+
+    /// Create a client which talks to the first of the Vault addresses given (eg the
+    /// nodes of a HA cluster). If a node can't be reached, responds with a server error
+    /// or turns us away (as standby nodes can), we fail over to the next one and
+    /// stick with whichever one works from then on.
+    pub fn new_with_failover(vault_urls: Vec<Url>, builder: reqwest::ClientBuilder) -> Result<Client> {
+        if vault_urls.is_empty() {
+            return Err(anyhow!("At least one Vault address is needed"));
         }
+        let transports = Transport::for_urls(vault_urls.clone(), builder)?;
+        Ok(Client {
+            transports: vault_urls.into_iter().zip(transports).collect(),
+            current: Arc::new(AtomicUsize::new(0)),
+            token: None,
+            namespace: None,
+            max_response_size: None,
+            wrap_ttl: None,
+            rate_limit_retries: 0,
+            retries: 0,
+            retry_max_delay: FIRST_RETRY_DELAY,
+            request_timeout: None,
+            max_concurrency: None,
+            rate_limit: None
+        })
+    }
+
+    /// Retry requests that Vault rate limits (429 responses) up to this many times.
+    pub fn with_rate_limit_retries(mut self, rate_limit_retries: usize) -> Client {
+        self.rate_limit_retries = rate_limit_retries;
+        self
+    }
+
+    /// Retry requests that fail with a server error (5xx), that can't reach
+    /// Vault or that are rate limited up to this many times, backing off
+    /// (with some jitter) up to the maximum delay given between each one.
+    /// Vault may have acted on a request that failed part way through, so
+    /// requests that change something (POSTs, which include logins and
+    /// generating credentials) are only retried if they never reached it,
+    /// although they're still sent on to the next Vault address (if any)
+    /// when one responds with a server error.
+    pub fn with_retries(mut self, retries: usize, max_delay: Duration) -> Client {
+        self.retries = retries;
+        self.retry_max_delay = max_delay;
+        self
+    }
+
+    /// Give up on each attempt at a request if Vault hasn't responded within this long.
+    /// This applies to every retry afresh, rather than to all of them together.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Client {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    /// Never have more than this many requests to Vault in flight at once.
+    pub fn with_max_concurrency(mut self, max_concurrency: NonZeroUsize) -> Client {
+        self.max_concurrency = Some(Arc::new(Semaphore::new(max_concurrency.get())));
+        self
+    }
+
+    /// Space requests out so that no more than this many are sent each second.
+    pub fn with_rate_limit(mut self, rate: RequestRate) -> Client {
+        self.rate_limit = Some(Arc::new(Pacer::new(rate)));
+        self
+    }
+
+    /// Make every request in this (Vault Enterprise) namespace.
+    pub fn with_namespace(mut self, namespace: String) -> Client {
+        self.namespace = Some(namespace);
+        self
+    }
+
+    /// Refuse to read responses larger than this.
+    pub fn with_max_response_size(mut self, max_response_size: ByteSize) -> Client {
+        self.max_response_size = Some(max_response_size);
+        self
+    }
+
+    /// Ask Vault to wrap responses in a single-use token which lasts this long.
+    pub fn with_wrap_ttl(&self, ttl: Duration) -> Client {
+        Client { wrap_ttl: Some(ttl), ..self.clone() }
     }
 
     pub fn with_token(&self, tok: String) -> Client {
-        Client {
-            vault_url: self.vault_url.clone(),
-            client: self.client.clone(),
-            token: Some(tok)
-        }
+        logging::redact(&tok);
+        Client { token: Some(tok), ..self.clone() }
+    }
+
+    /// The token that requests are made with, if any.
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
     }
 
     async fn request<D: DeserializeOwned, P: AsRef<str>, B: Serialize>(&self, method: Method, path: P, body: Option<B>) -> Result<D> {
         let path_str = path.as_ref();
-        let url = make_api_path(self.vault_url.clone(), path_str);
-        let mut builder = self.client.request(method, url);
-        if let Some(tok) = &self.token {
-            builder = builder.header("Authorization", format!("Bearer {}", tok));
-        }
-        if let Some(body) = &body {
-            builder = builder.json(body);
-        }
-        let res = builder.send()
-            .await
-            .with_context(|| anyhow!("Failed to make request to '{}'", path_str))?;
-
-        if !res.status().is_success() {
-            let reason = res.status().canonical_reason();
-            let status_str = res.status().as_str().to_owned();
-            let errors = res.json().await.unwrap_or(Errors::none());
-            if errors.errors.is_empty() {
-                return Err(match reason {
-                    Some(reason) => anyhow!("{} {} response from Vault", status_str, reason),
-                    None => anyhow!("{} response from Vault", status_str)
-                });
-            } else {
-                return Err(errors.into());
+        let body = match &body {
+            Some(body) => Some(serde_json::to_vec(body)
+                .with_context(|| anyhow!("Failed to encode the body of the request to '{}'", path_str))?),
+            None => None
+        };
+
+        // If Vault rate limits us, has a transient problem or can't be reached,
+        // wait for as long as it asks (or back off if it doesn't say) and try
+        // again, up to the number of retries allowed:
+        let mut retries = 0;
+        let mut delay = FIRST_RETRY_DELAY.min(self.retry_max_delay);
+        // Before waiting to retry, we try each of the other addresses we know of:
+        let mut addresses_tried = 0;
+        let mut permit = self.acquire_permit().await;
+        let (res, url) = loop {
+            if let Some(pacer) = &self.rate_limit {
+                pacer.wait().await;
+            }
+            let index = self.current.load(Ordering::SeqCst);
+            let transport = &self.transports[index].1;
+            let url = make_api_path(transport.base_url().clone(), path_str);
+            let mut headers = Vec::new();
+            if let Some(tok) = &self.token {
+                headers.push(("Authorization", format!("Bearer {}", tok)));
+            }
+            if let Some(namespace) = &self.namespace {
+                headers.push(("X-Vault-Namespace", namespace.clone()));
             }
+            if let Some(ttl) = self.wrap_ttl {
+                headers.push(("X-Vault-Wrap-TTL", format!("{}s", ttl.as_secs())));
+            }
+            debug!("{} {}", method, url);
+            let res = self.timed(path_str, self.send(transport, &method, url.clone(), &headers, &body)).await;
+
+            let retry = match Retry::reason(&method, &res) {
+                Some(retry) => retry,
+                None => break (res, url)
+            };
+            addresses_tried += 1;
+            if addresses_tried < self.transports.len() {
+                self.fail_over(index, retry, path_str);
+                continue;
+            }
+            addresses_tried = 0;
+            // Each address has had the request now, so we only go round again if it's safe to:
+            if !retry.can_resend(&method) {
+                break (res, url);
+            }
+            let max_retries = match retry {
+                Retry::RateLimited => self.rate_limit_retries.max(self.retries),
+                _ => self.retries
+            };
+            if retries >= max_retries {
+                break (res, url);
+            }
+            let wait = match res.as_ref().ok().and_then(retry_after) {
+                Some(wait) => wait.min(MAX_RETRY_AFTER),
+                None => jittered(delay)
+            };
+            retries += 1;
+            warn!("{} '{}'; retrying in {:?} ({} of {})", retry, path_str, wait, retries, max_retries);
+            // Let other requests go ahead while we wait:
+            drop(permit);
+            tokio::time::delay_for(wait).await;
+            permit = self.acquire_permit().await;
+            delay = (delay * 2).min(self.retry_max_delay);
+        };
+        let res = res.with_context(|| anyhow!("Failed to make request to '{}'", path_str))?;
+
+        let status = res.status();
+        debug!("{} {} responded with {}", method, url, status);
+        let body = self.timed(path_str, self.read_body(res, path_str)).await?;
+        drop(permit);
+
+        if !status.is_success() {
+            let errors = serde_json::from_slice(&body).unwrap_or_else(|_| Errors::none());
+            return Err(ApiError { status, errors: errors.errors }.into());
         }
 
-        let res: D = res.json()
-            .await
+        // Some requests (eg writes) succeed with an empty response:
+        let body = if body.is_empty() { b"null".to_vec() } else { body };
+        let res: D = serde_json::from_slice(&body)
             .with_context(|| anyhow!("Failed to handle API response from request to '{}'", path_str))?;
 
         Ok(res)
     }
 
+    /// Wait until we're allowed to make another request, if there's a limit on how many can be in flight.
+    async fn acquire_permit(&self) -> Option<SemaphorePermit<'_>> {
+        match &self.max_concurrency {
+            Some(semaphore) => Some(semaphore.acquire().await),
+            None => None
+        }
+    }
+
+    /// Send a request, following any redirects (standby nodes redirect requests to
+    /// the active node) with the same method, headers and body.
+    async fn send(&self, transport: &Transport, method: &Method, mut url: Url, headers: &[(&str, String)], body: &Option<Vec<u8>>) -> Result<Response> {
+        for _ in 0..=MAX_REDIRECTS {
+            let res = transport.send(method.clone(), url.clone(), headers, body.clone()).await?;
+            let is_redirect = res.status() == StatusCode::TEMPORARY_REDIRECT || res.status() == StatusCode::PERMANENT_REDIRECT;
+            let location = match res.headers().get(reqwest::header::LOCATION) {
+                Some(location) if is_redirect => location.to_str()
+                    .map_err(|_| anyhow!("Vault redirected the request to an invalid location"))?,
+                _ => return Ok(res)
+            };
+            let next_url = redirect_url(&url, location)?;
+            debug!("{} {} redirected to {}", method, url, next_url);
+            url = next_url;
+        }
+        Err(anyhow!("Vault redirected the request more than {} times", MAX_REDIRECTS))
+    }
+
+    /// Move on from the address at the index given to the next one, unless
+    /// another request has already failed over.
+    fn fail_over(&self, index: usize, retry: Retry, path_str: &str) {
+        let next = (index + 1) % self.transports.len();
+        if self.current.compare_exchange(index, next, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            warn!("{} '{}' at '{}'; failing over to '{}'", retry, path_str, self.transports[index].0, self.transports[next].0);
+        }
+    }
+
+    /// Fail with a [`TimedOut`] error if Vault doesn't respond in time.
+    async fn timed<T>(&self, path_str: &str, fut: impl Future<Output = Result<T>>) -> Result<T> {
+        let timeout = match self.request_timeout {
+            Some(timeout) => timeout,
+            None => return fut.await
+        };
+        match tokio::time::timeout(timeout, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(TimedOut { path: path_str.to_owned(), timeout }.into())
+        }
+    }
+
+    /// Read the body of a response, giving up if it's larger than we allow.
+    async fn read_body(&self, mut res: Response, path_str: &str) -> Result<Vec<u8>> {
+        let too_large = |max: ByteSize| anyhow!(
+            "The response from '{}' is larger than the maximum allowed size of {}", path_str, max);
+
+        if let (Some(max), Some(len)) = (self.max_response_size, res.content_length()) {
+            if len > max.0 as u64 {
+                return Err(too_large(max));
+            }
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = res.chunk()
+            .await
+            .with_context(|| anyhow!("Failed to read API response from request to '{}'", path_str))? {
+            body.extend_from_slice(&chunk);
+            if let Some(max) = self.max_response_size {
+                if body.len() > max.0 {
+                    return Err(too_large(max));
+                }
+            }
+        }
+        Ok(body)
+    }
+
     pub async fn get<D: DeserializeOwned, P: AsRef<str>>(&self, path: P) -> Result<D> {
         self.request(Method::GET, path, None as Option<()>).await
     }
@@ -72,15 +314,163 @@ impl Client {
         self.request(Method::POST, path, Some(body)).await
     }
 
+    pub async fn list<D: DeserializeOwned, P: AsRef<str>>(&self, path: P) -> Result<D> {
+        let list = Method::from_bytes(b"LIST").expect("LIST is a valid method");
+        self.request(list, path, None as Option<()>).await
+    }
+
+}
+
+/// A number of requests per second (eg '10', or '0.5' for one every two seconds).
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct RequestRate(pub f64);
+
+impl FromStr for RequestRate {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<RequestRate> {
+        match s.trim().parse::<f64>() {
+            Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(RequestRate(rate)),
+            _ => Err(anyhow!("'{}' is not a valid rate (try a number of requests per second, like '10' or '0.5').", s))
+        }
+    }
+}
+
+impl fmt::Display for RequestRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/s", self.0)
+    }
+}
+
+/// Spaces requests out evenly so that no more than some number are sent each second.
+struct Pacer {
+    interval: Duration,
+    next: Mutex<Option<Instant>>
+}
+
+impl Pacer {
+    fn new(rate: RequestRate) -> Pacer {
+        Pacer { interval: Duration::from_secs_f64(1.0 / rate.0), next: Mutex::new(None) }
+    }
+
+    /// Wait for the next free slot, taking it so that the next request waits for the one after.
+    async fn wait(&self) {
+        let slot = {
+            let now = Instant::now();
+            let mut next = self.next.lock().unwrap();
+            let slot = next.map(|next| next.max(now)).unwrap_or(now);
+            *next = Some(slot + self.interval);
+            slot
+        };
+        tokio::time::delay_until(slot).await;
+    }
+}
+
+/// Why a request is worth retrying.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Retry {
+    RateLimited,
+    ServerError(StatusCode),
+    Unreachable,
+    TimedOut,
+    Interrupted
+}
+
+impl Retry {
+    /// Why the request is worth making again, whether on the next address or (see
+    /// [`Retry::can_resend`]) on the same one, given the response to it.
+    fn reason(method: &Method, res: &Result<Response>) -> Option<Retry> {
+        match res {
+            // Rate limited requests are turned away before Vault does anything with them:
+            Ok(res) if res.status() == StatusCode::TOO_MANY_REQUESTS => Some(Retry::RateLimited),
+            // A '501 Not Implemented' isn't going to change if we ask again:
+            Ok(res) if res.status().is_server_error() && res.status() != StatusCode::NOT_IMPLEMENTED => {
+                Some(Retry::ServerError(res.status()))
+            },
+            Err(e) if is_not_connected(e) => Some(Retry::Unreachable),
+            // Vault may have acted on requests that were sent but not answered in time
+            // (or at all), and a slow node isn't one that's unavailable:
+            Err(e) if is_timeout(e) && is_idempotent(method) => Some(Retry::TimedOut),
+            Err(e) if is_interrupted(e) && is_idempotent(method) => Some(Retry::Interrupted),
+            _ => None
+        }
+    }
+
+    /// Can the request be sent to an address that's already had it? Vault may
+    /// have acted on a request that it responded to with a server error, so
+    /// that's only safe for requests which don't change anything.
+    fn can_resend(self, method: &Method) -> bool {
+        match self {
+            Retry::ServerError(_) | Retry::TimedOut | Retry::Interrupted => is_idempotent(method),
+            Retry::RateLimited | Retry::Unreachable => true
+        }
+    }
+}
+
+/// Can a request be made again without changing the outcome? Vault treats
+/// PUT like POST, so only reads and lists are safe to repeat.
+fn is_idempotent(method: &Method) -> bool {
+    method == Method::GET || method == Method::HEAD || method.as_str() == "LIST"
+}
+
+impl fmt::Display for Retry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Retry::RateLimited => write!(f, "Vault is rate limiting requests to"),
+            Retry::ServerError(status) => write!(f, "Vault responded with '{}' to the request to", status),
+            Retry::Unreachable => write!(f, "Failed to reach Vault for the request to"),
+            Retry::TimedOut => write!(f, "Vault did not respond in time to the request to"),
+            Retry::Interrupted => write!(f, "Lost the connection to Vault during the request to")
+        }
+    }
+}
+
+/// Pick a random delay between half of the one given and all of it, so that
+/// lots of clients retrying at once (eg after a leader election) are spread out.
+fn jittered(delay: Duration) -> Duration {
+    let random: [u8; 4] = ring::rand::generate(&ring::rand::SystemRandom::new())
+        .map(|r| r.expose())
+        .unwrap_or_default();
+    let fraction = u32::from_le_bytes(random) as f64 / u32::MAX as f64;
+    let jittered = delay / 2 + delay.mul_f64(fraction / 2.0);
+    Duration::from_millis(jittered.as_millis() as u64)
+}
+
+/// How long a rate limited response asks us to wait before retrying. Vault
+/// sends the number of seconds to wait in the 'Retry-After' header.
+fn retry_after(res: &Response) -> Option<Duration> {
+    let secs: u64 = res.headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+/// Where a redirect from the URL given to the location given goes. We won't send
+/// our token over plain HTTP if the original request was made over HTTPS.
+fn redirect_url(url: &Url, location: &str) -> Result<Url> {
+    let next_url = url.join(location)
+        .with_context(|| format!("Vault redirected the request to an invalid location '{}'", location))?;
+    if url.scheme() == "https" && next_url.scheme() != "https" {
+        return Err(anyhow!("Vault redirected the request to '{}', which doesn't use HTTPS", next_url));
+    }
+    Ok(next_url)
 }
 
 fn make_api_path(mut url: url::Url, path: &str) -> url::Url {
+    let (path, query) = match path.find('?') {
+        Some(idx) => (&path[0..idx], Some(&path[idx+1..])),
+        None => (path, None)
+    };
     let path = format!(
         "{prefix}/v1/{path}",
         prefix = url.path().trim_matches('/'),
         path = path.trim_matches('/')
     );
     url.set_path(&path);
+    url.set_query(query);
     url
 }
 
@@ -96,13 +486,262 @@ impl Errors {
     }
 }
 
-impl std::error::Error for Errors {}
+/// An unsuccessful response from the Vault API.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub errors: Vec<String>
+}
+
+impl ApiError {
+    /// Find the API error (if any) that led to the error provided.
+    pub fn find(err: &anyhow::Error) -> Option<&ApiError> {
+        err.chain().find_map(|e| e.downcast_ref::<ApiError>())
+    }
+}
+
+/// Was this error caused by Vault denying us permission (which is
+/// also what happens if our token is no longer valid)?
+pub fn is_permission_denied(err: &anyhow::Error) -> bool {
+    ApiError::find(err)
+        .map(|e| e.status == StatusCode::FORBIDDEN)
+        .unwrap_or(false)
+}
+
+/// Was this error caused by the Vault instance being unavailable, either
+/// because we can't connect to it or because it's a replication secondary
+/// which can't serve our requests? Requests that are slow to be answered
+/// don't count, since the instance is there; it's just busy.
+pub fn is_unavailable(err: &anyhow::Error) -> bool {
+    let is_secondary_error = ApiError::find(err)
+        .map(|e| e.errors.iter().any(|msg| msg.to_ascii_lowercase().contains("secondary")))
+        .unwrap_or(false);
+    is_not_connected(err) || is_secondary_error
+}
+
+/// Did the request fail before a connection to Vault was made, so that
+/// it can't have been acted on?
+fn is_not_connected(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| e.downcast_ref::<hyper::Error>().map(|e| e.is_connect()).unwrap_or(false))
+}
+
+/// Did Vault not respond to the request in time?
+fn is_timeout(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        e.is::<TimedOut>() || e.downcast_ref::<reqwest::Error>().map(|e| e.is_timeout()).unwrap_or(false)
+    })
+}
+
+/// Was the connection to Vault lost after the request was sent to it?
+fn is_interrupted(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| e.is::<hyper::Error>() || e.is::<std::io::Error>())
+}
+
+/// Vault didn't respond to a request within the request timeout.
+#[derive(Debug)]
+struct TimedOut {
+    path: String,
+    timeout: Duration
+}
+
+impl std::error::Error for TimedOut {}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Vault did not respond to the request to '{}' within {:?}", self.path, self.timeout)
+    }
+}
+
+impl std::error::Error for ApiError {}
 
-impl fmt::Display for Errors {
+impl fmt::Display for ApiError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.errors.is_empty() {
+            return match self.status.canonical_reason() {
+                Some(reason) => write!(f, "{} {} response from Vault", self.status.as_str(), reason),
+                None => write!(f, "{} response from Vault", self.status.as_str())
+            };
+        }
         for err in &self.errors {
-            write!(f, "{}\n", err)?;
+            writeln!(f, "{}", err)?;
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use serde_json::{ Value, json };
+
+    #[test]
+    fn parse_request_rates() {
+        let cases = vec![
+            ("10", Some(10.0)),
+            ("0.5", Some(0.5)),
+            (" 2 ", Some(2.0)),
+            ("0", None),
+            ("-1", None),
+            ("inf", None),
+            ("ten", None),
+        ];
+        for (s, expected) in cases {
+            let actual = RequestRate::from_str(s).ok().map(|r| r.0);
+            assert_eq!(actual, expected, "Unexpected rate for '{}'", s);
+        }
+    }
+
+    #[test]
+    fn redirect_urls() {
+        let cases = vec![
+            ("https://standby:8200/v1/secret/data/a", "https://active:8200/v1/secret/data/a", Some("https://active:8200/v1/secret/data/a")),
+            ("http://standby:8200/v1/secret/data/a", "http://active:8200/v1/secret/data/a?x=1", Some("http://active:8200/v1/secret/data/a?x=1")),
+            ("http://standby:8200/v1/secret/data/a", "https://active:8200/v1/secret/data/a", Some("https://active:8200/v1/secret/data/a")),
+            ("https://standby:8200/v1/secret/data/a", "/v1/secret/data/b", Some("https://standby:8200/v1/secret/data/b")),
+            ("https://standby:8200/v1/secret/data/a", "http://active:8200/v1/secret/data/a", None),
+            ("https://standby:8200/v1/secret/data/a", "http://[::1", None),
+        ];
+        for (url, location, expected) in cases {
+            let actual = redirect_url(&url.parse().unwrap(), location).ok();
+            assert_eq!(actual.as_ref().map(|u| u.as_str()), expected, "Unexpected redirect from '{}' to '{}'", url, location);
+        }
+    }
+
+    /// Serve a '503 Service Unavailable' response to every request, counting them.
+    async fn unavailable_vault() -> (Url, Arc<AtomicUsize>) {
+        use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                // Read the whole request (including any body) before responding:
+                let mut req = Vec::new();
+                let mut buf = [0u8; 1024];
+                loop {
+                    let n = socket.read(&mut buf).await.unwrap_or(0);
+                    req.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&req).to_ascii_lowercase();
+                    let header_len = match text.find("\r\n\r\n") {
+                        Some(idx) => idx + 4,
+                        None if n > 0 => continue,
+                        None => break
+                    };
+                    let content_length: usize = text.lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .and_then(|len| len.trim().parse().ok())
+                        .unwrap_or(0);
+                    if n == 0 || req.len() >= header_len + content_length {
+                        break
+                    }
+                }
+                let _ = socket.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+            }
+        });
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn only_idempotent_requests_are_retried() {
+        let (url, requests) = unavailable_vault().await;
+        let client = Client::new(url, reqwest::Client::builder())
+            .unwrap()
+            .with_retries(2, Duration::from_millis(10));
+
+        // Vault might have acted on a POST before failing, so it's not retried:
+        let res: Result<Value> = client.post("transit/datakey/plaintext/key", json!({})).await;
+        assert!(res.is_err());
+        assert_eq!(requests.swap(0, Ordering::SeqCst), 1);
+
+        // Reads are safe to retry:
+        let res: Result<Value> = client.get("secret/data/foo").await;
+        assert!(res.is_err());
+        assert_eq!(requests.swap(0, Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn server_errors_fail_over_without_resending() {
+        let (url1, requests1) = unavailable_vault().await;
+        let (url2, requests2) = unavailable_vault().await;
+        let client = Client::new_with_failover(vec![url1, url2], reqwest::Client::builder())
+            .unwrap()
+            .with_retries(2, Duration::from_millis(10));
+
+        // A POST is sent on to the next address, but never to the same one twice:
+        let res: Result<Value> = client.post("auth/approle/login", json!({})).await;
+        assert!(res.is_err());
+        assert_eq!(requests1.swap(0, Ordering::SeqCst), 1);
+        assert_eq!(requests2.swap(0, Ordering::SeqCst), 1);
+
+        // Reads go round every address on each retry:
+        let res: Result<Value> = client.get("secret/data/foo").await;
+        assert!(res.is_err());
+        assert_eq!(requests1.swap(0, Ordering::SeqCst), 3);
+        assert_eq!(requests2.swap(0, Ordering::SeqCst), 3);
+    }
+
+    /// Accept connections but never respond to them, counting them.
+    async fn silent_vault() -> (Url, Arc<AtomicUsize>) {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                sockets.push(socket);
+            }
+        });
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn slow_vault_is_not_unavailable() {
+        let (url, requests) = silent_vault().await;
+        let client = Client::new(url, reqwest::Client::builder())
+            .unwrap()
+            .with_retries(1, Duration::from_millis(10))
+            .with_request_timeout(Duration::from_millis(50));
+
+        // Reads that time out are retried, but Vault is only slow, not unavailable:
+        let err = client.get::<Value, _>("secret/data/foo").await.unwrap_err();
+        assert!(!is_unavailable(&err), "Timeout treated as unavailable: {:#}", err);
+        assert_eq!(requests.swap(0, Ordering::SeqCst), 2);
+
+        // Vault might have acted on a POST that timed out, so it's not retried:
+        let err = client.post::<Value, _, _>("auth/approle/login", json!({})).await.unwrap_err();
+        assert!(!is_unavailable(&err), "Timeout treated as unavailable: {:#}", err);
+        assert_eq!(requests.swap(0, Ordering::SeqCst), 1);
+
+        // Whereas an address that nothing is listening on is unavailable:
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+        drop(listener);
+        let client = Client::new(url, reqwest::Client::builder()).unwrap();
+        let err = client.post::<Value, _, _>("auth/approle/login", json!({})).await.unwrap_err();
+        assert!(is_unavailable(&err), "Connection failure not treated as unavailable: {:#}", err);
+    }
+
+    #[test]
+    fn jittered_delays() {
+        let cases = vec![
+            Duration::from_secs(0),
+            Duration::from_millis(500),
+            Duration::from_secs(1),
+            Duration::from_secs(10),
+        ];
+        for delay in cases {
+            for _ in 0..20 {
+                let wait = jittered(delay);
+                assert!(wait <= delay && wait >= (delay / 2).saturating_sub(Duration::from_millis(1)), "Unexpected wait {:?} for {:?}", wait, delay);
+            }
+        }
+    }
+
+}
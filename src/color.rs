@@ -0,0 +1,54 @@
+use std::str::FromStr;
+use anyhow::{ anyhow, Result };
+
+/// When should the messages that we print to stderr be colored?
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ColorMode {
+    Auto,
+    Always,
+    Never
+}
+
+impl ColorMode {
+
+    /// Decide whether to color output. In 'auto' mode, we color output if
+    /// stderr is a terminal and the 'NO_COLOR' env var is not set.
+    pub fn should_color(self) -> bool {
+        match self {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => std::env::var_os("NO_COLOR").is_none() && is_stderr_tty()
+        }
+    }
+
+    /// Apply this choice to everything that we print from now on.
+    pub fn apply(self) {
+        colored::control::set_override(self.should_color());
+    }
+
+}
+
+#[cfg(unix)]
+fn is_stderr_tty() -> bool {
+    // Safety: isatty is safe to call with any file descriptor.
+    unsafe { libc::isatty(libc::STDERR_FILENO) == 1 }
+}
+
+// Consoles on Windows might not understand the escape codes used to color
+// output, so there we only color it when asked to with 'always':
+#[cfg(not(unix))]
+fn is_stderr_tty() -> bool {
+    false
+}
+
+impl FromStr for ColorMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            _ => Err(anyhow!("'{}' is not a valid color mode (try 'auto', 'always' or 'never').", s))
+        }
+    }
+}
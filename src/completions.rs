@@ -0,0 +1,95 @@
+use std::io::Write;
+use anyhow::{ anyhow, Result };
+use structopt::clap::{ App, Shell };
+use crate::init;
+use vault_inject::secret_store::SecretStore;
+
+/// Wraps the completion function that clap generates for bash, so that the
+/// paths in '--secret' mappings are completed by asking us to list them.
+static BASH_SECRET_PATHS: &str = r#"
+_vault_inject_secret_paths() {
+    local line="${COMP_LINE:0:COMP_POINT}" words
+    read -ra words <<< "$line"
+    [[ "$line" == *[[:space:]] ]] && words+=("")
+    local cur="${words[${#words[@]}-1]}" prev="${words[${#words[@]}-2]}"
+    if [[ ( "$prev" == "--secret" || "$prev" == "-s" ) && "$cur" == *=* ]]; then
+        # Bash only hands over what comes after the '=' if it splits words there:
+        local prefix=""
+        [[ "$COMP_WORDBREAKS" != *=* ]] && prefix="${cur%%=*}="
+        local path="${cur#*=}"
+        COMPREPLY=( $(vault-inject complete-path "${path#"${path%%[![:space:]]*}"}" 2>/dev/null | sed "s|^|$prefix|") )
+        compopt -o nospace
+        return 0
+    fi
+    _vault-inject "$@"
+}
+complete -F _vault_inject_secret_paths -o bashdefault -o default vault-inject
+"#;
+
+/// Adds the paths in '--secret' mappings to the completions that clap generates for fish.
+static FISH_SECRET_PATHS: &str = r#"
+function __vault_inject_secret_paths
+    set -l token (commandline -ct)
+    string match -q -- '*=*' $token; or return
+    set -l parts (string split -m1 = -- $token)
+    for path in (vault-inject complete-path (string trim -- $parts[2]) 2>/dev/null)
+        echo "$parts[1]=$path"
+    end
+end
+complete -c vault-inject -s s -l secret -x -a '(__vault_inject_secret_paths)'
+"#;
+
+/// Write a script to complete our options in the shell given. If asked, this also
+/// completes the paths in '--secret' mappings by listing them from Vault.
+pub fn generate(mut app: App<'_,'_>, shell: Shell, secret_paths: bool, out: &mut impl Write) -> Result<()> {
+    let extra = match (secret_paths, shell) {
+        (false, _) => "",
+        (true, Shell::Bash) => BASH_SECRET_PATHS,
+        (true, Shell::Fish) => FISH_SECRET_PATHS,
+        (true, shell) => return Err(anyhow!("Secret paths can only be completed in bash and fish, not {}", shell.to_string().to_lowercase()))
+    };
+    app.gen_completions_to("vault-inject", shell, out);
+    out.write_all(extra.as_bytes())?;
+    Ok(())
+}
+
+/// The paths which begin with the partial path given: the entries in the
+/// directory it's in, or the keys of the secret if it's in a secret.
+pub async fn complete_path(store: &SecretStore, partial: &str) -> Result<Vec<String>> {
+    let (dir, prefix) = split_partial_path(partial);
+    let entries = init::describe_path(store, dir).await?;
+    Ok(entries.into_iter()
+        .filter(|entry| entry.starts_with(prefix))
+        .map(|entry| format!("{}{}", dir, entry))
+        .collect())
+}
+
+/// Split a partial path into the directory it's in and the start of the entry being typed.
+fn split_partial_path(partial: &str) -> (&str, &str) {
+    match partial.rfind('/') {
+        Some(idx) => partial.split_at(idx + 1),
+        None => ("", partial)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn split_partial_paths() {
+        let cases = vec![
+            ("", ("", "")),
+            ("sec", ("", "sec")),
+            ("/sec", ("/", "sec")),
+            ("/secret/", ("/secret/", "")),
+            ("/secret/app/d", ("/secret/app/", "d")),
+            ("secret/app/db/pa", ("secret/app/db/", "pa")),
+        ];
+        for (partial, expected) in cases {
+            assert_eq!(split_partial_path(partial), expected, "Unexpected split of '{}'", partial);
+        }
+    }
+
+}
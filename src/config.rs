@@ -0,0 +1,435 @@
+use directories::BaseDirs;
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::time::SystemTime;
+use anyhow::{ anyhow, Result, Context };
+use serde::Deserialize;
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use url::Url;
+use vault_inject::auth::AuthType;
+use vault_inject::processor::ProcessorDef;
+use vault_inject::secret_mapping::SecretMapping;
+use vault_inject::Shell;
+
+/// Configuration that lives in a file rather than on the command line.
+#[derive(Debug,Default,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Named groups of secret mappings, pulled in with '--bundle'.
+    #[serde(default)]
+    bundles: HashMap<String,Bundle>,
+    /// Named sets of options, picked with '--profile'.
+    #[serde(default, rename = "profile")]
+    profiles: HashMap<String,ProfileConfig>,
+    /// Named chains of processors, which mappings can pipe secrets through by name.
+    #[serde(default)]
+    processors: HashMap<String,String>
+}
+
+#[derive(Debug,Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Bundle {
+    secrets: Vec<String>
+}
+
+#[derive(Debug,Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ProfileConfig {
+    vault_url: Option<String>,
+    dr_vault_url: Option<String>,
+    auth_type: Option<String>,
+    auth_path: Option<String>,
+    shell: Option<String>,
+    #[serde(default)]
+    secrets: Vec<String>,
+    #[serde(default)]
+    require: Vec<String>
+}
+
+/// The options set in a profile. Options given on the command line take precedence.
+#[derive(Debug,Default)]
+pub struct Profile {
+    pub vault_url: Option<Url>,
+    pub dr_vault_url: Option<Url>,
+    pub auth_types: Vec<AuthType>,
+    pub auth_path: Option<String>,
+    pub shell: Option<Shell>,
+    pub secrets: Vec<SecretMapping>,
+    /// Environment variables which must be resolved from some secret.
+    pub require: Vec<String>
+}
+
+impl Config {
+
+    /// Load the config from the path given. If no path is given, we look for
+    /// the user specific config file, returning an empty config if there isn't one.
+    pub async fn load(path: Option<&Path>) -> Result<Config> {
+        let path = match Config::find_path(path) {
+            Some(path) => path,
+            None => return Ok(Config::default())
+        };
+
+        let contents = fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Failed to read the config file '{}'", path.display()))?;
+        Config::from_str(&contents)
+            .with_context(|| format!("Failed to parse the config file '{}'", path.display()))
+    }
+
+    /// Find the config file to load; the path given, or else the user
+    /// specific config file if it exists.
+    pub fn find_path(path: Option<&Path>) -> Option<PathBuf> {
+        match path {
+            Some(path) => Some(path.to_owned()),
+            None => Config::default_path().filter(|path| path.exists())
+        }
+    }
+
+    /// The user specific config file, eg '~/.config/vault_inject/config.toml'.
+    pub fn default_path() -> Option<PathBuf> {
+        let base_dirs = BaseDirs::new()?;
+        Some(base_dirs.config_dir().join("vault_inject").join("config.toml"))
+    }
+
+    /// The secret mappings in the bundle with the name given.
+    pub fn bundle(&self, name: &str) -> Result<Vec<SecretMapping>> {
+        let bundle = self.bundles.get(name)
+            .ok_or_else(|| not_found("bundle", name, &self.bundles))?;
+
+        bundle.secrets
+            .iter()
+            .map(|s| SecretMapping::from_str(s)
+                .with_context(|| format!("Invalid secret in the bundle '{}'", name)))
+            .collect()
+    }
+
+    /// The named processor chains that are defined.
+    pub fn processor_defs(&self) -> Result<Vec<ProcessorDef>> {
+        self.processors
+            .iter()
+            .map(|(name, chain)| ProcessorDef::new(name, chain)
+                .with_context(|| format!("Invalid processor '{}'", name)))
+            .collect()
+    }
+
+    /// The options in the profile with the name given.
+    pub fn profile(&self, name: &str) -> Result<Profile> {
+        let profile = self.profiles.get(name)
+            .ok_or_else(|| not_found("profile", name, &self.profiles))?;
+        profile.parse()
+            .with_context(|| format!("Invalid option in the profile '{}'", name))
+    }
+
+}
+
+impl ProfileConfig {
+
+    /// Parse the options given as strings.
+    fn parse(&self) -> Result<Profile> {
+        let parse_url = |url: &Option<String>| url.as_ref()
+            .map(|url| url.parse().with_context(|| format!("'{}' is not a valid URL", url)))
+            .transpose();
+        let auth_types = self.auth_type.iter()
+            .flat_map(|s| s.split(','))
+            .map(AuthType::from_str)
+            .collect::<Result<_>>()?;
+        let shell = self.shell.as_deref().map(Shell::from_str).transpose()?;
+        let secrets = self.secrets
+            .iter()
+            .map(|s| SecretMapping::from_str(s))
+            .collect::<Result<_>>()?;
+
+        Ok(Profile {
+            vault_url: parse_url(&self.vault_url)?,
+            dr_vault_url: parse_url(&self.dr_vault_url)?,
+            auth_types,
+            auth_path: self.auth_path.clone(),
+            shell,
+            secrets,
+            require: self.require.clone()
+        })
+    }
+
+}
+
+/// The names of the project files that we look for.
+static PROJECT_FILE_NAMES: &[&str] = &[".vault-inject", "vault-inject.toml"];
+
+/// Find the nearest project file, looking in the directory given and then in each of its parents.
+pub fn find_project_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .flat_map(|dir| PROJECT_FILE_NAMES.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+/// Load the options in a project file. These are written like a profile, but can't
+/// say which Vault instance to talk to, since they are checked into repositories that
+/// we might not trust with our credentials.
+pub async fn load_project_file(path: &Path) -> Result<Profile> {
+    let contents = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read the project file '{}'", path.display()))?;
+    parse_project_file(&contents)
+        .with_context(|| format!("Failed to parse the project file '{}'", path.display()))
+}
+
+fn parse_project_file(contents: &str) -> Result<Profile> {
+    let project: ProfileConfig = toml::from_str(contents)?;
+    if project.vault_url.is_some() || project.dr_vault_url.is_some() {
+        return Err(anyhow!("Project files can't set 'vault-url' or 'dr-vault-url' (use '--vault-url', VAULT_ADDR or a profile instead)"));
+    }
+    project.parse()
+}
+
+/// What to fetch, described by a JSON document (given with '--spec') rather than on
+/// the command line, for tools that drive us programmatically. Options are named as
+/// in profiles, and options given on the command line take precedence.
+#[derive(Debug,Default)]
+pub struct Spec {
+    pub profile: Profile,
+    pub namespace: Option<String>,
+    pub token: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>
+}
+
+#[derive(Debug,Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct SpecConfig {
+    vault_url: Option<String>,
+    dr_vault_url: Option<String>,
+    auth_type: Option<String>,
+    auth_path: Option<String>,
+    namespace: Option<String>,
+    token: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default)]
+    secrets: Vec<String>,
+    #[serde(default)]
+    require: Vec<String>
+}
+
+/// Load a spec from the path given, or from stdin if the path is '-'.
+pub async fn load_spec(path: &Path) -> Result<Spec> {
+    let is_stdin = path == Path::new("-");
+    let contents = if is_stdin {
+        let mut contents = String::new();
+        tokio::io::stdin().read_to_string(&mut contents)
+            .await
+            .context("Failed to read the spec from stdin")?;
+        contents
+    } else {
+        fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read the spec '{}'", path.display()))?
+    };
+    parse_spec(&contents).with_context(|| match is_stdin {
+        true => "Failed to parse the spec from stdin".to_owned(),
+        false => format!("Failed to parse the spec '{}'", path.display())
+    })
+}
+
+fn parse_spec(contents: &str) -> Result<Spec> {
+    let spec: SpecConfig = serde_json::from_str(contents)?;
+    let profile = ProfileConfig {
+        vault_url: spec.vault_url,
+        dr_vault_url: spec.dr_vault_url,
+        auth_type: spec.auth_type,
+        auth_path: spec.auth_path,
+        shell: None,
+        secrets: spec.secrets,
+        require: spec.require
+    };
+    Ok(Spec {
+        profile: profile.parse()?,
+        namespace: spec.namespace,
+        token: spec.token,
+        username: spec.username,
+        password: spec.password
+    })
+}
+
+/// Complain that some named thing isn't in the config, listing the things that are.
+fn not_found<T>(kind: &str, name: &str, things: &HashMap<String,T>) -> anyhow::Error {
+    let mut names: Vec<&str> = things.keys().map(|n| &**n).collect();
+    names.sort_unstable();
+    if names.is_empty() {
+        anyhow!("There is no {} called '{}' (no {}s are defined in the config)", kind, name, kind)
+    } else {
+        anyhow!("There is no {} called '{}' in the config (available {}s: {})", kind, name, kind, names.join(", "))
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Config> {
+        Ok(toml::from_str(s)?)
+    }
+}
+
+/// Notice when the config file changes, so that long running
+/// processes can pick up the changes.
+pub struct ConfigWatch {
+    path: PathBuf,
+    modified: Option<SystemTime>
+}
+
+impl ConfigWatch {
+
+    pub fn new(path: PathBuf) -> ConfigWatch {
+        let modified = modified_time(&path);
+        ConfigWatch { path, modified }
+    }
+
+    /// The config file being watched.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Has the config file changed since we last asked?
+    pub fn has_changed(&mut self) -> bool {
+        let modified = modified_time(&self.path);
+        let has_changed = modified != self.modified;
+        self.modified = modified;
+        has_changed
+    }
+
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn load_bundles() {
+
+        let config = Config::from_str(r#"
+            [bundles.postgres]
+            secrets = [
+                "PGUSER = /secret/db/user",
+                "PGPASSWORD = /secret/db/password",
+            ]
+
+            [bundles.broken]
+            secrets = [ "nope" ]
+        "#).unwrap();
+
+        let postgres: Vec<String> = config.bundle("postgres").unwrap()
+            .iter()
+            .map(|m| m.to_string())
+            .collect();
+        assert_eq!(postgres, vec!["PGUSER = secret/db/user", "PGPASSWORD = secret/db/password"]);
+
+        assert!(config.bundle("broken").is_err(), "Invalid mappings should be an error");
+        assert!(config.bundle("aws").is_err(), "Unknown bundles should be an error");
+        assert!(Config::from_str("[wibble]").is_err(), "Unknown config should be an error");
+
+    }
+
+    #[test]
+    fn load_processor_defs() {
+
+        let config = Config::from_str(r#"
+            [processors]
+            pgpass = "base64 --decode | @trim"
+        "#).unwrap();
+        assert_eq!(config.processor_defs().unwrap(), vec![ProcessorDef::new("pgpass", "base64 --decode | @trim").unwrap()]);
+
+        let broken = Config::from_str("[processors]\npgpass = \"base64 |\"").unwrap();
+        assert!(broken.processor_defs().is_err(), "Invalid processors should be an error");
+
+    }
+
+    #[test]
+    fn load_profiles() {
+
+        let config = Config::from_str(r#"
+            [profile.prod]
+            vault-url = "https://vault.example.com"
+            auth-type = "token,ldap"
+            auth-path = "corp-ldap"
+            shell = "/bin/bash"
+            secrets = [ "PGPASSWORD = /secret/db/password" ]
+            require = [ "PGPASSWORD", "PGUSER" ]
+
+            [profile.empty]
+
+            [profile.broken]
+            auth-type = "nope"
+        "#).unwrap();
+
+        let prod = config.profile("prod").unwrap();
+        assert_eq!(prod.vault_url.unwrap().as_str(), "https://vault.example.com/");
+        assert_eq!(prod.dr_vault_url, None);
+        assert_eq!(prod.auth_types, vec![AuthType::Token, AuthType::Ldap]);
+        assert_eq!(prod.auth_path.as_deref(), Some("corp-ldap"));
+        assert_eq!(prod.shell.as_ref().map(|s| s.program()), Some("/bin/bash"));
+        assert_eq!(prod.secrets.len(), 1);
+        assert_eq!(prod.require, vec!["PGPASSWORD", "PGUSER"]);
+
+        let empty = config.profile("empty").unwrap();
+        assert!(empty.vault_url.is_none() && empty.auth_types.is_empty() && empty.secrets.is_empty() && empty.require.is_empty());
+
+        assert!(config.profile("broken").is_err(), "Invalid options should be an error");
+        assert!(config.profile("dev").is_err(), "Unknown profiles should be an error");
+        assert!(Config::from_str("[profile.prod]\nwibble = 1").is_err(), "Unknown options should be an error");
+
+    }
+
+    #[test]
+    fn load_specs() {
+
+        let spec = parse_spec(r#"{
+            "vault-url": "https://vault.example.com",
+            "auth-type": "token",
+            "token": "s.TOKEN",
+            "secrets": [ "PGPASSWORD = /secret/db/password" ]
+        }"#).unwrap();
+        assert_eq!(spec.profile.vault_url, Some("https://vault.example.com".parse().unwrap()));
+        assert_eq!(spec.profile.auth_types, vec![AuthType::Token]);
+        assert_eq!(spec.token.as_deref(), Some("s.TOKEN"));
+        assert_eq!(spec.profile.secrets.len(), 1);
+
+        let cases = vec![
+            "",
+            "{ \"wibble\": 1 }",
+            "{ \"shell\": \"bash\" }",
+            "{ \"secrets\": [ \"no mapping\" ] }",
+            "{ \"vault-url\": \"not a url\" }",
+        ];
+        for contents in cases {
+            assert!(parse_spec(contents).is_err(), "Expected '{}' to be rejected", contents);
+        }
+
+    }
+
+    #[test]
+    fn load_project_files() {
+
+        let project = parse_project_file(r#"
+            auth-type = "ldap"
+            secrets = [ "PGPASSWORD = /secret/db/password" ]
+        "#).unwrap();
+        assert_eq!(project.auth_types, vec![AuthType::Ldap]);
+        assert_eq!(project.secrets.len(), 1);
+
+        let cases = vec![
+            "vault-url = \"https://elsewhere.example.com\"",
+            "dr-vault-url = \"https://elsewhere.example.com\"",
+            "[profile.prod]",
+        ];
+        for contents in cases {
+            assert!(parse_project_file(contents).is_err(), "Expected '{}' to be rejected", contents);
+        }
+
+    }
+
+}
@@ -0,0 +1,116 @@
+use std::num::NonZeroU32;
+use anyhow::{ anyhow, Result };
+use ring::aead::{ Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN };
+use ring::rand::{ SecureRandom, SystemRandom };
+use ring::pbkdf2;
+use serde::{ Deserialize, Serialize };
+
+/// How many PBKDF2 iterations to use when deriving a key from a passphrase.
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// A 256 bit key to encrypt things with.
+#[derive(Clone,PartialEq,Eq)]
+pub struct Key([u8; 32]);
+
+impl Key {
+
+    /// A new random key.
+    pub fn random() -> Result<Key> {
+        Ok(Key(random_bytes()?))
+    }
+
+    /// Derive a key from a passphrase and salt.
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> Key {
+        let mut key = [0; 32];
+        let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).expect("non-zero iterations");
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+        Key(key)
+    }
+
+    /// Load a key that was saved with [`Key::to_base64`].
+    pub fn from_base64(s: &str) -> Result<Key> {
+        let bytes = base64::decode(s.trim())?;
+        let mut key = [0; 32];
+        if bytes.len() != key.len() {
+            return Err(anyhow!("Expected a {} byte key but got {} bytes", key.len(), bytes.len()));
+        }
+        key.copy_from_slice(&bytes);
+        Ok(Key(key))
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.0)
+    }
+
+    /// Encrypt some data (using ChaCha20-Poly1305 with a random nonce).
+    pub fn encrypt(&self, data: &[u8]) -> Result<Encrypted> {
+        let nonce: [u8; NONCE_LEN] = random_bytes()?;
+        let mut in_out = data.to_vec();
+        self.aead_key()
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut in_out)
+            .map_err(|_| anyhow!("Failed to encrypt data"))?;
+        Ok(Encrypted { nonce: base64::encode(nonce), data: base64::encode(in_out) })
+    }
+
+    /// Decrypt some data, failing if it wasn't encrypted with this key.
+    pub fn decrypt(&self, encrypted: &Encrypted) -> Result<Vec<u8>> {
+        let bad_data = || anyhow!("Failed to decrypt data (the key may be wrong)");
+        let nonce = Nonce::try_assume_unique_for_key(&base64::decode(&encrypted.nonce)?)
+            .map_err(|_| bad_data())?;
+        let mut in_out = base64::decode(&encrypted.data)?;
+        let data = self.aead_key()
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .map_err(|_| bad_data())?;
+        Ok(data.to_vec())
+    }
+
+    fn aead_key(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &self.0).expect("valid key length"))
+    }
+
+}
+
+/// Some encrypted data, and the nonce it was encrypted with (both base64 encoded).
+#[derive(Debug,Clone,PartialEq,Eq,Serialize,Deserialize)]
+pub struct Encrypted {
+    nonce: String,
+    data: String
+}
+
+/// Some cryptographically secure random bytes.
+pub fn random_bytes<T: AsMut<[u8]> + Default>() -> Result<T> {
+    let mut bytes = T::default();
+    SystemRandom::new()
+        .fill(bytes.as_mut())
+        .map_err(|_| anyhow!("Failed to generate random bytes"))?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        let salt = b"0123456789abcdef";
+        let key = Key::from_passphrase("correct horse", salt);
+        let other_keys = vec![
+            Key::from_passphrase("wrong horse", salt),
+            Key::from_passphrase("correct horse", b"fedcba9876543210"),
+            Key::random().unwrap(),
+        ];
+
+        let encrypted = key.encrypt(b"s.TOKEN").unwrap();
+        assert_eq!(key.decrypt(&encrypted).unwrap(), b"s.TOKEN");
+        assert_ne!(key.encrypt(b"s.TOKEN").unwrap(), encrypted, "Nonces should differ each time");
+        for other_key in other_keys {
+            assert!(other_key.decrypt(&encrypted).is_err());
+        }
+
+        let key = Key::random().unwrap();
+        assert!(Key::from_base64(&key.to_base64()).unwrap() == key);
+        assert!(Key::from_base64("c2hvcnQ=").is_err());
+    }
+
+}
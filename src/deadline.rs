@@ -0,0 +1,70 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A point in time by which we need to be done waiting on Vault (or
+/// never, if no timeout was given).
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Deadline {
+    at: Option<(Instant, Duration)>
+}
+
+impl Deadline {
+
+    /// A deadline this long from now, if a timeout is given.
+    pub fn after(timeout: Option<Duration>) -> Deadline {
+        Deadline { at: timeout.map(|timeout| (Instant::now() + timeout, timeout)) }
+    }
+
+    /// No deadline at all.
+    pub fn none() -> Deadline {
+        Deadline { at: None }
+    }
+
+    /// Wait for the future to complete, returning None if the deadline passes first.
+    pub async fn within<F: Future>(&self, fut: F) -> Option<F::Output> {
+        match self.at {
+            Some((at, _)) => tokio::time::timeout_at(at, fut).await.ok(),
+            None => Some(fut.await)
+        }
+    }
+
+    /// Wait for the future to complete, failing if the deadline passes first.
+    pub async fn run<T>(&self, waiting_for: &str, fut: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+        self.within(fut).await.unwrap_or_else(|| Err(self.error(waiting_for)))
+    }
+
+    /// The error to give when the deadline passes, saying what we were still waiting for.
+    pub fn error(&self, waiting_for: &str) -> anyhow::Error {
+        let timeout = self.at.map(|(_, timeout)| timeout).unwrap_or_default();
+        anyhow::anyhow!("Timed out after {:?} (see '--timeout') while {}", timeout, waiting_for)
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn waiting_within_deadlines() {
+        let slow = || tokio::time::delay_for(Duration::from_millis(200));
+        let cases = vec![
+            (Deadline::none(), true),
+            (Deadline::after(None), true),
+            (Deadline::after(Some(Duration::from_secs(10))), true),
+            (Deadline::after(Some(Duration::from_millis(10))), false),
+        ];
+        for (deadline, completes) in cases {
+            assert_eq!(deadline.within(slow()).await.is_some(), completes, "Unexpected result for {:?}", deadline);
+        }
+
+        let err = Deadline::after(Some(Duration::from_millis(10)))
+            .run("logging in", async { slow().await; Ok(()) })
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Timed out after 10ms (see '--timeout') while logging in");
+    }
+
+}
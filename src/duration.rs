@@ -0,0 +1,149 @@
+use std::str::FromStr;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use anyhow::{ anyhow, Result };
+
+/// Parse a human friendly duration like '500ms', '10s', '5m' or '1h'.
+/// A plain number is interpreted as a number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let idx = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num_str, unit) = (&s[0..idx], s[idx..].trim());
+
+    let num: u64 = num_str.parse()
+        .map_err(|_| anyhow!("Expected a duration like '10s' or '5m' but got '{}'", s))?;
+
+    let duration = match unit {
+        "ms" => Duration::from_millis(num),
+        "" | "s" => Duration::from_secs(num),
+        "m" => Duration::from_secs(num * 60),
+        "h" => Duration::from_secs(num * 60 * 60),
+        "d" => Duration::from_secs(num * 60 * 60 * 24),
+        _ => return Err(anyhow!("'{}' is not a valid duration unit in '{}' (try 'ms', 's', 'm', 'h' or 'd')", unit, s))
+    };
+    Ok(duration)
+}
+
+/// A time in UTC, formatted like '2020-03-15T09:45:02Z' (RFC 3339).
+pub fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Convert days since the epoch into a civil date:
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z"
+        , year, month, day
+        , secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+}
+
+/// An exponential backoff, starting at some minimum delay and
+/// doubling each time up to some maximum delay. This is written as
+/// '1s..60s', or just '5s' for a constant delay.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration
+}
+
+impl Backoff {
+    /// The first delay to wait for.
+    pub fn min(&self) -> Duration {
+        self.min
+    }
+    /// The longest delay we'll wait for.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+    /// Given the last delay, return the next one.
+    pub fn next(&self, last: Duration) -> Duration {
+        std::cmp::min(last * 2, self.max)
+    }
+}
+
+impl FromStr for Backoff {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Backoff> {
+        let (min, max) = match s.find("..") {
+            Some(idx) => (parse_duration(&s[0..idx])?, parse_duration(&s[idx+2..])?),
+            None => { let d = parse_duration(s)?; (d, d) }
+        };
+        if min > max {
+            return Err(anyhow!("The backoff '{}' should go from a smaller to a larger duration", s));
+        }
+        Ok(Backoff { min, max })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+
+        let cases = vec![
+            ("10", Some(Duration::from_secs(10))),
+            ("10s", Some(Duration::from_secs(10))),
+            (" 10s ", Some(Duration::from_secs(10))),
+            ("250ms", Some(Duration::from_millis(250))),
+            ("5m", Some(Duration::from_secs(300))),
+            ("2h", Some(Duration::from_secs(7200))),
+            ("1d", Some(Duration::from_secs(86400))),
+            // Invalid units or numbers:
+            ("10x", None),
+            ("s", None),
+            ("-1s", None),
+            ("", None),
+        ];
+
+        for (s, expected) in cases {
+            assert_eq!(parse_duration(s).ok(), expected, "Unexpected result parsing duration '{}'", s);
+        }
+
+    }
+
+    #[test]
+    fn format_utc_timestamps() {
+        let cases = vec![
+            (0, "1970-01-01T00:00:00Z"),
+            (951_782_400, "2000-02-29T00:00:00Z"),
+            (1_584_265_502, "2020-03-15T09:45:02Z"),
+        ];
+        for (secs, expected) in cases {
+            assert_eq!(utc_timestamp(UNIX_EPOCH + Duration::from_secs(secs)), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_backoff() {
+
+        let secs = Duration::from_secs;
+        let cases = vec![
+            ("1s..60s", Some((secs(1), secs(60)))),
+            ("5s", Some((secs(5), secs(5)))),
+            ("500ms..1m", Some((Duration::from_millis(500), secs(60)))),
+            // The minimum can't exceed the maximum:
+            ("60s..1s", None),
+            ("1s..", None),
+        ];
+
+        for (s, expected) in cases {
+            let actual = Backoff::from_str(s).ok().map(|b| (b.min(), b.max()));
+            assert_eq!(actual, expected, "Unexpected result parsing backoff '{}'", s);
+        }
+
+        let backoff = Backoff::from_str("1s..5s").unwrap();
+        assert_eq!(backoff.next(secs(1)), secs(2));
+        assert_eq!(backoff.next(secs(4)), secs(5));
+    }
+
+}
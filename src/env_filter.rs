@@ -0,0 +1,153 @@
+use std::str::FromStr;
+use regex::Regex;
+use anyhow::{ anyhow, Result };
+use std::process::Command;
+use crate::glob::Glob;
+
+/// Our own environment variables which hold credentials. These aren't passed
+/// on to the commands that we run unless we're asked to.
+static CREDENTIAL_ENV_VARS: &[&str] = &[
+    "VAULT_INJECT_PASSWORD", "VAULT_INJECT_TOKEN", "VAULT_INJECT_GITHUB_TOKEN", "VAULT_INJECT_JWT",
+    "VAULT_INJECT_CACHE_PASSPHRASE", "VAULT_INJECT_MFA_PASSCODE"
+];
+
+/// Decide which of our own environment variables are passed on
+/// to the commands that we run.
+#[derive(Debug,Clone,Default)]
+pub struct EnvFilter {
+    allow: Vec<EnvPattern>,
+    deny: Vec<EnvPattern>,
+    clear: bool,
+    keep_credentials: bool
+}
+
+impl EnvFilter {
+
+    pub fn new(allow: Vec<EnvPattern>, deny: Vec<EnvPattern>) -> EnvFilter {
+        EnvFilter { allow, deny, clear: false, keep_credentials: false }
+    }
+
+    /// If true, nothing is passed on unless it matches one of the allow patterns.
+    pub fn with_clear(mut self, clear: bool) -> EnvFilter {
+        self.clear = clear;
+        self
+    }
+
+    /// If true, the environment variables holding our own credentials (eg
+    /// 'VAULT_INJECT_PASSWORD') are passed on like any other.
+    pub fn with_keep_credentials(mut self, keep_credentials: bool) -> EnvFilter {
+        self.keep_credentials = keep_credentials;
+        self
+    }
+
+    /// Should the environment variable with this name be passed on? If any
+    /// allow patterns are given (or we've been asked to clear everything), it
+    /// must match one of them. It must not match any of the deny patterns, or
+    /// hold our credentials.
+    pub fn is_allowed(&self, name: &str) -> bool {
+        let is_allowed = (self.allow.is_empty() && !self.clear) || self.allow.iter().any(|p| p.matches(name));
+        let is_denied = self.deny.iter().any(|p| p.matches(name)) || self.is_credential(name);
+        is_allowed && !is_denied
+    }
+
+    fn is_credential(&self, name: &str) -> bool {
+        !self.keep_credentials && CREDENTIAL_ENV_VARS.contains(&name)
+    }
+
+    /// Replace the environment that the command would inherit from us
+    /// with just those variables which are allowed through.
+    pub fn apply(&self, cmd: &mut Command) {
+        if self.allow.is_empty() && self.deny.is_empty() && !self.clear {
+            for name in CREDENTIAL_ENV_VARS.iter().filter(|name| self.is_credential(name)) {
+                cmd.env_remove(name);
+            }
+            return
+        }
+        cmd.env_clear();
+        for (name, value) in std::env::vars_os() {
+            if self.is_allowed(&name.to_string_lossy()) {
+                cmd.env(name, value);
+            }
+        }
+    }
+
+}
+
+/// A pattern to match environment variable names against. This is
+/// a glob like 'AWS_*', or a regex if wrapped in slashes like '/^AWS_/'.
+#[derive(Debug,Clone)]
+pub enum EnvPattern {
+    Glob(Glob),
+    Regex(Regex)
+}
+
+impl EnvPattern {
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            EnvPattern::Glob(glob) => glob.matches(name),
+            EnvPattern::Regex(re) => re.is_match(name)
+        }
+    }
+}
+
+impl FromStr for EnvPattern {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<EnvPattern> {
+        let s = s.trim();
+        if s.len() > 1 && s.starts_with('/') && s.ends_with('/') {
+            let re = Regex::new(&s[1..s.len()-1])
+                .map_err(|e| anyhow!("Invalid regex '{}': {}", s, e))?;
+            Ok(EnvPattern::Regex(re))
+        } else {
+            Ok(EnvPattern::Glob(Glob::new(s)?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn patterns(ps: &[&str]) -> Vec<EnvPattern> {
+        ps.iter().map(|p| EnvPattern::from_str(p).unwrap()).collect()
+    }
+
+    #[test]
+    fn filter_env_vars() {
+
+        let cases = vec![
+            // With no patterns, everything is allowed:
+            (vec![], vec![], false, "AWS_SECRET_ACCESS_KEY", true),
+            // Deny patterns remove things:
+            (vec![], vec!["AWS_*"], false, "AWS_SECRET_ACCESS_KEY", false),
+            (vec![], vec!["AWS_*"], false, "HOME", true),
+            (vec![], vec!["/^AWS_|TOKEN$/"], false, "GITHUB_TOKEN", false),
+            // Allow patterns restrict everything else:
+            (vec!["PATH", "HOME"], vec![], false, "HOME", true),
+            (vec!["PATH", "HOME"], vec![], false, "USER", false),
+            // Deny patterns take priority:
+            (vec!["*"], vec!["USER"], false, "USER", false),
+            // Clearing removes everything that isn't allowed:
+            (vec![], vec![], true, "HOME", false),
+            (vec!["PATH"], vec![], true, "PATH", true),
+            (vec!["PATH"], vec![], true, "HOME", false),
+            // Our credentials are never passed on by default:
+            (vec![], vec![], false, "VAULT_INJECT_PASSWORD", false),
+            (vec!["VAULT_INJECT_*"], vec![], false, "VAULT_INJECT_TOKEN", false),
+            (vec![], vec![], false, "VAULT_INJECT_CACHE_PASSPHRASE", false),
+            (vec!["VAULT_INJECT_*"], vec![], true, "VAULT_INJECT_MFA_PASSCODE", false),
+            (vec![], vec![], false, "VAULT_INJECT_USERNAME", true),
+        ];
+
+        for (allow, deny, clear, name, expected) in cases {
+            let filter = EnvFilter::new(patterns(&allow), patterns(&deny)).with_clear(clear);
+            assert_eq!(filter.is_allowed(name), expected, "Unexpected result filtering '{}' with allow {:?}, deny {:?} and clear {}", name, allow, deny, clear);
+        }
+
+        let filter = EnvFilter::default().with_keep_credentials(true);
+        assert!(filter.is_allowed("VAULT_INJECT_PASSWORD"), "Credentials should be passed on if asked");
+
+    }
+
+}
@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use anyhow::{ anyhow, Result, Context };
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+#[cfg(unix)]
+use std::ffi::CString;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+/// There are no fds to hand secrets over in on Windows ('memfd:' mappings fail there).
+#[cfg(not(unix))]
+pub type RawFd = i32;
+
+/// Options that apply to every file that we write out. Windows has no
+/// file modes or owners for these to set.
+#[derive(Debug,Clone,Default)]
+#[cfg_attr(not(unix), allow(dead_code))]
+pub struct FileOptions {
+    pub mode: Option<FileMode>,
+    pub owner: Option<FileOwner>
+}
+
+/// Atomically write some contents to a file, by writing to a temporary file
+/// alongside it and then renaming that over the top. Nothing is written if the
+/// file already has exactly these contents. Returns true if the file changed.
+pub async fn write_file(path: &Path, contents: &[u8], opts: &FileOptions) -> Result<bool> {
+    if let Ok(existing) = fs::read(path).await {
+        if existing == contents {
+            return Ok(false)
+        }
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create the directory '{}'", parent.display()))?;
+    }
+
+    let file_name = path.file_name()
+        .ok_or_else(|| anyhow!("Cannot write to '{}'; it is not a file", path.display()))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let res = write_tmp_file(&tmp_path, contents, opts).await;
+    let res = match res {
+        Ok(()) => fs::rename(&tmp_path, path)
+            .await
+            .with_context(|| format!("Failed to move the temporary file into place at '{}'", path.display())),
+        Err(e) => Err(e)
+    };
+
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path).await;
+    }
+    res.map(|_| true)
+}
+
+#[cfg_attr(not(unix), allow(unused_variables))]
+async fn write_tmp_file(tmp_path: &Path, contents: &[u8], opts: &FileOptions) -> Result<()> {
+    // Create the file with the mode asked for so that it's never readable by others
+    // (Windows has no file modes, so there the mode is ignored):
+    let mut open_opts = std::fs::OpenOptions::new();
+    open_opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        open_opts.mode(opts.mode.map(|m| m.0).unwrap_or(0o666));
+    }
+    let mut file = fs::OpenOptions::from(open_opts)
+        .open(tmp_path)
+        .await
+        .with_context(|| format!("Failed to create the file '{}'", tmp_path.display()))?;
+    file.write_all(contents)
+        .await
+        .with_context(|| format!("Failed to write to the file '{}'", tmp_path.display()))?;
+    file.sync_all()
+        .await
+        .with_context(|| format!("Failed to sync the file '{}' to disk", tmp_path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Some(mode) = opts.mode {
+            fs::set_permissions(tmp_path, std::fs::Permissions::from_mode(mode.0))
+                .await
+                .with_context(|| format!("Failed to set the mode of '{}' to {}", tmp_path.display(), mode))?;
+        }
+        if let Some(owner) = opts.owner {
+            chown(tmp_path, owner)
+                .with_context(|| format!("Failed to change the owner of '{}'", tmp_path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Files that secrets have been written to, which are removed again
+/// when this is dropped (unless we've been asked to keep them). This
+/// also holds the FIFOs and memfds that secrets are handed over through,
+/// which are always removed (or closed) when this is dropped.
+#[derive(Debug,Default)]
+pub struct SecretFiles {
+    paths: Vec<PathBuf>,
+    fifos: HashMap<String,Fifo>,
+    #[cfg(unix)]
+    memfds: HashMap<String,RawFd>,
+    keep: bool
+}
+
+/// A FIFO in a private temporary directory. While it's armed, a background
+/// thread waits to write the contents to the next thing to open it.
+#[derive(Debug)]
+struct Fifo {
+    dir: PathBuf,
+    path: PathBuf,
+    contents: Arc<Mutex<Vec<u8>>>,
+    armed: Arc<AtomicBool>
+}
+
+impl Fifo {
+    /// Let the secret be read from the FIFO (once), if it can't be already.
+    fn arm(&self) {
+        if self.armed.swap(true, Ordering::SeqCst) {
+            return
+        }
+        let (path, contents, armed) = (self.path.clone(), self.contents.clone(), self.armed.clone());
+        std::thread::spawn(move || {
+            // This blocks until something opens the FIFO to read from it, and fails
+            // once the FIFO has been removed. Readers might not read everything, so
+            // we ignore errors writing to it:
+            if let Ok(mut fifo) = std::fs::OpenOptions::new().write(true).open(&path) {
+                let contents = contents.lock().unwrap().clone();
+                let _ = fifo.write_all(&contents);
+            }
+            armed.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+impl SecretFiles {
+
+    pub fn new() -> SecretFiles {
+        SecretFiles::default()
+    }
+
+    /// Write a secret to a file, remembering to remove it later.
+    /// Returns true if the file changed.
+    pub async fn write(&mut self, path: &Path, contents: &[u8], opts: &FileOptions) -> Result<bool> {
+        let changed = write_file(path, contents, opts).await?;
+        if !self.paths.iter().any(|p| p == path) {
+            self.paths.push(path.to_owned());
+        }
+        Ok(changed)
+    }
+
+    /// Hand a secret over through a FIFO, returning its path. The secret can be read
+    /// from it once (and once more after each call to 'rearm'). Calling this again
+    /// with the same name updates the secret rather than creating a new FIFO.
+    pub fn fifo(&mut self, name: &str, contents: &[u8]) -> Result<PathBuf> {
+        if let Some(fifo) = self.fifos.get(name) {
+            *fifo.contents.lock().unwrap() = contents.to_vec();
+            return Ok(fifo.path.clone())
+        }
+
+        let (dir, path) = make_fifo()?;
+        let fifo = Fifo {
+            dir,
+            path: path.clone(),
+            contents: Arc::new(Mutex::new(contents.to_vec())),
+            armed: Arc::new(AtomicBool::new(false))
+        };
+        fifo.arm();
+        self.fifos.insert(name.to_owned(), fifo);
+        Ok(path)
+    }
+
+    /// Hand a secret over through an anonymous memory file, returning its fd
+    /// (which is inherited by the commands we run). Calling this again with the
+    /// same name rewrites the secret rather than creating a new memfd.
+    #[cfg(unix)]
+    pub fn memfd(&mut self, name: &str, contents: &[u8]) -> Result<RawFd> {
+        let fd = match self.memfds.get(name) {
+            Some(&fd) => fd,
+            None => {
+                let fd = memfd_create(name)?;
+                self.memfds.insert(name.to_owned(), fd);
+                fd
+            }
+        };
+        // Safety: the fd is a memfd that we own, and we write from a valid buffer.
+        let written = unsafe {
+            if libc::ftruncate(fd, 0) != 0 { -1 } else { libc::pwrite(fd, contents.as_ptr() as *const libc::c_void, contents.len(), 0) }
+        };
+        if written < 0 || written as usize != contents.len() {
+            return Err(std::io::Error::last_os_error()).context("Failed to write a secret to a memfd");
+        }
+        Ok(fd)
+    }
+
+    #[cfg(not(unix))]
+    pub fn memfd(&mut self, _name: &str, _contents: &[u8]) -> Result<RawFd> {
+        Err(anyhow!("'memfd:' mappings are only supported on Linux"))
+    }
+
+    /// Get ready to hand the secrets to another command; FIFOs can be read
+    /// from again, and memfds are moved back to the start.
+    pub fn rearm(&self) {
+        for fifo in self.fifos.values() {
+            fifo.arm();
+        }
+        #[cfg(unix)]
+        for &fd in self.memfds.values() {
+            // Safety: lseek is safe to call with any fd.
+            unsafe { libc::lseek(fd, 0, libc::SEEK_SET); }
+        }
+    }
+
+    /// Leave the files in place rather than removing them.
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+
+}
+
+impl Drop for SecretFiles {
+    fn drop(&mut self) {
+        for fifo in self.fifos.values() {
+            let _ = std::fs::remove_file(&fifo.path);
+            let _ = std::fs::remove_dir(&fifo.dir);
+        }
+        #[cfg(unix)]
+        for &fd in self.memfds.values() {
+            // Safety: we own the fd and nothing uses it after this.
+            unsafe { libc::close(fd); }
+        }
+        if self.keep {
+            return
+        }
+        for path in &self.paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Make a FIFO in a directory that only we can access, returning the
+/// directory and the path to the FIFO.
+#[cfg(unix)]
+fn make_fifo() -> Result<(PathBuf,PathBuf)> {
+    use std::os::unix::ffi::OsStrExt;
+    let dir = make_temp_dir()?;
+    let path = dir.join("secret");
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // Safety: mkfifo is given a valid C string.
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        let err = std::io::Error::last_os_error();
+        let _ = std::fs::remove_dir(&dir);
+        return Err(err).with_context(|| format!("Failed to create the FIFO '{}'", path.display()));
+    }
+    Ok((dir, path))
+}
+
+#[cfg(not(unix))]
+fn make_fifo() -> Result<(PathBuf,PathBuf)> {
+    Err(anyhow!("'fifo:' mappings are only supported on unix platforms"))
+}
+
+/// Make a directory that only we can access in the temporary directory.
+#[cfg(unix)]
+fn make_temp_dir() -> Result<PathBuf> {
+    use std::os::unix::ffi::{ OsStrExt, OsStringExt };
+    let template = std::env::temp_dir().join("vault-inject.XXXXXX");
+    let mut template = CString::new(template.as_os_str().as_bytes())?.into_bytes_with_nul();
+    // Safety: mkdtemp is given a valid, mutable, C string, which it fills in.
+    if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+        return Err(std::io::Error::last_os_error()).context("Failed to create a temporary directory");
+    }
+    template.pop();
+    Ok(PathBuf::from(std::ffi::OsString::from_vec(template)))
+}
+
+/// Create an anonymous memory file. It's deliberately not close-on-exec,
+/// so that the commands we run inherit it.
+#[cfg(target_os = "linux")]
+fn memfd_create(name: &str) -> Result<RawFd> {
+    let c_name = CString::new(name)?;
+    // Safety: memfd_create is given a valid C string.
+    let fd = unsafe { libc::syscall(libc::SYS_memfd_create, c_name.as_ptr(), 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to create a memfd");
+    }
+    Ok(fd as RawFd)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn memfd_create(_name: &str) -> Result<RawFd> {
+    Err(anyhow!("'memfd:' mappings are only supported on Linux"))
+}
+
+/// A file mode, written in octal (eg '0600').
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct FileMode(pub u32);
+
+impl std::fmt::Display for FileMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04o}", self.0)
+    }
+}
+
+impl FromStr for FileMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<FileMode> {
+        let mode = u32::from_str_radix(s.trim(), 8)
+            .map_err(|_| anyhow!("Expected an octal file mode like '0600' but got '{}'", s))?;
+        if mode > 0o7777 {
+            return Err(anyhow!("'{}' is not a valid file mode", s));
+        }
+        Ok(FileMode(mode))
+    }
+}
+
+/// A file owner, written as 'user', 'user:group' or ':group'. Users and
+/// groups can be names or numeric IDs.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct FileOwner {
+    uid: Option<u32>,
+    gid: Option<u32>
+}
+
+#[cfg(unix)]
+impl FromStr for FileOwner {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<FileOwner> {
+        let (user, group) = match s.find(':') {
+            Some(idx) => (&s[0..idx], Some(&s[idx+1..])),
+            None => (s, None)
+        };
+
+        let uid = if user.is_empty() {
+            None
+        } else if let Ok(uid) = user.parse() {
+            Some(uid)
+        } else {
+            Some(lookup_uid(user).ok_or_else(|| anyhow!("The user '{}' does not exist", user))?)
+        };
+
+        let gid = match group {
+            None | Some("") => None,
+            Some(group) => match group.parse() {
+                Ok(gid) => Some(gid),
+                Err(_) => Some(lookup_gid(group).ok_or_else(|| anyhow!("The group '{}' does not exist", group))?)
+            }
+        };
+
+        if uid.is_none() && gid.is_none() {
+            return Err(anyhow!("Expected a file owner like 'user:group' but got '{}'", s));
+        }
+        Ok(FileOwner { uid, gid })
+    }
+}
+
+#[cfg(not(unix))]
+impl FromStr for FileOwner {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<FileOwner> {
+        Err(anyhow!("Cannot set the owner of files to '{}': file owners are only supported on unix platforms", s))
+    }
+}
+
+/// Change the owner and/or group of a file, leaving whichever isn't given as it is.
+#[cfg(unix)]
+fn chown(path: &Path, owner: FileOwner) -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // -1 (all bits set) leaves the owner or group unchanged:
+    let uid = owner.uid.unwrap_or(libc::uid_t::MAX);
+    let gid = owner.gid.unwrap_or(libc::gid_t::MAX);
+    // Safety: chown is given a valid C string.
+    if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn lookup_uid(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    // Safety: getpwnam is given a valid C string, and we copy
+    // the uid out of the returned struct before doing anything else.
+    unsafe {
+        let passwd = libc::getpwnam(name.as_ptr());
+        if passwd.is_null() { None } else { Some((*passwd).pw_uid) }
+    }
+}
+
+#[cfg(unix)]
+fn lookup_gid(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    // Safety: getgrnam is given a valid C string, and we copy
+    // the gid out of the returned struct before doing anything else.
+    unsafe {
+        let group = libc::getgrnam(name.as_ptr());
+        if group.is_null() { None } else { Some((*group).gr_gid) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parse_file_mode() {
+        assert_eq!(FileMode::from_str("0600").ok(), Some(FileMode(0o600)));
+        assert_eq!(FileMode::from_str("644").ok(), Some(FileMode(0o644)));
+        assert_eq!(FileMode::from_str("0800").ok(), None);
+        assert_eq!(FileMode::from_str("77777").ok(), None);
+        assert_eq!(FileMode(0o640).to_string(), "0640");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn parse_file_owner() {
+        let owner = |uid, gid| Some(FileOwner { uid, gid });
+        assert_eq!(FileOwner::from_str("1000").ok(), owner(Some(1000), None));
+        assert_eq!(FileOwner::from_str("1000:100").ok(), owner(Some(1000), Some(100)));
+        assert_eq!(FileOwner::from_str(":100").ok(), owner(None, Some(100)));
+        assert_eq!(FileOwner::from_str("root:root").ok(), owner(Some(0), Some(0)));
+        assert_eq!(FileOwner::from_str(":").ok(), None);
+        assert_eq!(FileOwner::from_str("no-such-user-here").ok(), None);
+    }
+
+}
@@ -0,0 +1,92 @@
+use std::str::FromStr;
+use regex::Regex;
+use anyhow::{ Result, anyhow };
+
+/// A basic glob pattern. '*' matches anything except a '/', '**' matches
+/// anything at all, and '?' matches any single character except a '/'.
+#[derive(Debug,Clone)]
+pub struct Glob {
+    re: Regex
+}
+
+impl Glob {
+
+    /// Instantiate a new glob given a string like 'foo/*.conf'
+    pub fn new(s: &str) -> Result<Glob> {
+        Glob::from_str(s)
+    }
+
+    /// Does the string provided match this glob?
+    pub fn matches(&self, s: &str) -> bool {
+        self.re.is_match(s)
+    }
+
+}
+
+impl FromStr for Glob {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Glob> {
+        let mut out_regex = String::new();
+        out_regex.push('^');
+
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' => {
+                    if chars.peek() == Some(&'*') {
+                        chars.next();
+                        out_regex.push_str(".*");
+                    } else {
+                        out_regex.push_str("[^/]*");
+                    }
+                },
+                '?' => {
+                    out_regex.push_str("[^/]");
+                },
+                c => {
+                    out_regex.push_str(&regex::escape(&c.to_string()));
+                }
+            }
+        }
+        out_regex.push('$');
+
+        let re = Regex::new(&out_regex)
+            .map_err(|e| anyhow!("Invalid glob pattern '{}': {}", s, e))?;
+        Ok(Glob { re })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn match_glob() {
+
+        let cases = vec![
+            ("foo", "foo", true),
+            ("foo", "foobar", false),
+            ("*.conf", "app.conf", true),
+            ("*.conf", "app.confx", false),
+            // '*' doesn't cross directories, but '**' does:
+            ("*.conf", "dir/app.conf", false),
+            ("**.conf", "dir/app.conf", true),
+            ("dir/**", "dir/a/b/c", true),
+            // '?' matches exactly one character:
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("a?c", "a/c", false),
+            // Other characters are matched literally:
+            ("AWS_*", "AWS_SECRET_ACCESS_KEY", true),
+            ("a.b", "axb", false),
+        ];
+
+        for (glob_str, s, does_match) in cases {
+            let glob = Glob::new(glob_str).expect("Could not instantiate glob");
+            assert_eq!(glob.matches(s), does_match, "'{}' matching '{}' should be {}", glob_str, s, does_match);
+        }
+
+    }
+
+}
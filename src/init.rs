@@ -0,0 +1,165 @@
+use std::str::FromStr;
+use anyhow::{ anyhow, Result, Context };
+use serde_json::Value;
+use tokio::io::{ self, AsyncWriteExt, AsyncBufReadExt };
+use vault_inject::auth::AuthType;
+use vault_inject::client::Client;
+use vault_inject::secret_mapping::SecretMapping;
+use vault_inject::secret_store::SecretStore;
+
+/// Ask questions on stderr and read the answers from stdin. Answers are read
+/// through the same buffer each time so that piped input isn't lost.
+pub struct Prompter {
+    stdin: io::BufReader<io::Stdin>
+}
+
+impl Default for Prompter {
+    fn default() -> Prompter {
+        Prompter::new()
+    }
+}
+
+impl Prompter {
+
+    pub fn new() -> Prompter {
+        Prompter { stdin: io::BufReader::new(io::stdin()) }
+    }
+
+    /// Ask a question, returning the default answer (if one is given)
+    /// if nothing is entered.
+    pub async fn ask(&mut self, question: &str, default: Option<&str>) -> Result<String> {
+        let msg = match default {
+            Some(default) => format!("{} [{}]: ", question, default),
+            None => format!("{}: ", question)
+        };
+        io::stderr().write_all(msg.as_bytes())
+            .await
+            .context("Could not write to stderr")?;
+
+        let mut answer = String::new();
+        let n = self.stdin.read_line(&mut answer)
+            .await
+            .context("Failed to read an answer from stdin")?;
+        if n == 0 {
+            return Err(anyhow!("Expected an answer to '{}' but stdin was closed", question));
+        }
+
+        let answer = answer.trim();
+        match default {
+            Some(default) if answer.is_empty() => Ok(default.to_owned()),
+            _ => Ok(answer.to_owned())
+        }
+    }
+
+}
+
+/// Find the auth methods that we support which are enabled on the Vault instance,
+/// returning the path each is mounted at. Vault only tells us about the methods
+/// which are visible to unauthenticated users, so this may come back empty.
+pub async fn discover_auth_methods(client: &Client) -> Vec<(String, AuthType)> {
+    let res: Value = match client.get("/sys/internal/ui/mounts").await {
+        Ok(res) => res,
+        Err(_) => return Vec::new()
+    };
+    let auth_mounts = match res["data"]["auth"].as_object() {
+        Some(auth_mounts) => auth_mounts,
+        None => return Vec::new()
+    };
+
+    let mut methods: Vec<(String, AuthType)> = auth_mounts.iter()
+        .filter_map(|(path, props)| {
+            let auth_type = AuthType::from_str(props["type"].as_str()?).ok()?;
+            Some((path.trim_matches('/').to_owned(), auth_type))
+        })
+        .collect();
+    methods.sort_by(|a, b| a.0.cmp(&b.0));
+    methods
+}
+
+/// Describe what's at a path ending in '/', to help find the secret that's wanted.
+/// This lists the entries under the path, or the keys of the secret at it.
+pub async fn describe_path(store: &SecretStore, path: &str) -> Result<Vec<String>> {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return Ok(store.mount_points().into_iter().map(|m| format!("{}/", m)).collect());
+    }
+    match store.list(path).await {
+        Ok(entries) => Ok(entries),
+        Err(list_err) => match store.get(path.trim_end_matches('/')).await {
+            Ok(secret) => Ok(secret.into_iter().map(|(key, _)| key).collect()),
+            Err(_) => Err(list_err)
+        }
+    }
+}
+
+/// The details needed to write out a config file.
+pub struct InitConfig {
+    pub vault_url: url::Url,
+    pub auth_type: AuthType,
+    pub auth_path: Option<String>,
+    pub bundle: String,
+    pub secrets: Vec<SecretMapping>
+}
+
+impl InitConfig {
+
+    /// Render a commented config file containing the bundle of secrets.
+    pub fn render(&self) -> String {
+        let mut cmd = format!("vault-inject --vault-url {} --auth-type {}", self.vault_url, self.auth_type);
+        if let Some(auth_path) = &self.auth_path {
+            cmd.push_str(&format!(" --auth-path {}", auth_path));
+        }
+        cmd.push_str(&format!(" --bundle {} --command '...'", self.bundle));
+
+        let mut out = String::new();
+        out.push_str("# Written by 'vault-inject init'. Bundles group secret mappings together\n");
+        out.push_str("# under a name so that they can be injected using '--bundle'; for example:\n");
+        out.push_str("#\n");
+        out.push_str(&format!("#   {}\n", cmd));
+        out.push_str("#\n");
+        out.push_str("# Each secret is written 'ENV_VAR = /path/to/secret/key', optionally followed\n");
+        out.push_str("# by '| command' to pipe the secret through before it's injected.\n");
+        out.push_str(&format!("[bundles.{}]\n", self.bundle));
+        out.push_str("secrets = [\n");
+        for secret in &self.secrets {
+            // JSON strings are valid TOML strings:
+            out.push_str(&format!("    {},\n", Value::String(secret.to_string())));
+        }
+        out.push_str("]\n");
+        out
+    }
+
+}
+
+/// Bundle names are written into the config as bare keys, so we keep them simple.
+pub fn is_valid_bundle_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn rendered_config_can_be_loaded() {
+
+        let init_config = InitConfig {
+            vault_url: "https://vault.example.com".parse().unwrap(),
+            auth_type: AuthType::Ldap,
+            auth_path: None,
+            bundle: "postgres".to_owned(),
+            secrets: vec![
+                SecretMapping::from_str("PGUSER = /secret/db/user").unwrap(),
+                SecretMapping::from_str("PGPASSWORD = /secret/db/password | tr -d '\"'").unwrap(),
+            ]
+        };
+
+        let config = Config::from_str(&init_config.render()).unwrap();
+        let secrets: Vec<String> = config.bundle("postgres").unwrap().iter().map(|m| m.to_string()).collect();
+        assert_eq!(secrets, vec!["PGUSER = secret/db/user", "PGPASSWORD = secret/db/password | tr -d '\"'"]);
+
+    }
+
+}
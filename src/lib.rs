@@ -0,0 +1,55 @@
+//! Fetch secrets from Vault and hand them to the things that need them.
+//!
+//! This is the library behind the `vault-inject` binary, for tools that want to
+//! resolve secret mappings the same way without running it. Connect to Vault with
+//! a [`Client`], log in with [`Auth`], find the secret stores with [`SecretStore`],
+//! and then [`resolve`] some [`SecretMapping`]s into environment variables:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use vault_inject::{ Auth, AuthDetails, Client, SecretMapping, SecretStore };
+//!
+//! let client = Client::new("https://vault.example.com".parse()?, reqwest::Client::builder())?;
+//! let details = AuthDetails::UserPass {
+//!     path: None,
+//!     username: "deploy".to_owned(),
+//!     password: std::env::var("DEPLOY_PASSWORD")?
+//! };
+//! let token = Auth::new(client.clone()).login(details).await?;
+//! let store = SecretStore::new(client.with_token(token.token)).await?;
+//!
+//! let mappings: Vec<SecretMapping> = vec!["PGPASSWORD = /secret/foo/bar/password".parse()?];
+//! let env_vars = vault_inject::resolve(&store, &mappings).await?;
+//! # Ok(()) }
+//! ```
+//!
+//! Mappings which target a file, FIFO or memfd (eg 'file:/etc/app/key = ...')
+//! are returned under that name rather than written out; see
+//! [`secret_mapping::Target`]. Warnings, such as falling back to a default
+//! value, are emitted as `tracing` events; a [`RedactingWriter`] keeps the
+//! tokens and secret values that we've seen out of whatever they're written to.
+
+pub mod auth;
+mod aws;
+pub mod secret_store;
+pub mod secret_mapping;
+pub mod template;
+pub mod client;
+mod transport;
+pub mod duration;
+pub mod processor;
+pub mod size;
+pub mod share;
+mod assertion;
+pub(crate) mod shell;
+mod selector;
+pub(crate) mod logging;
+mod resolve;
+
+pub use crate::auth::{ Auth, AuthDetails, AuthType };
+pub use crate::client::Client;
+pub use crate::secret_store::SecretStore;
+pub use crate::secret_mapping::SecretMapping;
+pub use crate::resolve::{ resolve, resolve_mapping, OnError, DEFAULT_MAX_SECRET_SIZE };
+pub use crate::shell::Shell;
+pub use crate::logging::RedactingWriter;
@@ -0,0 +1,46 @@
+use std::fmt;
+use std::io;
+use colored::*;
+use tracing::{ Event, Level, Subscriber };
+use tracing_subscriber::fmt::{ FmtContext, FormatEvent, FormatFields, format };
+use tracing_subscriber::registry::LookupSpan;
+use vault_inject::RedactingWriter;
+
+/// Log what we're doing to stderr: just warnings by default, the main steps with
+/// '-v', each request made to Vault with '-vv', and everything with '-vvv'.
+pub fn init(verbosity: u8) {
+    let level = match verbosity {
+        0 => {
+            tracing_subscriber::fmt()
+                .with_max_level(Level::WARN)
+                .event_format(Warnings)
+                .with_writer(|| RedactingWriter::new(io::stderr()))
+                .init();
+            return
+        },
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_target(false)
+        .with_writer(|| RedactingWriter::new(io::stderr()))
+        .init();
+}
+
+/// Writes each warning on its own, in yellow, like the rest of what we print
+/// to stderr when we haven't been asked to log what we're doing.
+struct Warnings;
+
+impl<S, N> FormatEvent<S, N> for Warnings
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: format::Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut message = String::new();
+        ctx.format_fields(format::Writer::new(&mut message), event)?;
+        writeln!(writer, "{}", message.yellow())
+    }
+}
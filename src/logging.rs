@@ -0,0 +1,72 @@
+use std::io::{ self, Write };
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+
+/// Values (tokens and secrets) which are replaced wherever they appear in what we log.
+static REDACTED: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// What we replace redacted values with.
+const REDACTED_TEXT: &str = "<redacted>";
+
+/// Never log this value (eg because it's a token or a secret).
+pub fn redact(value: &str) {
+    if value.is_empty() {
+        return
+    }
+    let mut redacted = REDACTED.lock().unwrap();
+    if !redacted.iter().any(|v| v == value) {
+        redacted.push(value.to_owned());
+        // Longer values first, so that none are left partly visible
+        // because a shorter value inside them was replaced first:
+        redacted.sort_by_key(|v| std::cmp::Reverse(v.len()));
+    }
+}
+
+fn redact_in(line: &str, values: &[String]) -> String {
+    values.iter().fold(line.to_owned(), |line, value| line.replace(&**value, REDACTED_TEXT))
+}
+
+/// Hands each log line on once any tokens or secret values that we've come across
+/// have been replaced with '<redacted>'. Use this as the writer for a tracing
+/// subscriber; each event is written in one go, so values are never split across
+/// writes.
+pub struct RedactingWriter<W>(W);
+
+impl <W: Write> RedactingWriter<W> {
+    /// Redact what's written before handing it on to the writer given.
+    pub fn new(inner: W) -> RedactingWriter<W> {
+        RedactingWriter(inner)
+    }
+}
+
+impl <W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = redact_in(&String::from_utf8_lossy(buf), &REDACTED.lock().unwrap());
+        self.0.write_all(line.as_bytes())?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn redact_values() {
+        let values = vec!["s.LONGTOKEN".to_owned(), "hunter2".to_owned(), "TOKEN".to_owned()];
+        let cases = vec![
+            ("GET /v1/secret/data/app", "GET /v1/secret/data/app"),
+            ("token s.LONGTOKEN is valid", "token <redacted> is valid"),
+            ("password=hunter2, hunter2", "password=<redacted>, <redacted>"),
+            ("TOKEN", "<redacted>"),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(redact_in(line, &values), expected, "Unexpected redaction of '{}'", line);
+        }
+    }
+
+}
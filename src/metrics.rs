@@ -0,0 +1,159 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::Duration;
+use anyhow::{ Result, Context };
+use once_cell::sync::Lazy;
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+
+/// The metrics that we record while running, exposed in the Prometheus
+/// text format if '--metrics-addr' is given.
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// The upper bounds (in seconds) of the buckets that fetch latencies are counted in.
+static LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug,Default)]
+pub struct Metrics {
+    pub auth_attempts: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub secrets_fetched: AtomicU64,
+    pub secret_fetch_failures: AtomicU64,
+    pub command_restarts: AtomicU64,
+    pub fetch_duration: Histogram
+}
+
+impl Metrics {
+
+    /// Render the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("vault_inject_auth_attempts_total", "Attempts to authenticate with Vault", &self.auth_attempts),
+            ("vault_inject_auth_failures_total", "Failed attempts to authenticate with Vault", &self.auth_failures),
+            ("vault_inject_secrets_fetched_total", "Secret mappings fetched from Vault", &self.secrets_fetched),
+            ("vault_inject_secret_fetch_failures_total", "Secret mappings that could not be fetched from Vault", &self.secret_fetch_failures),
+            ("vault_inject_command_restarts_total", "Times that the supervised command has been restarted", &self.command_restarts),
+        ];
+        for (name, help, counter) in &counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        self.fetch_duration.render(
+            "vault_inject_secret_fetch_duration_seconds",
+            "How long it took to fetch (and process) each secret mapping",
+            &mut out
+        );
+        out
+    }
+
+}
+
+/// Count durations into a fixed set of buckets.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0)
+        }
+    }
+}
+
+impl Histogram {
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, &le) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if secs <= le {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bucket, le) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, bucket.load(Ordering::Relaxed));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+
+}
+
+/// Serve the metrics over HTTP at the address given, in the background. Every
+/// request is answered with the metrics, whatever its path.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let mut listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen for metrics requests on '{}'", addr))?;
+
+    tokio::spawn(async move {
+        loop {
+            let mut socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(_) => continue
+            };
+            tokio::spawn(async move {
+                // We don't care what was asked for, but read the request so
+                // that the client isn't cut off before it has finished sending:
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+
+                let body = METRICS.render();
+                let res = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}"
+                    , body.len(), body);
+                let _ = socket.write_all(res.as_bytes()).await;
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn render_metrics() {
+
+        let metrics = Metrics::default();
+        metrics.auth_attempts.fetch_add(2, Ordering::Relaxed);
+        metrics.fetch_duration.observe(Duration::from_millis(20));
+        metrics.fetch_duration.observe(Duration::from_millis(300));
+
+        let out = metrics.render();
+        let expected = vec![
+            "vault_inject_auth_attempts_total 2",
+            "vault_inject_auth_failures_total 0",
+            "vault_inject_secret_fetch_duration_seconds_bucket{le=\"0.01\"} 0",
+            "vault_inject_secret_fetch_duration_seconds_bucket{le=\"0.025\"} 1",
+            "vault_inject_secret_fetch_duration_seconds_bucket{le=\"0.5\"} 2",
+            "vault_inject_secret_fetch_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "vault_inject_secret_fetch_duration_seconds_sum 0.32",
+            "vault_inject_secret_fetch_duration_seconds_count 2",
+        ];
+        for line in expected {
+            assert!(out.lines().any(|l| l == line), "Expected to find '{}' in:\n{}", line, out);
+        }
+
+    }
+
+}
@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use anyhow::{ anyhow, Result };
+use serde_json::Value;
+
+/// How to print the environment variables that we've resolved, so
+/// that they can be consumed by other tools.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum OutputFormat {
+    Json,
+    Yaml
+}
+
+impl OutputFormat {
+
+    /// Render the environment variables given as a map from name to value.
+    /// If a variable is given more than once, the last value wins (as it
+    /// would when setting them on a command).
+    pub fn render(self, env_vars: &[(String,String)]) -> String {
+        let env_vars: BTreeMap<&str,&str> = env_vars
+            .iter()
+            .map(|(k,v)| (&**k, &**v))
+            .collect();
+
+        match self {
+            OutputFormat::Json => {
+                let mut out = serde_json::to_string_pretty(&env_vars).unwrap_or_default();
+                out.push('\n');
+                out
+            },
+            OutputFormat::Yaml => {
+                if env_vars.is_empty() {
+                    return "{}\n".to_owned();
+                }
+                // JSON strings are valid YAML (double quoted) strings:
+                env_vars.iter()
+                    .map(|(k,v)| format!("{}: {}\n", Value::from(*k), Value::from(*v)))
+                    .collect()
+            }
+        }
+    }
+
+    /// Render a list of strings.
+    pub fn render_list(self, items: &[String]) -> String {
+        match self {
+            OutputFormat::Json => {
+                let mut out = serde_json::to_string_pretty(items).unwrap_or_default();
+                out.push('\n');
+                out
+            },
+            OutputFormat::Yaml => {
+                if items.is_empty() {
+                    return "[]\n".to_owned();
+                }
+                items.iter()
+                    .map(|item| format!("- {}\n", Value::from(&**item)))
+                    .collect()
+            }
+        }
+    }
+
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(OutputFormat::Json),
+            "yaml" | "yml" => Ok(OutputFormat::Yaml),
+            _ => Err(anyhow!("'{}' is not a valid output format (try 'json' or 'yaml').", s))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn render_env_vars() {
+
+        let env_vars = vec![
+            ("PGUSER".to_owned(), "admin".to_owned()),
+            ("PGPASSWORD".to_owned(), "first".to_owned()),
+            ("PGPASSWORD".to_owned(), "hunter2 \"quoted\"\nline".to_owned()),
+        ];
+
+        let cases = vec![
+            (OutputFormat::Json, "{\n  \"PGPASSWORD\": \"hunter2 \\\"quoted\\\"\\nline\",\n  \"PGUSER\": \"admin\"\n}\n"),
+            (OutputFormat::Yaml, "\"PGPASSWORD\": \"hunter2 \\\"quoted\\\"\\nline\"\n\"PGUSER\": \"admin\"\n"),
+        ];
+
+        for (format, expected) in cases {
+            assert_eq!(format.render(&env_vars), expected, "Unexpected output for {:?}", format);
+        }
+        assert_eq!(OutputFormat::Yaml.render(&[]), "{}\n");
+
+    }
+
+    #[test]
+    fn render_lists() {
+
+        let items = vec!["db".to_owned(), "nested/".to_owned()];
+
+        let cases = vec![
+            (OutputFormat::Json, "[\n  \"db\",\n  \"nested/\"\n]\n"),
+            (OutputFormat::Yaml, "- \"db\"\n- \"nested/\"\n"),
+        ];
+
+        for (format, expected) in cases {
+            assert_eq!(format.render_list(&items), expected, "Unexpected output for {:?}", format);
+        }
+        assert_eq!(OutputFormat::Yaml.render_list(&[]), "[]\n");
+
+    }
+
+}
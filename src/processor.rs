@@ -0,0 +1,561 @@
+use std::fmt;
+use std::process::Stdio;
+use std::str::FromStr;
+use anyhow::{ anyhow, Result, Context };
+use futures::future;
+use serde_json::{ Value, json };
+use tokio::process::Command;
+use tokio::prelude::*;
+use tracing::debug;
+use crate::client::Client;
+use crate::size::ByteSize;
+use crate::shell::Shell;
+
+/// The hashing algorithms that Vault's 'sys/tools/hash' endpoint supports.
+static VAULT_HASH_ALGORITHMS: &[&str] = &[
+    "sha2-224", "sha2-256", "sha2-384", "sha2-512",
+    "sha3-224", "sha3-256", "sha3-384", "sha3-512"
+];
+
+/// A step that secrets are piped through before being handed out.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Processor {
+    /// A shell command which is handed the secret on stdin,
+    /// and whose stdout becomes the new secret.
+    Command(String),
+    /// A shell command which is handed the secret as the env var $secret
+    /// ('env:CMD'), for commands that can't read it from stdin.
+    EnvCommand(String),
+    /// Hash the secret using Vault's 'sys/tools/hash' endpoint
+    /// ('@vault-hash:sha2-256').
+    VaultHash { algorithm: String },
+    /// A transformation that we do ourselves (eg '@base64d'), so that the
+    /// secret doesn't need to be handed to another program.
+    Builtin(Builtin)
+}
+
+/// The transformations that we can make to secrets without running a command.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Builtin {
+    Base64,
+    Base64Decode,
+    Trim,
+    UrlEncode,
+    Upper,
+    Lower
+}
+
+impl Builtin {
+    const ALL: &'static [Builtin] = &[
+        Builtin::Base64, Builtin::Base64Decode, Builtin::Trim,
+        Builtin::UrlEncode, Builtin::Upper, Builtin::Lower
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Builtin::Base64 => "base64",
+            Builtin::Base64Decode => "base64d",
+            Builtin::Trim => "trim",
+            Builtin::UrlEncode => "urlencode",
+            Builtin::Upper => "upper",
+            Builtin::Lower => "lower"
+        }
+    }
+
+    fn apply(self, secret: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Builtin::Base64 => Ok(base64::encode(&secret).into_bytes()),
+            Builtin::Base64Decode => {
+                let encoded: Vec<u8> = secret.into_iter().filter(|b| !b.is_ascii_whitespace()).collect();
+                base64::decode(&encoded).context("The secret is not valid base64 ('@base64d')")
+            },
+            Builtin::Trim => {
+                let start = secret.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(secret.len());
+                let end = secret.iter().rposition(|b| !b.is_ascii_whitespace()).map(|idx| idx + 1).unwrap_or(start);
+                Ok(secret[start..end].to_vec())
+            },
+            Builtin::UrlEncode => Ok(url_encode(&secret).into_bytes()),
+            Builtin::Upper => Ok(String::from_utf8_lossy(&secret).to_uppercase().into_bytes()),
+            Builtin::Lower => Ok(String::from_utf8_lossy(&secret).to_lowercase().into_bytes())
+        }
+    }
+}
+
+/// Percent-encode everything but the characters that are never reserved in URLs.
+fn url_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+impl fmt::Display for Processor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Processor::Command(cmd) => write!(f, "{}", cmd),
+            Processor::EnvCommand(cmd) => write!(f, "env:{}", cmd),
+            Processor::VaultHash { algorithm } => write!(f, "@vault-hash:{}", algorithm),
+            Processor::Builtin(builtin) => write!(f, "@{}", builtin.name())
+        }
+    }
+}
+
+impl FromStr for Processor {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Processor> {
+        let s = s.trim();
+        if let Some(cmd) = s.strip_prefix("env:") {
+            let cmd = cmd.trim();
+            if cmd.is_empty() {
+                return Err(anyhow!("Expected a command to run after 'env:'"));
+            }
+            return Ok(Processor::EnvCommand(cmd.to_owned()));
+        }
+        if !s.starts_with('@') {
+            return Ok(Processor::Command(s.to_owned()));
+        }
+
+        let (name, arg) = match s.find(':') {
+            Some(idx) => (&s[1..idx], Some(s[idx+1..].trim())),
+            None => (&s[1..], None)
+        };
+
+        match name.trim() {
+            "vault-hash" => {
+                let algorithm = arg.unwrap_or("sha2-256");
+                if !VAULT_HASH_ALGORITHMS.contains(&algorithm) {
+                    return Err(anyhow!("'{}' is not a hash algorithm that Vault supports (try one of {})"
+                        , algorithm, VAULT_HASH_ALGORITHMS.join(", ")));
+                }
+                Ok(Processor::VaultHash { algorithm: algorithm.to_owned() })
+            },
+            name => match Builtin::ALL.iter().find(|builtin| builtin.name() == name) {
+                Some(&builtin) if arg.is_none() => Ok(Processor::Builtin(builtin)),
+                Some(_) => Err(anyhow!("The built-in processor '@{}' doesn't take an argument", name)),
+                None => {
+                    let names: Vec<String> = Builtin::ALL.iter().map(|b| format!("'@{}'", b.name())).collect();
+                    Err(anyhow!("'{}' is not a known built-in processor (try '@vault-hash', {})", s, names.join(", ")))
+                }
+            }
+        }
+    }
+}
+
+/// A named chain of processors (eg 'pgpass=base64 --decode | tr -d "\n"'), which
+/// mappings can pipe secrets through by name (eg '| pgpass').
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ProcessorDef {
+    name: String,
+    processors: Vec<Processor>
+}
+
+impl ProcessorDef {
+    pub fn new(name: &str, chain: &str) -> Result<ProcessorDef> {
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(anyhow!("Processor names can only contain letters, numbers, '-' and '_', but got '{}'", name));
+        }
+        let processors = chain.split('|')
+            .map(|s| s.trim())
+            .map(|s| if s.is_empty() {
+                Err(anyhow!("Every '|' must forward to a command, but the processor '{}' has an empty one", name))
+            } else {
+                Processor::from_str(s)
+            })
+            .collect::<Result<_>>()?;
+        Ok(ProcessorDef { name: name.to_owned(), processors })
+    }
+}
+
+impl FromStr for ProcessorDef {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<ProcessorDef> {
+        let idx = s.find('=')
+            .ok_or_else(|| anyhow!("Expected processors to be defined like 'name=command | command' but got '{}'", s))?;
+        ProcessorDef::new(&s[0..idx], &s[idx+1..])
+    }
+}
+
+/// Replace any processors which are just the name of a definition with the
+/// processors it's made up of. Later definitions take precedence over earlier
+/// ones, and definitions can use each other (but not themselves).
+pub fn expand(processors: &[Processor], defs: &[ProcessorDef]) -> Vec<Processor> {
+    expand_except(processors, defs, &mut Vec::new())
+}
+
+fn expand_except<'a>(processors: &[Processor], defs: &'a [ProcessorDef], expanding: &mut Vec<&'a str>) -> Vec<Processor> {
+    let mut out = Vec::new();
+    for processor in processors {
+        let def = match processor {
+            Processor::Command(cmd) => defs.iter().rev().find(|def| def.name == *cmd && !expanding.contains(&&*def.name)),
+            _ => None
+        };
+        match def {
+            Some(def) => {
+                expanding.push(&def.name);
+                out.extend(expand_except(&def.processors, defs, expanding));
+                expanding.pop();
+            },
+            None => out.push(processor.clone())
+        }
+    }
+    out
+}
+
+/// Shell builtins (including some from bash), which don't need to be found on the PATH.
+static SHELL_BUILTINS: &[&str] = &[
+    ".", ":", "[", "[[", "alias", "cd", "command", "declare", "echo", "eval", "exec", "exit",
+    "export", "false", "let", "local", "mapfile", "printf", "read", "readarray", "set", "shift",
+    "source", "test", "true", "unset"
+];
+
+/// Shell keywords, which can come before the program in a command.
+static SHELL_KEYWORDS: &[&str] = &[
+    "!", "{", "}", "do", "done", "elif", "else", "fi", "if", "then", "time", "until", "while"
+];
+
+impl Processor {
+
+    /// The external programs that this processor runs, as best we can tell
+    /// without a full shell parser: the first word of each command in it.
+    pub fn programs(&self) -> Vec<String> {
+        let cmd = match self {
+            Processor::Command(cmd) | Processor::EnvCommand(cmd) => cmd,
+            Processor::VaultHash { .. } | Processor::Builtin(_) => return Vec::new()
+        };
+
+        cmd.split(&['|', ';', '&', '\n'][..])
+            .filter_map(|part| {
+                // Skip over any leading keywords and 'NAME=value' env var assignments:
+                let program = part.split_whitespace()
+                    .find(|word| !is_env_assignment(word) && !SHELL_KEYWORDS.contains(word))?;
+                let program = program.trim_matches(&['\'', '"'][..]);
+                let is_builtin = SHELL_BUILTINS.contains(&program);
+                let is_expansion = program.starts_with(&['$', '(', '`'][..]);
+                if program.is_empty() || is_builtin || is_expansion {
+                    None
+                } else {
+                    Some(program.to_owned())
+                }
+            })
+            .collect()
+    }
+
+}
+
+fn is_env_assignment(word: &str) -> bool {
+    match word.find('=') {
+        Some(idx) => idx > 0 && word[0..idx].chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        None => false
+    }
+}
+
+/// Can the program given be run? Programs containing a '/' are paths;
+/// anything else is looked for on the PATH.
+pub fn program_exists(program: &str) -> bool {
+    #[cfg(unix)]
+    let is_executable = |path: &std::path::Path| {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+    // Windows has no executable bit, and programs can be named without their '.exe':
+    #[cfg(not(unix))]
+    let is_executable = |path: &std::path::Path| {
+        path.is_file() || path.with_extension("exe").is_file()
+    };
+
+    if program.contains('/') || (cfg!(windows) && program.contains('\\')) {
+        return is_executable(std::path::Path::new(program));
+    }
+    let path_var = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path_var).any(|dir| is_executable(&dir.join(program)))
+}
+
+/// Pipe a secret through each of the processors given in turn, running commands
+/// via the shell given. Processors aren't allowed to output anything larger than
+/// the max size given.
+pub async fn process(mut secret: Vec<u8>, processors: &[Processor], client: &Client, shell: &Shell, max_size: ByteSize) -> Result<String> {
+    for processor in processors {
+        secret = match processor {
+            Processor::Command(command) => run_command(secret, shell, command, SecretInput::Stdin, max_size).await?,
+            Processor::EnvCommand(command) => run_command(secret, shell, command, SecretInput::EnvVar, max_size).await?,
+            Processor::VaultHash { algorithm } => vault_hash(secret, algorithm, client).await?,
+            Processor::Builtin(builtin) => builtin.apply(secret)?
+        };
+        if secret.len() > max_size.0 {
+            return Err(anyhow!("The output of '{}' is larger than the maximum allowed size of {}", processor, max_size));
+        }
+    }
+    Ok(String::from_utf8_lossy(&secret).into_owned())
+}
+
+/// How a command is handed the secret to process.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum SecretInput {
+    Stdin,
+    EnvVar
+}
+
+async fn run_command(secret: Vec<u8>, shell: &Shell, command: &str, input: SecretInput, max_size: ByteSize) -> Result<Vec<u8>> {
+    debug!("Running the processor '{}'", command);
+    let mut cmd = Command::from(shell.command(command));
+    cmd.stdout(Stdio::piped())
+       .stderr(Stdio::piped());
+    match input {
+        SecretInput::Stdin => {
+            cmd.stdin(Stdio::piped());
+        },
+        SecretInput::EnvVar => {
+            if secret.contains(&0) {
+                return Err(anyhow!("The secret contains a NUL byte, so can't be handed to the command '{}' in an env var", command));
+            }
+            cmd.stdin(Stdio::null())
+               .env("secret", secret_to_os_string(secret.clone()));
+        }
+    }
+    let mut child = cmd.spawn()
+        .with_context(|| format!("Failed to run the command '{}'", command))?;
+    let stdin = child.stdin.take();
+    let stdout = child.stdout.take()
+        .with_context(|| format!("Failed to open stdout for the command '{}'", command))?;
+    let mut stderr = child.stderr.take()
+        .with_context(|| format!("Failed to open stderr for the command '{}'", command))?;
+
+    // Hand over the secret and read the output and errors all at once, so
+    // that the command is never stuck writing to a pipe that we aren't reading:
+    let write_secret = async {
+        if let Some(mut stdin) = stdin {
+            stdin.write_all(&secret)
+                .await
+                .with_context(|| format!("Failed to write to stdin for the command '{}'", command))?;
+        }
+        Ok(())
+    };
+    let read_output = async {
+        // Read no more than the max size (plus a byte, to know if it's been exceeded):
+        let mut output = Vec::new();
+        stdout.take(max_size.0 as u64 + 1)
+            .read_to_end(&mut output)
+            .await
+            .with_context(|| format!("Failed to read stdout for the command '{}'", command))?;
+        if output.len() > max_size.0 {
+            return Err(anyhow!("The output of the command '{}' is larger than the maximum allowed size of {}", command, max_size));
+        }
+        Ok(output)
+    };
+    let read_errors = async {
+        let mut errors = Vec::new();
+        stderr.read_to_end(&mut errors)
+            .await
+            .with_context(|| format!("Failed to read stderr for the command '{}'", command))?;
+        Ok(errors)
+    };
+    let (_, stdout, stderr) = match future::try_join3(write_secret, read_output, read_errors).await {
+        Ok(res) => res,
+        Err(e) => {
+            // Don't leave the command running (or unreaped) once we've given up on it:
+            let _ = child.kill();
+            let _ = child.await;
+            return Err(e);
+        }
+    };
+    child.await
+        .with_context(|| format!("Failed to wait for the command '{}'", command))?;
+    let secret = trim_trailing_newline(stdout);
+
+    if secret.is_empty() {
+        let error_output = String::from_utf8_lossy(&stderr);
+        return Err(anyhow!("The command '{}' failed:\n\n'{}'", command, error_output));
+    }
+    Ok(secret)
+}
+
+#[cfg(unix)]
+fn secret_to_os_string(secret: Vec<u8>) -> std::ffi::OsString {
+    use std::os::unix::ffi::OsStringExt;
+    std::ffi::OsString::from_vec(secret)
+}
+
+#[cfg(not(unix))]
+fn secret_to_os_string(secret: Vec<u8>) -> std::ffi::OsString {
+    String::from_utf8_lossy(&secret).into_owned().into()
+}
+
+/// Commands usually end their output with a newline, which we don't want.
+pub fn trim_trailing_newline(mut output: Vec<u8>) -> Vec<u8> {
+    if output.ends_with(b"\n") {
+        output.pop();
+        if output.ends_with(b"\r") {
+            output.pop();
+        }
+    }
+    output
+}
+
+async fn vault_hash(secret: Vec<u8>, algorithm: &str, client: &Client) -> Result<Vec<u8>> {
+    let body = json!({
+        "input": base64::encode(&secret),
+        "format": "hex"
+    });
+    let res: Value = client.post(format!("sys/tools/hash/{}", algorithm), &body)
+        .await
+        .with_context(|| format!("Failed to hash the secret using Vault ('@vault-hash:{}')", algorithm))?;
+
+    let sum = res["data"]["sum"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Could not find the hash in Vault's response ('@vault-hash:{}')", algorithm))?;
+    Ok(sum.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_string_to_processor() {
+
+        let hash = |algorithm: &str| Some(Processor::VaultHash { algorithm: algorithm.to_owned() });
+        let cases = vec![
+            ("base64", Some(Processor::Command("base64".to_owned()))),
+            (" tr -d '\\n' ", Some(Processor::Command("tr -d '\\n'".to_owned()))),
+            ("@vault-hash", hash("sha2-256")),
+            ("@vault-hash:sha2-512", hash("sha2-512")),
+            ("@vault-hash: sha3-256", hash("sha3-256")),
+            ("@base64d", Some(Processor::Builtin(Builtin::Base64Decode))),
+            (" @upper ", Some(Processor::Builtin(Builtin::Upper))),
+            // Unknown algorithms and built-ins aren't allowed, and only some take arguments:
+            ("@vault-hash:md5", None),
+            ("@nope", None),
+            ("@trim:x", None),
+            ("env:my-tool --in \"$secret\"", Some(Processor::EnvCommand("my-tool --in \"$secret\"".to_owned()))),
+            ("env: rev ", Some(Processor::EnvCommand("rev".to_owned()))),
+            ("env:", None),
+        ];
+
+        for (s, expected) in cases {
+            assert_eq!(Processor::from_str(s).ok(), expected, "Unexpected result parsing processor '{}'", s);
+        }
+
+    }
+
+    #[test]
+    fn expand_processor_defs() {
+
+        let defs: Vec<ProcessorDef> = vec![
+            "pgpass = base64 --decode | tr -d '\\n'",
+            "clean=@trim | @lower",
+            "both = pgpass | clean",
+            "base64 = base64 -w0",
+            "clean = @trim",
+        ].into_iter().map(|s| s.parse().unwrap()).collect();
+
+        let cases = vec![
+            (vec!["rev"], vec!["rev"]),
+            (vec!["pgpass"], vec!["base64 --decode", "tr -d '\\n'"]),
+            // Later definitions win:
+            (vec!["clean", "rev"], vec!["@trim", "rev"]),
+            // Definitions can use each other, but not themselves:
+            (vec!["both"], vec!["base64 --decode", "tr -d '\\n'", "@trim"]),
+            (vec!["base64"], vec!["base64 -w0"]),
+            // Only whole commands are names:
+            (vec!["pgpass --flag"], vec!["pgpass --flag"]),
+        ];
+
+        for (processors, expected) in cases {
+            let processors: Vec<Processor> = processors.iter().map(|s| s.parse().unwrap()).collect();
+            let expected: Vec<Processor> = expected.iter().map(|s| s.parse().unwrap()).collect();
+            assert_eq!(expand(&processors, &defs), expected, "Unexpected expansion of {:?}", processors);
+        }
+
+        let invalid = vec!["nope", "=base64", "bad name=base64", "empty=base64 |", "unknown=@nope"];
+        for s in invalid {
+            assert!(ProcessorDef::from_str(s).is_err(), "Expected '{}' to be rejected", s);
+        }
+
+    }
+
+    #[test]
+    fn apply_builtins() {
+
+        let cases = vec![
+            (Builtin::Base64, "hello", Some("aGVsbG8=")),
+            (Builtin::Base64Decode, "aGVsbG8=", Some("hello")),
+            (Builtin::Base64Decode, " aGVs\nbG8=\n", Some("hello")),
+            (Builtin::Base64Decode, "not base64!", None),
+            (Builtin::Trim, " \t hello world\n", Some("hello world")),
+            (Builtin::Trim, "   ", Some("")),
+            (Builtin::UrlEncode, "p@ss w/rd&x=1~", Some("p%40ss%20w%2Frd%26x%3D1~")),
+            (Builtin::Upper, "Hello", Some("HELLO")),
+            (Builtin::Lower, "Hello", Some("hello")),
+        ];
+
+        for (builtin, input, expected) in cases {
+            let actual = builtin.apply(input.as_bytes().to_vec()).ok().map(|out| String::from_utf8(out).unwrap());
+            assert_eq!(actual.as_deref(), expected, "Unexpected output from '@{}' given '{}'", builtin.name(), input);
+        }
+
+    }
+
+    #[test]
+    fn find_programs() {
+
+        let cases = vec![
+            ("base64", vec!["base64"]),
+            ("base64 | rev", vec!["base64", "rev"]),
+            ("jq -r .password && tr -d '\\n'", vec!["jq", "tr"]),
+            ("LC_ALL=C /usr/bin/sort; echo done", vec!["/usr/bin/sort"]),
+            // Builtins and expansions are skipped:
+            ("read x; printf '%s' \"$x\"", vec![]),
+            ("$TOOL --flag", vec![]),
+            // As are keywords, to find the program after them:
+            ("{ read -r x; echo \"${x^^}\"; }", vec![]),
+            ("if ! grep -q x; then base64; fi", vec!["grep", "base64"]),
+            ("@vault-hash", vec![]),
+            ("@base64", vec![]),
+            ("env:my-tool --in \"$secret\"", vec!["my-tool"]),
+        ];
+
+        for (s, expected) in cases {
+            let processor = Processor::from_str(s).unwrap();
+            assert_eq!(processor.programs(), expected, "Unexpected programs found in '{}'", s);
+        }
+
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn run_commands_with_lots_of_output() {
+
+        let shell = Shell::default();
+        let max_size = ByteSize(1 << 10);
+        let cases = vec![
+            // Plenty written to stderr before anything is written to stdout:
+            ("cat; head -c 1000000 /dev/zero >&2", SecretInput::Stdin, Ok("hello")),
+            ("head -c 1000000 /dev/zero >&2; echo \"$secret\"", SecretInput::EnvVar, Ok("hello")),
+            // Too much written to stdout:
+            ("cat; head -c 1000000 /dev/zero", SecretInput::Stdin, Err("is larger than the maximum allowed size of 1KiB")),
+        ];
+
+        for (command, input, expected) in cases {
+            let run = run_command(b"hello".to_vec(), &shell, command, input, max_size);
+            let res = tokio::time::timeout(std::time::Duration::from_secs(10), run)
+                .await
+                .unwrap_or_else(|_| panic!("The command '{}' didn't finish", command));
+            match (res, expected) {
+                (Ok(output), Ok(expected)) => assert_eq!(output, expected.as_bytes(), "Unexpected output from '{}'", command),
+                (Err(e), Err(expected)) => assert!(e.to_string().contains(expected), "Unexpected error from '{}': {}", command, e),
+                (res, _) => panic!("Unexpected result from '{}': {:?}", command, res.map(String::from_utf8))
+            }
+        }
+
+    }
+
+}
@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use anyhow::{ anyhow, Result, Context };
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use vault_inject::processor;
+
+/// A key and the value to write to it, given as 'key=value', 'key=@path/to/file',
+/// or 'key=@-' to read the value from stdin.
+#[derive(Debug,Clone,PartialEq)]
+pub struct KeyValue {
+    key: String,
+    value: ValueSource
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum ValueSource {
+    Literal(String),
+    File(PathBuf),
+    Stdin
+}
+
+impl FromStr for KeyValue {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<KeyValue> {
+        let idx = s.find('=')
+            .ok_or_else(|| anyhow!("Expected values of the form 'key=value', 'key=@file' or 'key=@-' but got '{}'", s))?;
+        let key = s[0..idx].trim();
+        if key.is_empty() {
+            return Err(anyhow!("Expected a key before the '=' in '{}'", s));
+        }
+
+        let value = &s[idx+1..];
+        let value = match value.strip_prefix('@') {
+            Some("-") => ValueSource::Stdin,
+            Some("") => return Err(anyhow!("Expected a file to read the value from after the '@' in '{}'", s)),
+            Some(path) => ValueSource::File(path.into()),
+            None => ValueSource::Literal(value.to_owned())
+        };
+        Ok(KeyValue { key: key.to_owned(), value })
+    }
+}
+
+/// Read the value for each key given. Values from stdin have a trailing
+/// newline removed, so that they can be typed or echoed in.
+pub async fn read_values(key_values: &[KeyValue]) -> Result<Vec<(String,String)>> {
+    if key_values.iter().filter(|kv| kv.value == ValueSource::Stdin).count() > 1 {
+        return Err(anyhow!("Only one value can be read from stdin ('@-')"));
+    }
+
+    let mut values = Vec::new();
+    for KeyValue { key, value } in key_values {
+        let value = match value {
+            ValueSource::Literal(value) => value.clone(),
+            ValueSource::File(path) => fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read the value for '{}' from '{}'", key, path.display()))?,
+            ValueSource::Stdin => {
+                let mut value = Vec::new();
+                tokio::io::stdin().read_to_end(&mut value)
+                    .await
+                    .with_context(|| format!("Failed to read the value for '{}' from stdin", key))?;
+                String::from_utf8(processor::trim_trailing_newline(value))
+                    .with_context(|| format!("The value for '{}' is not valid UTF-8", key))?
+            }
+        };
+        values.push((key.clone(), value));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parse_key_values() {
+
+        let cases = vec![
+            ("password=hunter2", Some(("password", ValueSource::Literal("hunter2".to_owned())))),
+            ("url = a=b", Some(("url", ValueSource::Literal(" a=b".to_owned())))),
+            ("empty=", Some(("empty", ValueSource::Literal("".to_owned())))),
+            ("password=@-", Some(("password", ValueSource::Stdin))),
+            ("cert=@/etc/app/cert.pem", Some(("cert", ValueSource::File("/etc/app/cert.pem".into())))),
+            ("cert=@", None),
+            ("=hunter2", None),
+            ("password", None),
+        ];
+
+        for (s, expected) in cases {
+            let expected = expected.map(|(key, value)| KeyValue { key: key.to_owned(), value });
+            assert_eq!(KeyValue::from_str(s).ok(), expected, "Unexpected result parsing '{}'", s);
+        }
+
+    }
+
+}
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use once_cell::sync::Lazy;
+use regex::{ Regex, Captures };
+use anyhow::{ anyhow, Result, Context };
+use futures::future;
+use tokio::{ fs, task };
+use vault_inject::secret_store::SecretStore;
+use crate::glob::Glob;
+use crate::files::{ self, FileOptions };
+
+/// Placeholders look like '{path/to/secret/key}'; they must contain at
+/// least one '/' so that they don't clash with other uses of braces.
+static PLACEHOLDER_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\{\s*([a-zA-Z0-9_.-]+(?:/[a-zA-Z0-9_.-]+)+)\s*\}").unwrap()
+});
+
+/// The name of the file (in the root of a template directory) which lists
+/// glob patterns for files that should not be rendered.
+static IGNORE_FILENAME: &str = ".vaultignore";
+
+/// Render templates containing '{path/to/secret/key}' placeholders, fetching
+/// each secret path that's referenced just once.
+pub struct Renderer<'a> {
+    store: &'a SecretStore,
+    secrets: HashMap<String, HashMap<String,String>>
+}
+
+impl <'a> Renderer<'a> {
+
+    pub fn new(store: &'a SecretStore) -> Renderer<'a> {
+        Renderer { store, secrets: HashMap::new() }
+    }
+
+    /// Render a single template, replacing each placeholder with the secret it
+    /// points to. It's an error for a placeholder to point to a missing secret.
+    pub async fn render(&mut self, template: &str) -> Result<String> {
+
+        // Fetch any secret paths that we haven't already got:
+        let mut paths = Vec::new();
+        for cap in PLACEHOLDER_RE.captures_iter(template) {
+            let (path, _) = split_path_and_key(cap.get(1).unwrap().as_str());
+            if !self.secrets.contains_key(path) && !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        let store = self.store;
+        let fetched = future::try_join_all(paths.into_iter().map(|path| async move {
+            let secrets = store.get(path).await?;
+            Ok::<_,anyhow::Error>((path.to_owned(), secrets.into_iter().collect()))
+        })).await?;
+        self.secrets.extend(fetched);
+
+        // Substitute the placeholders for the secrets:
+        let mut missing = None;
+        let out = PLACEHOLDER_RE.replace_all(template, |cap: &Captures| {
+            let (path, key) = split_path_and_key(&cap[1]);
+            match self.secrets.get(path).and_then(|s| s.get(key)) {
+                Some(val) => val.to_owned(),
+                None => {
+                    missing.get_or_insert_with(|| cap[1].to_owned());
+                    String::new()
+                }
+            }
+        });
+        if let Some(missing) = missing {
+            return Err(anyhow!("The secret '{}' could not be found", missing));
+        }
+
+        Ok(out.into_owned())
+    }
+
+    /// Render every file in the template directory into the output directory,
+    /// preserving the directory structure and skipping anything matching a
+    /// pattern in the template directory's '.vaultignore' file. Returns true
+    /// if any of the output files changed.
+    pub async fn render_dir(&mut self, template_dir: &Path, out_dir: &Path, file_opts: &FileOptions) -> Result<bool> {
+        let mut changed = false;
+        for rel_path in template_files(template_dir).await? {
+            changed |= self.render_file(&template_dir.join(&rel_path), &out_dir.join(&rel_path), file_opts).await?;
+        }
+        Ok(changed)
+    }
+
+    /// Render a single template file to the output path given. Returns
+    /// true if the output file changed.
+    pub async fn render_file(&mut self, template_path: &Path, out_path: &Path, file_opts: &FileOptions) -> Result<bool> {
+        let template = fs::read_to_string(template_path)
+            .await
+            .with_context(|| format!("Failed to read the template '{}'", template_path.display()))?;
+        let rendered = self.render(&template)
+            .await
+            .with_context(|| format!("Failed to render the template '{}'", template_path.display()))?;
+
+        files::write_file(out_path, rendered.as_bytes(), file_opts)
+            .await
+            .with_context(|| format!("Failed to write the rendered template to '{}'", out_path.display()))
+    }
+
+}
+
+/// Find the secret paths that the templates in some directory point to, without
+/// fetching them. Each path is returned once, in the order first seen.
+pub async fn find_secret_paths(template_dir: &Path) -> Result<Vec<String>> {
+    let mut paths: Vec<String> = Vec::new();
+    for rel_path in template_files(template_dir).await? {
+        for path in find_secret_paths_in_file(&template_dir.join(&rel_path)).await? {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Find the secret paths that a single template file points to, without fetching them.
+pub async fn find_secret_paths_in_file(template_path: &Path) -> Result<Vec<String>> {
+    let template = fs::read_to_string(template_path)
+        .await
+        .with_context(|| format!("Failed to read the template '{}'", template_path.display()))?;
+    let mut paths: Vec<String> = Vec::new();
+    for cap in PLACEHOLDER_RE.captures_iter(&template) {
+        let (path, _) = split_path_and_key(cap.get(1).unwrap().as_str());
+        if !paths.iter().any(|p| p == path) {
+            paths.push(path.to_owned());
+        }
+    }
+    Ok(paths)
+}
+
+/// A single template file to render, and where to write it; given as 'in.tmpl:out.conf'.
+#[derive(Debug,Clone,PartialEq)]
+pub struct TemplateFile {
+    pub input: PathBuf,
+    pub output: PathBuf
+}
+
+impl FromStr for TemplateFile {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let idx = s.find(':')
+            .ok_or_else(|| anyhow!("'{}' should be the template and the file to write to, separated by ':' (eg 'in.tmpl:out.conf')", s))?;
+        let (input, output) = (s[..idx].trim(), s[idx+1..].trim());
+        if input.is_empty() || output.is_empty() {
+            return Err(anyhow!("'{}' should name both a template and a file to write to (eg 'in.tmpl:out.conf')", s));
+        }
+        Ok(TemplateFile { input: input.into(), output: output.into() })
+    }
+}
+
+/// Find the templates in some directory that aren't ignored, returning
+/// their paths relative to the directory.
+async fn template_files(template_dir: &Path) -> Result<Vec<PathBuf>> {
+    let ignore = load_ignore_patterns(template_dir).await?;
+    let dir = template_dir.to_owned();
+    let files = task::spawn_blocking(move || find_files(&dir))
+        .await?
+        .with_context(|| format!("Failed to read the template directory '{}'", template_dir.display()))?;
+    Ok(files.into_iter().filter(|rel_path| !is_ignored(&ignore, rel_path)).collect())
+}
+
+/// Split 'path/to/secret/key' into 'path/to/secret' and 'key'.
+fn split_path_and_key(s: &str) -> (&str, &str) {
+    let idx = s.rfind('/').unwrap_or(0);
+    (s[0..idx].trim_start_matches('/'), &s[idx+1..])
+}
+
+/// Load the glob patterns in the '.vaultignore' file if one exists.
+async fn load_ignore_patterns(template_dir: &Path) -> Result<Vec<Glob>> {
+    let ignore_path = template_dir.join(IGNORE_FILENAME);
+    let contents = match fs::read_to_string(&ignore_path).await {
+        Ok(contents) => contents,
+        Err(_) => return Ok(Vec::new())
+    };
+
+    let mut patterns = vec![Glob::new(IGNORE_FILENAME)?];
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let glob = Glob::new(line.trim_matches('/'))
+            .with_context(|| format!("Invalid pattern in '{}'", ignore_path.display()))?;
+        patterns.push(glob);
+    }
+    Ok(patterns)
+}
+
+/// A file is ignored if a pattern matches its path relative to the template
+/// directory, or any of its parent directories, or any single path component
+/// (so that 'secrets' ignores a 'secrets' folder wherever it is).
+fn is_ignored(patterns: &[Glob], rel_path: &Path) -> bool {
+    let components: Vec<_> = rel_path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect();
+
+    for (idx,component) in components.iter().enumerate() {
+        let prefix = components[0..=idx].join("/");
+        if patterns.iter().any(|p| p.matches(&prefix) || p.matches(component)) {
+            return true
+        }
+    }
+    false
+}
+
+/// Recursively find every file in some directory, returning their
+/// paths relative to that directory.
+fn find_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    fn go(root: &Path, rel_dir: &Path, out: &mut Vec<PathBuf>) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(root.join(rel_dir))? {
+            let entry = entry?;
+            let rel_path = rel_dir.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                go(root, &rel_path, out)?;
+            } else {
+                out.push(rel_path);
+            }
+        }
+        Ok(())
+    }
+
+    let mut out = Vec::new();
+    go(dir, Path::new(""), &mut out)?;
+    out.sort();
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn find_placeholders() {
+
+        let cases = vec![
+            ("password = {secret/app/db/password}", vec!["secret/app/db/password"]),
+            ("{ secret/app/db/user }:{secret/app/db/password}", vec!["secret/app/db/user", "secret/app/db/password"]),
+            // Braces without a '/' in them are left alone:
+            ("{ \"json\": true }", vec![]),
+            ("server { listen 80; }", vec![]),
+            ("${HOME}", vec![]),
+        ];
+
+        for (template, expected) in cases {
+            let actual: Vec<_> = PLACEHOLDER_RE.captures_iter(template)
+                .map(|cap| cap[1].to_owned())
+                .collect();
+            assert_eq!(actual, expected, "Unexpected placeholders found in '{}'", template);
+        }
+
+    }
+
+    #[test]
+    fn parse_template_files() {
+
+        let cases = vec![
+            ("app.conf.tmpl:/etc/app.conf", Some(("app.conf.tmpl", "/etc/app.conf"))),
+            ("in.tmpl : out.conf", Some(("in.tmpl", "out.conf"))),
+            ("app.conf.tmpl", None),
+            (":out.conf", None),
+            ("in.tmpl:", None),
+        ];
+
+        for (s, expected) in cases {
+            let expected = expected.map(|(i,o)| TemplateFile { input: i.into(), output: o.into() });
+            assert_eq!(TemplateFile::from_str(s).ok(), expected, "Unexpected result parsing '{}'", s);
+        }
+
+    }
+
+    #[test]
+    fn ignore_patterns() {
+
+        let patterns: Vec<Glob> = vec!["*.bak", "secrets", "nginx/sites/*"]
+            .into_iter()
+            .map(|p| Glob::new(p).unwrap())
+            .collect();
+
+        let cases = vec![
+            ("app.conf", false),
+            ("app.conf.bak", true),
+            ("nested/app.conf.bak", true),
+            ("secrets/app.conf", true),
+            ("nested/secrets/app.conf", true),
+            ("nginx/sites/default", true),
+            ("nginx/nginx.conf", false),
+        ];
+
+        for (path, ignored) in cases {
+            assert_eq!(is_ignored(&patterns, Path::new(path)), ignored, "Unexpected result ignoring '{}'", path);
+        }
+
+    }
+
+}
@@ -0,0 +1,206 @@
+use std::collections::{ HashMap, VecDeque };
+use std::str::FromStr;
+use anyhow::{ anyhow, Result, Context };
+use futures::future;
+use tracing::warn;
+use crate::logging;
+use crate::processor;
+use crate::secret_mapping::SecretMapping;
+use crate::secret_store::SecretStore;
+use crate::shell::Shell;
+use crate::size::ByteSize;
+
+/// The largest secret we'll accept from processing commands unless told otherwise.
+pub const DEFAULT_MAX_SECRET_SIZE: ByteSize = ByteSize(4 << 20);
+
+/// What to do about a mapping that fails to resolve (eg because the secret
+/// can't be found) and doesn't have a default value to fall back to.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum OnError {
+    /// Fail, so that nothing runs without every secret it needs.
+    Fail,
+    /// Leave the environment variable unset and carry on.
+    Skip,
+    /// Set the environment variable to an empty value and carry on.
+    Default
+}
+
+impl OnError {
+
+    /// What a mapping resolves to, given the result of resolving it. Mappings with
+    /// a default value fall back to it if they fail or the key isn't found; for
+    /// the rest, failures are handled as this says.
+    pub fn apply(self, mapping: &SecretMapping, res: Result<Vec<(String,String)>>) -> Result<Vec<(String,String)>> {
+        let env_var = mapping.env_var_name();
+        let e = match (res, mapping.default_value(), &env_var) {
+            (Ok(values), Some(default_value), Some(env_var)) if values.is_empty() => {
+                return Ok(vec![(env_var.clone(), default_value.to_owned())]);
+            },
+            (Ok(values), _, _) => return Ok(values),
+            (Err(e), _, _) => e
+        };
+        match (self, mapping.default_value(), env_var) {
+            (_, Some(default_value), Some(env_var)) => {
+                warn!("Using the default value for '{}': {}", mapping, first_line(&e));
+                Ok(vec![(env_var, default_value.to_owned())])
+            },
+            (OnError::Fail, _, _) => Err(e),
+            (OnError::Default, _, Some(env_var)) => {
+                warn!("Using an empty value for '{}': {}", mapping, first_line(&e));
+                Ok(vec![(env_var, String::new())])
+            },
+            (OnError::Skip, _, _) | (OnError::Default, _, None) => {
+                warn!("Skipping '{}': {}", mapping, first_line(&e));
+                Ok(Vec::new())
+            }
+        }
+    }
+
+}
+
+impl FromStr for OnError {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<OnError> {
+        match s.to_ascii_lowercase().as_str() {
+            "fail" => Ok(OnError::Fail),
+            "skip" => Ok(OnError::Skip),
+            "default" => Ok(OnError::Default),
+            _ => Err(anyhow!("'{}' is not a valid error policy (try 'fail', 'skip' or 'default').", s))
+        }
+    }
+}
+
+/// The first line of an error, for warnings which shouldn't go on at length.
+fn first_line(e: &anyhow::Error) -> String {
+    e.to_string().lines().next().unwrap_or_default().to_owned()
+}
+
+/// Fetch the secrets pointed to by each mapping, returning the value of each
+/// environment variable that they set. Later mappings take precedence, and
+/// mappings which fail fall back to their default values if they have them.
+///
+/// Mappings which write to a file or hand the secret over through a FIFO or
+/// memfd come back under their target name (eg 'file:/etc/app/key'), as
+/// nothing is written here; use [`Target::parse`] to tell them apart from
+/// environment variables. Processor commands are run with the default shell.
+///
+/// [`Target::parse`]: crate::secret_mapping::Target::parse
+pub async fn resolve(store: &SecretStore, mappings: &[SecretMapping]) -> Result<HashMap<String,String>> {
+    let shell = &Shell::default();
+    let resolved = future::try_join_all(mappings.iter().map(|mapping| async move {
+        let res = resolve_mapping(store, mapping, shell, DEFAULT_MAX_SECRET_SIZE).await;
+        OnError::Fail.apply(mapping, res)
+    })).await?;
+    Ok(resolved.into_iter().flatten().collect())
+}
+
+/// Fetch the secrets pointed to by a single mapping, returning the environment
+/// variables that it sets and their values (once selected from, processed and
+/// checked against any assertion). Processor commands are run via the shell given.
+pub async fn resolve_mapping(store: &SecretStore, secret_mapping: &SecretMapping, shell: &Shell, max_secret_size: ByteSize) -> Result<Vec<(String,String)>> {
+    if secret_mapping.path_param().is_some() {
+        let mappings = expand_path_params(store, secret_mapping).await?;
+        let resolved = future::try_join_all(mappings.iter().map(|mapping| {
+            resolve_mapping_at_path(store, mapping, shell, max_secret_size)
+        })).await?;
+        return Ok(resolved.into_iter().flatten().collect());
+    }
+    resolve_mapping_at_path(store, secret_mapping, shell, max_secret_size).await
+}
+
+/// Expand a mapping with template parameters in its path (eg 'DB_{name} =
+/// kv/app/databases/{name}/password') into one mapping for each secret that
+/// they can be filled in with, found by listing the path up to each in turn.
+pub async fn expand_path_params(store: &SecretStore, secret_mapping: &SecretMapping) -> Result<Vec<SecretMapping>> {
+    let mut expanded = Vec::new();
+    let mut pending = VecDeque::from(vec![secret_mapping.clone()]);
+    while let Some(mapping) = pending.pop_front() {
+        let (dir, _, rest) = match mapping.path_param() {
+            Some(param) => param,
+            None => {
+                expanded.push(mapping);
+                continue
+            }
+        };
+        // Parameters followed by more of the path are filled in with directories:
+        let is_dir = rest.starts_with('/');
+        let entries = store.list(dir)
+            .await
+            .with_context(|| format!("Could not list the secrets matching '{}'", mapping.path()))?;
+        for entry in &entries {
+            let name = match entry.strip_suffix('/') {
+                Some(name) if is_dir => name,
+                None if !is_dir => entry,
+                _ => continue
+            };
+            if !name.contains(['{', '}']) {
+                pending.push_back(mapping.with_path_param(name));
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// Fetch the secrets pointed to by a single mapping whose path has no template parameters.
+async fn resolve_mapping_at_path(store: &SecretStore, secret_mapping: &SecretMapping, shell: &Shell, max_secret_size: ByteSize) -> Result<Vec<(String,String)>> {
+    let secret_values = store.get(secret_mapping.path()).await?;
+    // If the secret has a key that the selector is part of (eg 'key#.field'), that's the one we want:
+    let selector_in_key = secret_mapping.with_selector_in_key()
+        .filter(|mapping| secret_values.iter().any(|(key, _)| mapping.env_var_from_key(key).is_some()));
+    let secret_mapping = selector_in_key.as_ref().unwrap_or(secret_mapping);
+    let mut out_values = Vec::new();
+    for (key,val) in secret_values {
+        if let Some(env_var) = secret_mapping.env_var_from_key(&key) {
+            let val = match secret_mapping.selector() {
+                Some(selector) => selector.select(&val)
+                    .with_context(|| format!("Failed to select '{}' from the secret '{}/{}'", selector, secret_mapping.path(), key))?,
+                None => val
+            };
+            let secret_value = processor::process(val.into_bytes(), secret_mapping.processors(), store.client(), shell, max_secret_size)
+                .await
+                .with_context(|| format!("Failed to process the secret '{}/{}'", secret_mapping.path(), key))?;
+            logging::redact(&secret_value);
+            if let Some(assertion) = secret_mapping.assertion() {
+                if !assertion.check(&secret_value) {
+                    return Err(anyhow!("The value for '{}' (from the secret '{}/{}') does not pass the assertion '{}'"
+                        , env_var, secret_mapping.path(), key, assertion));
+                }
+            }
+            out_values.push((env_var, secret_value));
+        }
+    }
+    Ok(out_values)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn apply_error_policies() {
+        type Resolved = Result<Vec<(String,String)>>;
+        let ok = || -> Resolved { Ok(vec![("FOO".to_owned(), "secret".to_owned())]) };
+        let missing = || -> Resolved { Ok(Vec::new()) };
+        let failed = || -> Resolved { Err(anyhow!("Could not find any secrets")) };
+        let cases = vec![
+            ("FOO = kv/app/foo", OnError::Fail, ok(), Some(vec![("FOO", "secret")])),
+            ("FOO = kv/app/foo ?? fallback", OnError::Fail, ok(), Some(vec![("FOO", "secret")])),
+            ("FOO = kv/app/foo ?? fallback", OnError::Fail, missing(), Some(vec![("FOO", "fallback")])),
+            ("FOO = kv/app/foo ?? fallback", OnError::Fail, failed(), Some(vec![("FOO", "fallback")])),
+            ("FOO = kv/app/foo ?? fallback", OnError::Skip, failed(), Some(vec![("FOO", "fallback")])),
+            ("FOO = kv/app/foo", OnError::Fail, missing(), Some(vec![])),
+            ("FOO = kv/app/foo", OnError::Fail, failed(), None),
+            ("FOO = kv/app/foo", OnError::Skip, failed(), Some(vec![])),
+            ("FOO = kv/app/foo", OnError::Default, failed(), Some(vec![("FOO", "")])),
+            ("APP_{key} = kv/app/*", OnError::Default, failed(), Some(vec![])),
+        ];
+        for (mapping_str, on_error, res, expected) in cases {
+            let mapping = SecretMapping::from_str(mapping_str).unwrap();
+            let actual = on_error.apply(&mapping, res).ok();
+            let expected = expected.map(|values| values.into_iter().map(|(k,v)| (k.to_owned(), v.to_owned())).collect::<Vec<_>>());
+            assert_eq!(actual, expected, "Unexpected result for '{}' with {:?}", mapping_str, on_error);
+        }
+    }
+
+}
@@ -1,23 +1,100 @@
+use std::path::Path;
 use std::str::FromStr;
 use anyhow::{ anyhow, Result };
 use crate::template::Template;
+use crate::processor::{ self, Processor, ProcessorDef };
+use crate::assertion::Assertion;
+use crate::secret_store::split_version;
+use crate::selector::Selector;
 
 /// A mapping from secret to environment variable
 #[derive(Clone,Debug)]
 pub struct SecretMapping {
     path: String,
     key: Template,
-    processors: Vec<String>,
+    selector: Option<Selector>,
+    processors: Vec<Processor>,
+    assertion: Option<Assertion>,
     env_var: Template,
+    default_value: Option<String>,
 }
 
 impl SecretMapping {
     pub fn path(&self) -> &str {
         &self.path
     }
-    pub fn processors(&self) -> &[String] {
+    pub fn selector(&self) -> Option<&Selector> {
+        self.selector.as_ref()
+    }
+    pub fn processors(&self) -> &[Processor] {
         &self.processors
     }
+    pub fn assertion(&self) -> Option<&Assertion> {
+        self.assertion.as_ref()
+    }
+    /// The value to use if the secret can't be found, given like 'FOO = path/to/secret/key ?? value'.
+    pub fn default_value(&self) -> Option<&str> {
+        self.default_value.as_deref()
+    }
+
+    /// The first part of the path that's a template parameter, as the path up to
+    /// it, the parameter's name and the rest of the path (eg 'kv/app/', 'name' and
+    /// '/db' for the path 'kv/app/{name}/db').
+    pub fn path_param(&self) -> Option<(&str,&str,&str)> {
+        let start = self.path.find('{')?;
+        let end = start + self.path[start..].find('}')?;
+        Some((&self.path[..start], &self.path[start+1..end], &self.path[end+1..]))
+    }
+
+    /// A key followed by a selector (eg 'key#.field') could instead be a key with a
+    /// '#' in it. This is the mapping which takes it that way, which is used instead
+    /// if the secret turns out to have a key by that name.
+    pub fn with_selector_in_key(&self) -> Option<SecretMapping> {
+        let selector = self.selector.as_ref()?;
+        let key = Template::new(&format!("{}#{}", self.key.as_literal()?, selector)).ok()?;
+        Some(SecretMapping { key, selector: None, ..self.clone() })
+    }
+
+    /// This mapping with the first template parameter in its path (see 'path_param')
+    /// filled in with the value given, both there and in the environment variable.
+    pub fn with_path_param(&self, value: &str) -> SecretMapping {
+        let mut mapping = self.clone();
+        if let Some((before, name, after)) = self.path_param() {
+            mapping.path = format!("{}{}{}", before, value, after);
+            mapping.env_var = self.env_var.fill(name, value);
+        }
+        mapping
+    }
+
+    /// Expand any processors which name one of the definitions given.
+    pub fn with_processor_defs(mut self, defs: &[ProcessorDef]) -> SecretMapping {
+        self.processors = processor::expand(&self.processors, defs);
+        self
+    }
+
+    /// The environment variable that this mapping will set, if
+    /// it doesn't depend on the keys of the secrets found.
+    pub fn env_var_name(&self) -> Option<String> {
+        self.env_var.as_literal()
+    }
+
+    /// Is the secret handed over through a FIFO or memfd? These only
+    /// exist while we're running, so need a command to hand them to.
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(Target::parse(&self.env_var.to_string()), Target::Fifo(_) | Target::Memfd(_))
+    }
+
+    /// Is the secret handed over through a FIFO? These need us to stay
+    /// running to write the secret to them.
+    pub fn is_fifo(&self) -> bool {
+        matches!(Target::parse(&self.env_var.to_string()), Target::Fifo(_))
+    }
+
+    /// Does this mapping pick out keys with a template (like '{key}' or '*'),
+    /// rather than naming a single key?
+    pub fn has_key_template(&self) -> bool {
+        self.key.as_literal().is_none()
+    }
 
     /// If the provided key matches this mapping, return the
     /// environment variable name it corresponds to, else None.
@@ -28,6 +105,68 @@ impl SecretMapping {
     }
 }
 
+/// Where a secret ends up. Mappings normally set an environment variable, but
+/// one written like 'file:/path/to/file = path/to/secret/key' writes to a file.
+/// 'fifo:ENV_VAR' and 'memfd:ENV_VAR' hand the secret over through a named pipe
+/// or an anonymous memory file, setting the env var to its path or fd number.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Target<'a> {
+    EnvVar(&'a str),
+    File(&'a Path),
+    Fifo(&'a str),
+    Memfd(&'a str)
+}
+
+impl <'a> Target<'a> {
+    /// Work out the target from the name that a mapping produces.
+    pub fn parse(name: &'a str) -> Target<'a> {
+        if let Some(path) = name.strip_prefix("file:") {
+            Target::File(Path::new(path))
+        } else if let Some(env_var) = name.strip_prefix("fifo:") {
+            Target::Fifo(env_var)
+        } else if let Some(env_var) = name.strip_prefix("memfd:") {
+            Target::Memfd(env_var)
+        } else {
+            Target::EnvVar(name)
+        }
+    }
+
+    /// The environment variable that's set, if any (files aren't handed over in one).
+    pub fn env_var(&self) -> Option<&'a str> {
+        match *self {
+            Target::EnvVar(env_var) | Target::Fifo(env_var) | Target::Memfd(env_var) => Some(env_var),
+            Target::File(_) => None
+        }
+    }
+}
+
+impl std::fmt::Display for SecretMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match split_version(&self.path) {
+            Ok((path, Some(version))) => write!(f, "{} = {}/{}?version={}", self.env_var, path, self.key, version)?,
+            _ => write!(f, "{} = {}/{}", self.env_var, self.path, self.key)?
+        }
+        if let Some(selector) = &self.selector {
+            write!(f, "#{}", selector)?;
+        }
+        for processor in &self.processors {
+            write!(f, " | {}", processor)?;
+        }
+        if let Some(assertion) = &self.assertion {
+            write!(f, " | {}", assertion)?;
+        }
+        if let Some(default_value) = &self.default_value {
+            // Quote values which wouldn't survive being parsed again as they are:
+            if default_value.trim() != default_value || default_value.starts_with('"') {
+                write!(f, " ?? {}", serde_json::Value::from(&**default_value))?;
+            } else {
+                write!(f, " ?? {}", default_value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl FromStr for SecretMapping {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<SecretMapping> {
@@ -37,6 +176,17 @@ impl FromStr for SecretMapping {
         let env_var_str = s[0..idx].trim();
         let secret_str = &s[idx+1..];
 
+        // A default value to use if the secret can't be found comes after
+        // everything else, following a ' ??' or a '||':
+        let (secret_str, default_value) = split_default_value(secret_str)?;
+
+        // An assertion must come last. Its regex may contain '|', so
+        // we split it off before splitting up the processors:
+        let (secret_str, assertion) = match find_assertion(secret_str) {
+            Some(idx) => (&secret_str[0..idx], Some(Assertion::from_str(&secret_str[idx+1..])?)),
+            None => (secret_str, None)
+        };
+
         let secret_str_bits = secret_str
             .split('|')
             .map(|s| s.trim())
@@ -53,33 +203,180 @@ impl FromStr for SecretMapping {
             .split_first()
             .ok_or_else(|| anyhow!("Expected secret values of the form 'path/to/secret/key [| command ...]' but got '{}'", secret_str))?;
 
-        let (path_str, key_str) = split_secret_path_and_key(path_and_key_str)
-            .ok_or_else(|| anyhow!("Expected the secret path to have at least one '/' in it but got '{}'", path_and_key_str))?;
+        // A field can be selected from values which are JSON documents with a
+        // suffix like '#.database.host'. Keys can contain a '#' too, so anything
+        // else after one is part of the key (see also 'with_selector_in_key'):
+        let (path_and_key_str, selector) = split_selector(path_and_key_str);
 
-        let path = path_str.trim_start_matches('/').to_owned();
+        // A KV2 version can be pinned with a '?version=N' suffix:
+        let (path_and_key_str, version) = split_version(path_and_key_str)?;
 
-        let key = Template::new(key_str)
-            .map_err(|e| anyhow!("Invalid key template '{}': {}", key_str, e))?;
+        // A wrapping token ('unwrap:<token>') can be given without a key, to
+        // use whatever was wrapped in it:
+        let (path_str, key_str) = match path_and_key_str.strip_prefix("unwrap:") {
+            Some(token) if !token.contains('/') => (path_and_key_str, "*"),
+            _ => split_secret_path_and_key(path_and_key_str)
+                .ok_or_else(|| anyhow!("Expected the secret path to have at least one '/' in it but got '{}'", path_and_key_str))?
+        };
+
+        // The version is passed on to the store as part of the path:
+        let path = match version {
+            Some(version) => format!("{}?version={}", path_str.trim_start_matches('/'), version),
+            None => path_str.trim_start_matches('/').to_owned()
+        };
+
+        // Parts of the path can be template parameters (eg 'kv/app/{name}/password'),
+        // which are filled in with each secret found by listing the path up to them:
+        let path = match path.contains(['{', '}']) {
+            true => check_path_params(&path)?,
+            false => path
+        };
+
+        // A key of '*' matches every key, which is available as '{key}':
+        let key = if key_str == "*" {
+            Template::new("{key}")?
+        } else {
+            Template::new(key_str)
+                .map_err(|e| anyhow!("Invalid key template '{}': {}", key_str, e))?
+        };
         let env_var = Template::new(env_var_str)
             .map_err(|e| anyhow!("Invalid environment variable template '{}': {}", env_var_str, e))?;
-        if !env_var.can_stringify_from(&key) {
-            return Err(anyhow!("The environment variable pattern '{}' contains template parameters not seen in the corresponding key '{}'", env_var_str, key_str));
+        match Target::parse(env_var_str) {
+            Target::File(path) if path.as_os_str().is_empty() => {
+                return Err(anyhow!("Expected a path to write the secret to after 'file:' but got '{}'", env_var_str));
+            },
+            Target::Fifo("") | Target::Memfd("") => {
+                return Err(anyhow!("Expected an environment variable name after the ':' in '{}'", env_var_str));
+            },
+            _ => {}
+        }
+        let params = Template::new(&format!("{}/{}", path, key))
+            .map_err(|e| anyhow!("Invalid secret path template '{}': {}", path_and_key_str, e))?;
+        if !env_var.can_stringify_from(&params) {
+            return Err(anyhow!("The environment variable pattern '{}' contains template parameters not seen in the corresponding path or key '{}'", env_var_str, path_and_key_str));
+        }
+        if default_value.is_some() && env_var.as_literal().is_none() {
+            return Err(anyhow!("A default value can only be given for a mapping to a single environment variable, not '{}'", env_var_str));
         }
 
         let processors = processor_strs
             .iter()
-            .map(|&s| s.to_owned())
-            .collect();
+            .map(|&s| Processor::from_str(s))
+            .collect::<Result<_>>()?;
 
         Ok(SecretMapping {
             path,
             key,
+            selector,
             env_var,
-            processors
+            processors,
+            assertion,
+            default_value
         })
     }
 }
 
+/// Every secret under some path (looked for recursively), given like
+/// 'PREFIX_ = path/to/secrets/'. Each key is injected into an environment
+/// variable named after the prefix, the path to it and the key.
+#[derive(Clone,Debug,PartialEq)]
+pub struct SecretTree {
+    prefix: String,
+    path: String
+}
+
+impl SecretTree {
+    /// The path to look for secrets under; this always ends in '/'.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The environment variable for a key in the secret at some path relative
+    /// to the tree, eg 'PREFIX_' + 'db/main' + 'password' is 'PREFIX_DB_MAIN_PASSWORD'.
+    pub fn env_var(&self, rel_path: &str, key: &str) -> String {
+        let name: String = format!("{}_{}", rel_path, key)
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect();
+        format!("{}{}", self.prefix, name)
+    }
+}
+
+impl std::fmt::Display for SecretTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} = {}", self.prefix, self.path)
+    }
+}
+
+impl FromStr for SecretTree {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<SecretTree> {
+        let idx = s.find('=')
+            .ok_or_else(|| anyhow!("Expected secret trees of the form 'PREFIX_=path/to/secrets/' but got '{}'", s))?;
+        let prefix = s[0..idx].trim().to_owned();
+        let path = s[idx+1..].trim().trim_matches('/');
+        if path.is_empty() {
+            return Err(anyhow!("Expected a path to look for secrets under in '{}'", s));
+        }
+        Ok(SecretTree { prefix, path: format!("{}/", path) })
+    }
+}
+
+/// Split off the default value at the end of a mapping, if there is one. It's given
+/// after the first ' ??' (eg '?? value' or '?? "quoted value"'), or as a quoted
+/// string after a '||' (eg '|| "value"'), which would otherwise be an empty command.
+fn split_default_value(s: &str) -> Result<(&str, Option<String>)> {
+    let after_question_marks = s.find(" ??").map(|idx| (idx, idx + 3));
+    let after_pipes = s.match_indices("||")
+        .map(|(idx, _)| idx)
+        .find(|&idx| s[idx+2..].trim_start().starts_with('"'))
+        .map(|idx| (idx, idx + 2));
+    let (idx, start) = match after_question_marks.into_iter().chain(after_pipes).min() {
+        Some(found) => found,
+        None => return Ok((s, None))
+    };
+    let value = s[start..].trim();
+    if !value.starts_with('"') {
+        return Ok((&s[0..idx], Some(value.to_owned())));
+    }
+    let value = serde_json::from_str(value)
+        .map_err(|_| anyhow!("Expected the default value {} to be a string in double quotes (like \"value\")", value))?;
+    Ok((&s[0..idx], Some(value)))
+}
+
+/// Check that the template parameters in a secret path are whole parts of it
+/// (eg 'kv/app/{name}/password'), returning the path with them written plainly.
+fn check_path_params(path: &str) -> Result<String> {
+    let (path_without_version, _) = split_version(path)?;
+    for part in path_without_version.split('/') {
+        let is_param = part.starts_with('{')
+            && part.ends_with('}')
+            && part.matches(['{', '}']).count() == 2
+            && Template::new(part).map(|t| t.as_literal().is_none()).unwrap_or(false);
+        if part.contains(['{', '}']) && !is_param {
+            return Err(anyhow!("Only whole parts of a secret path can be template parameters (eg 'kv/app/{{name}}/password'), not '{}'", part));
+        }
+    }
+    let path = Template::new(path)
+        .map_err(|e| anyhow!("Invalid secret path template '{}': {}", path, e))?;
+    Ok(path.to_string())
+}
+
+/// Find the '|' that an '@assert' follows, if there is one.
+fn find_assertion(s: &str) -> Option<usize> {
+    s.match_indices('|')
+        .map(|(idx, _)| idx)
+        .find(|&idx| s[idx+1..].trim_start().starts_with("@assert"))
+}
+
+/// Split a selector off the end of a path and key, after the first '#' that's
+/// followed by one.
+fn split_selector(s: &str) -> (&str, Option<Selector>) {
+    s.match_indices('#')
+        .find_map(|(idx, _)| Some((&s[0..idx], Some(Selector::from_str(&s[idx+1..]).ok()?))))
+        .unwrap_or((s, None))
+}
+
 fn split_secret_path_and_key(s: &str) -> Option<(&str, &str)> {
     let idx = s.rfind('/')?;
     if idx == 0 { return None  }
@@ -113,6 +410,40 @@ mod test {
             // You can use parameters:
             ("{bar} = /hello/foo/{bar} ", Some(("{bar}", "hello/foo", "{bar}", vec![]))),
             ("FOO_{bar} = /hello/foo/{bar} ", Some(("FOO_{bar}", "hello/foo", "{bar}", vec![]))),
+            // Whole parts of the path can be parameters too:
+            ("DB_{name} = kv/app/databases/{name}/password", Some(("DB_{name}", "kv/app/databases/{name}", "password", vec![]))),
+            ("{env}_{name}_{key} = kv/{ env }/db/{name}/*", Some(("{env}_{name}_{key}", "kv/{env}/db/{name}", "{key}", vec![]))),
+            ("DB = kv/app/databases/{name}/password?version=2", Some(("DB", "kv/app/databases/{name}?version=2", "password", vec![]))),
+            // A key of '*' matches every key, as '{key}':
+            ("APP_{key} = kv/app/config/*", Some(("APP_{key}", "kv/app/config", "{key}", vec![]))),
+            // Secrets can be written to files:
+            ("file:/run/secrets/db_pass=kv/app/db/password", Some(("file:/run/secrets/db_pass", "kv/app/db", "password", vec![]))),
+            ("file:/run/secrets/{key} = kv/app/db/{key}", Some(("file:/run/secrets/{key}", "kv/app/db", "{key}", vec![]))),
+            // Built-in processors start with '@':
+            ("FOO = /hello/foo/bar | @vault-hash:sha2-512", Some(("FOO", "hello/foo", "bar", vec!["@vault-hash:sha2-512"]))),
+            // Secrets can be unwrapped from wrapping tokens, with or without a key:
+            ("FOO = unwrap:s.abc123", Some(("FOO", "unwrap:s.abc123", "{key}", vec![]))),
+            ("FOO = unwrap:s.abc123/password", Some(("FOO", "unwrap:s.abc123", "password", vec![]))),
+            ("{key} = unwrap:s.abc123/*", Some(("{key}", "unwrap:s.abc123", "{key}", vec![]))),
+            // The metadata of KV2 secrets can be mapped too:
+            ("CREATED = meta:kv/app/db/created_time", Some(("CREATED", "meta:kv/app/db", "created_time", vec![]))),
+            // Paths can name the type of store that they're in:
+            ("FOO = kv2:secret/app/foo/bar", Some(("FOO", "kv2:secret/app/foo", "bar", vec![]))),
+            ("FOO = /kv1:legacy/app/foo/bar", Some(("FOO", "kv1:legacy/app/foo", "bar", vec![]))),
+            // KV2 versions can be pinned, and are passed on as part of the path:
+            ("FOO = kv/app/db/password?version=3", Some(("FOO", "kv/app/db?version=3", "password", vec![]))),
+            ("FOO = kv/app/db/password?version=3 | base64", Some(("FOO", "kv/app/db?version=3", "password", vec!["base64"]))),
+            // Keys can contain '@', even followed by a number:
+            ("FOO = kv/app/db/user@example.com", Some(("FOO", "kv/app/db", "user@example.com", vec![]))),
+            ("FOO = kv/app/db/user@2", Some(("FOO", "kv/app/db", "user@2", vec![]))),
+            // Fields can be selected from JSON values:
+            ("FOO = kv/app/config/blob#.database.host", Some(("FOO", "kv/app/config", "blob", vec![]))),
+            ("FOO = kv/app/config/blob?version=2#/database/host | base64", Some(("FOO", "kv/app/config?version=2", "blob", vec!["base64"]))),
+            ("FOO = kv/app/config/blob#.[\"a#b\"]", Some(("FOO", "kv/app/config", "blob", vec![]))),
+            // Keys can contain a '#' that isn't followed by a valid selector:
+            ("FOO = kv/app/key#1", Some(("FOO", "kv/app", "key#1", vec![]))),
+            ("FOO = kv/app/config/blob#database", Some(("FOO", "kv/app/config", "blob#database", vec![]))),
+            ("FOO = kv/app/config/blob#.a..b", Some(("FOO", "kv/app/config", "blob#.a..b", vec![]))),
 
             // ###################
             // ### NOT Allowed ###
@@ -122,20 +453,40 @@ mod test {
             ("FOO", None),
             // The path string must have at least one '/' in it (path/key):
             ("FOO = /hello", None),
+            // '*' only provides '{key}':
+            ("APP_{name} = kv/app/config/*", None),
+            // Parameters in the path must be whole parts of it, and used once:
+            ("DB_{name} = kv/app/db_{name}/password", None),
+            ("DB_{name} = kv/app/{name}.d/password", None),
+            ("DB_{name} = kv/app/{name}/{name}", None),
+            ("DB_{name} = kv/app/{na me}/password", None),
+            ("DB_{other} = kv/app/{name}/password", None),
+            // Files must have a path, and FIFOs and memfds an env var:
+            ("file: = /hello/foo/bar", None),
+            ("fifo: = /hello/foo/bar", None),
+            ("memfd: = /hello/foo/bar", None),
             // You must use '='
             ("FOO /hello/lark", None),
             // You can't have empty commands:
             ("FOO = /hello/lark |", None),
             ("FOO = /hello/lark ||", None),
             ("FOO = /hello/lark ||rev", None),
+            // Versions must be numbers, starting at 1:
+            ("FOO = kv/app/db/password?version=latest", None),
+            ("FOO = kv/app/db/password?version=0", None),
+            // Built-in processors must exist:
+            ("FOO = /hello/lark | @nope", None),
+            // Assertions must be valid, and come last:
+            ("FOO = /hello/foo/bar | @assert:nope", None),
+            ("FOO = /hello/foo/bar | @assert:url | base64", None),
         ];
 
         for (s, res) in cases {
             match res {
                 Some((env, path, key, processors)) => {
-                    let processor_strings: Vec<String> = processors
+                    let processors: Vec<Processor> = processors
                         .into_iter()
-                        .map(|s: &str| s.to_owned())
+                        .map(|s: &str| Processor::from_str(s).unwrap())
                         .collect();
                     let mapping = match SecretMapping::from_str(s) {
                         Ok(mapping) => mapping,
@@ -145,7 +496,7 @@ mod test {
                     assert_eq!(Template::new(env).unwrap(), mapping.env_var, "Environment variable doesn't match expected");
                     assert_eq!(Template::new(key).unwrap(), mapping.key, "Key doesn't match expected");
                     assert_eq!(path.to_owned(), mapping.path, "Path doesn't match expected");
-                    assert_eq!(processor_strings, mapping.processors, "Piped commands don't match expected");
+                    assert_eq!(processors, mapping.processors, "Piped commands don't match expected");
                 },
                 None => {
                     if let Ok(mapping) = SecretMapping::from_str(s) {
@@ -157,4 +508,135 @@ mod test {
 
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_secret_mapping_selectors_in_keys() {
+        let cases = vec![
+            ("FOO = kv/app/config/blob#.database.host", Some(".database.host"), Some("blob#.database.host")),
+            ("FOO = kv/app/config/blob#/database/host | base64", Some("/database/host"), Some("blob#/database/host")),
+            // Only literal keys can have a selector in them:
+            ("APP_{key} = kv/app/config/*#.host", Some(".host"), None),
+            ("FOO = kv/app/key#1", None, None),
+            ("FOO = kv/app/config/blob", None, None),
+        ];
+        for (s, expected_selector, expected_key) in cases {
+            let mapping = SecretMapping::from_str(s).unwrap();
+            assert_eq!(mapping.selector().map(|s| s.to_string()).as_deref(), expected_selector, "Unexpected selector for '{}'", s);
+            let in_key = mapping.with_selector_in_key();
+            assert_eq!(in_key.as_ref().and_then(|m| m.key.as_literal()).as_deref(), expected_key, "Unexpected key with the selector in it for '{}'", s);
+            assert!(in_key.map(|m| m.selector().is_none()).unwrap_or(true), "Expected no selector once it's part of the key for '{}'", s);
+        }
+    }
+
+    #[test]
+    fn test_secret_mapping_path_params() {
+        let cases = vec![
+            ("DB_{name} = kv/app/databases/{name}/password", Some(("kv/app/databases/", "name", "")), vec!["main"], ("DB_main", "kv/app/databases/main")),
+            ("{env}_DB = kv/{env}/db/password", Some(("kv/", "env", "/db")), vec!["prod"], ("prod_DB", "kv/prod/db")),
+            ("{env}_{name} = kv/{env}/{name}/password", Some(("kv/", "env", "/{name}")), vec!["prod", "main"], ("prod_main", "kv/prod/main")),
+            ("DB = kv/app/databases/main/password", None, vec![], ("DB", "kv/app/databases/main")),
+        ];
+        for (s, expected_param, values, (expected_env_var, expected_path)) in cases {
+            let mut mapping = SecretMapping::from_str(s).unwrap();
+            assert_eq!(mapping.path_param(), expected_param, "Unexpected path param for '{}'", s);
+            for value in values {
+                mapping = mapping.with_path_param(value);
+            }
+            assert_eq!(mapping.path(), expected_path, "Unexpected path once filled in for '{}'", s);
+            assert_eq!(mapping.env_var_name().as_deref(), Some(expected_env_var), "Unexpected env var once filled in for '{}'", s);
+        }
+    }
+
+    #[test]
+    fn test_secret_mapping_defaults() {
+
+        let cases = vec![
+            ("FOO = kv/app/foo/bar", Some(None)),
+            ("FOO = kv/app/foo/bar ?? fallback", Some(Some("fallback"))),
+            ("FOO = kv/app/foo/bar ??  two words ", Some(Some("two words"))),
+            ("FOO = kv/app/foo/bar ??", Some(Some(""))),
+            ("FOO = kv/app/foo/bar | base64 | @assert:/^a??b$/ ?? fallback", Some(Some("fallback"))),
+            ("FOO = kv/app/foo/bar ?? a ?? b", Some(Some("a ?? b"))),
+            ("FOO = kv/app/foo/bar ?? \" padded \"", Some(Some(" padded "))),
+            // Quoted defaults can also follow '||':
+            ("FOO = kv/app/foo/bar || \"default-value\"", Some(Some("default-value"))),
+            ("FOO = kv/app/foo/bar | base64 ||\"say \\\"hi\\\"\"", Some(Some("say \"hi\""))),
+            ("FOO = kv/app/foo/bar || \"\"", Some(Some(""))),
+            ("FOO = kv/app/foo/bar || \"unterminated", None),
+            ("FOO = kv/app/foo/bar ?? \"unterminated", None),
+            ("FOO = kv/app/foo/bar || unquoted", None),
+            // Only a single environment variable can be given a default:
+            ("APP_{key} = kv/app/config/* ?? fallback", None),
+        ];
+
+        for (s, expected) in cases {
+            let actual = SecretMapping::from_str(s).ok();
+            assert_eq!(actual.as_ref().map(|m| m.default_value()), expected, "Unexpected default for '{}'", s);
+            // Defaults should survive being displayed and parsed again:
+            if let Some(mapping) = actual {
+                let reparsed = SecretMapping::from_str(&mapping.to_string()).unwrap();
+                assert_eq!(reparsed.default_value(), mapping.default_value(), "Unexpected default after displaying '{}'", s);
+            }
+        }
+
+    }
+
+    #[test]
+    fn test_targets() {
+
+        let cases = vec![
+            ("FOO", Target::EnvVar("FOO")),
+            ("file:/run/secrets/db_pass", Target::File(Path::new("/run/secrets/db_pass"))),
+            ("file:db_pass", Target::File(Path::new("db_pass"))),
+            ("fifo:PGPASSFILE", Target::Fifo("PGPASSFILE")),
+            ("memfd:SECRET_FD", Target::Memfd("SECRET_FD")),
+        ];
+
+        for (name, expected) in cases {
+            assert_eq!(Target::parse(name), expected, "Unexpected target for '{}'", name);
+        }
+
+    }
+
+    #[test]
+    fn test_secret_trees() {
+
+        let cases = vec![
+            ("APP_ = kv/app/", Some(("APP_", "kv/app/"))),
+            ("APP_=/kv/app", Some(("APP_", "kv/app/"))),
+            (" = kv/app/", Some(("", "kv/app/"))),
+            ("APP_ = /", None),
+            ("APP_", None),
+        ];
+
+        for (s, expected) in cases {
+            let expected = expected.map(|(prefix, path)| SecretTree { prefix: prefix.to_owned(), path: path.to_owned() });
+            assert_eq!(SecretTree::from_str(s).ok(), expected, "Unexpected result parsing '{}'", s);
+        }
+
+        let tree = SecretTree::from_str("APP_ = kv/app/").unwrap();
+        assert_eq!(tree.env_var("db", "password"), "APP_DB_PASSWORD");
+        assert_eq!(tree.env_var("nested/deep-one", "api.key"), "APP_NESTED_DEEP_ONE_API_KEY");
+
+    }
+
+    #[test]
+    fn test_secretmapping_assertions() {
+
+        let cases = vec![
+            ("FOO = /hello/foo/bar", vec![], None),
+            ("FOO = /hello/foo/bar | @assert:url", vec![], Some("@assert:url")),
+            ("FOO = /hello/foo/bar | base64 |@assert: url ", vec!["base64"], Some("@assert:url")),
+            // Regexes can contain '|':
+            ("FOO = /hello/foo/bar | rev | @assert:/^(foo|bar)$/", vec!["rev"], Some("@assert:/^(foo|bar)$/")),
+        ];
+
+        for (s, processors, assertion) in cases {
+            let mapping = SecretMapping::from_str(s).unwrap();
+            let actual_processors: Vec<String> = mapping.processors().iter().map(|p| p.to_string()).collect();
+            assert_eq!(actual_processors, processors, "Unexpected processors in '{}'", s);
+            assert_eq!(mapping.assertion().map(|a| a.to_string()).as_deref(), assertion, "Unexpected assertion in '{}'", s);
+        }
+
+    }
+
+}
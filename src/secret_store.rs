@@ -1,15 +1,87 @@
 use std::str::FromStr;
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{ Arc, Mutex };
+use std::convert::TryInto;
+use std::time::{ Duration, Instant, SystemTime };
 use anyhow::{ anyhow, Result, Context };
-use serde_json::Value;
+use serde_json::{ Value, json };
 use serde::{ Deserialize };
-use crate::client::Client;
+use futures::future;
+use tracing::{ debug, info };
+use crate::client::{ self, Client };
+use crate::logging;
+use crate::share;
 
 pub struct SecretStore {
     // Client to make requests with:
     client: Client,
     // list of mount points and storage types for each:
-    mount_points: Vec<(StorageType,String)>
+    mount_points: Vec<(StorageType,String)>,
+    // secrets that are generated on request, so that we only generate them once,
+    // and when those that expire do so (until which they're kept):
+    generated: Mutex<HashMap<String,Arc<GeneratedSecret>>>,
+    expiries: Mutex<HashMap<String,Expiry>>,
+    // secrets that we've read so far, so that each path is only asked for once
+    // (until 'forget_cached_secrets' is called):
+    read: Mutex<HashMap<String,Arc<GeneratedSecret>>>,
+    // the leases of any dynamic secrets that we've been given:
+    leases: Mutex<Vec<Lease>>,
+    // static (KV) secrets that we've been handed from a cache, and those that
+    // we've fetched from Vault which could be cached, keyed by path:
+    cached: Mutex<HashMap<String,Vec<(String,String)>>>,
+    cacheable: Mutex<HashMap<String,Vec<(String,String)>>>,
+    // the public key to sign when asked for a secret from an SSH store:
+    ssh_public_key: Option<String>,
+    // the certificate to issue when asked for a secret from a PKI store:
+    pki_certificate: Option<PkiCertificate>,
+    // whether to hand back values which are objects or arrays as JSON:
+    json_values: bool
+}
+
+/// The details of the certificates that we'll ask PKI stores to issue.
+struct PkiCertificate {
+    common_name: String,
+    ttl: Option<Duration>
+}
+
+/// The lease on some dynamic secret (eg database credentials); once
+/// it expires, Vault revokes the secret unless the lease is renewed.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Lease {
+    pub id: String,
+    pub ttl: Duration,
+    pub renewable: bool
+}
+
+/// A path that our token can't obtain the secret at, because it lacks the
+/// capability needed on the API path that we'd ask Vault for.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct AccessDenied {
+    pub path: String,
+    pub api_path: String,
+    pub capability: &'static str,
+    pub capabilities: Vec<String>
+}
+
+impl std::fmt::Display for AccessDenied {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "'/{}' needs the '{}' capability on '{}'", self.path.trim_start_matches('/'), self.capability, self.api_path)?;
+        match self.capabilities.is_empty() {
+            true => Ok(()),
+            false => write!(f, " (the token has: {})", self.capabilities.join(", "))
+        }
+    }
+}
+
+/// A secret which will be generated the first time it's asked for.
+type GeneratedSecret = tokio::sync::Mutex<Option<Vec<(String,String)>>>;
+
+/// When a generated secret stops working, and the lease (if any) which
+/// keeps it working for longer if it's renewed.
+struct Expiry {
+    at: Instant,
+    lease_id: Option<String>
 }
 
 impl SecretStore {
@@ -22,48 +94,189 @@ impl SecretStore {
         // more permissions:
         let mut sys_auth: Value = client.get("/sys/internal/ui/mounts")
             .await
-            .with_context(|| format!("Failed to get secret store information from Vault"))?;
+            .context("Failed to get secret store information from Vault")?;
 
         #[derive(Deserialize)]
         struct SysMountsData {
-            r#type: String
+            r#type: String,
+            #[serde(default)]
+            options: Option<HashMap<String,String>>
         }
         let secret_mounts: HashMap<String,SysMountsData> = serde_json::from_value(sys_auth["data"]["secret"].take())
-            .with_context(|| format!("Failed to get secret store information from Vault (unexpected response)"))?;
+            .context("Failed to get secret store information from Vault (unexpected response)")?;
 
         let mount_points = secret_mounts
             .into_iter()
             .filter_map(|(mount,props)| {
-                let ty = StorageType::from_str(&props.r#type).ok()?;
+                let version = props.options.as_ref().and_then(|o| o.get("version")).map(|v| &**v);
+                let ty = StorageType::from_mount(&props.r#type, version).ok()?;
                 let mount = mount.trim_matches('/').to_owned();
                 Some((ty, mount))
             })
-            .collect();
+            .collect::<Vec<_>>();
+        for (ty, mount) in &mount_points {
+            info!("The {} store mounted at '/{}' is available", ty.name(), mount);
+        }
+
+        Ok(SecretStore { mount_points, ..SecretStore::without_mounts(client) })
+    }
+
+    /// Create a new SecretStore instance without asking Vault which secret stores
+    /// are mounted (which some policies don't allow). It only knows about the
+    /// stores named by engine prefixes on paths (eg 'kv2:secret/foo/bar').
+    pub fn without_mounts(client: Client) -> SecretStore {
+        SecretStore { client, mount_points: Vec::new(), generated: Mutex::new(HashMap::new()), expiries: Mutex::new(HashMap::new()), read: Mutex::new(HashMap::new()), leases: Mutex::new(Vec::new()), cached: Mutex::new(HashMap::new()), cacheable: Mutex::new(HashMap::new()), ssh_public_key: None, pki_certificate: None, json_values: false }
+    }
 
-        Ok(SecretStore { client, mount_points })
+    /// Sign this public key when asked for a secret from an SSH store.
+    pub fn with_ssh_public_key(mut self, public_key: String) -> SecretStore {
+        self.ssh_public_key = Some(public_key);
+        self
     }
 
-    /// Given some path, obtain the secrets pointed to
+    /// Issue certificates for this common name (lasting for the TTL given, if
+    /// any) when asked for a secret from a PKI store.
+    pub fn with_pki_certificate(mut self, common_name: String, ttl: Option<Duration>) -> SecretStore {
+        self.pki_certificate = Some(PkiCertificate { common_name, ttl });
+        self
+    }
+
+    /// Hand back secret values which are objects or arrays as compact JSON,
+    /// rather than failing to fetch the secrets that contain them.
+    pub fn with_json_values(mut self, json_values: bool) -> SecretStore {
+        self.json_values = json_values;
+        self
+    }
+
+    /// Use these secrets (keyed by path) rather than asking Vault for them,
+    /// until 'forget_cached_secrets' is called.
+    pub fn with_cached_secrets(mut self, secrets: HashMap<String,Vec<(String,String)>>) -> SecretStore {
+        self.cached = Mutex::new(secrets);
+        self
+    }
+
+    /// Ask Vault for every secret from now on, rather than using those we were
+    /// handed from a cache or have already read. Secrets that are asked for
+    /// again (eg to check them for changes) need to be fresh. Generated secrets
+    /// (eg database credentials) are kept until they expire, so that we don't
+    /// obtain new ones every time.
+    pub fn forget_cached_secrets(&self) {
+        self.cached.lock().unwrap().clear();
+        self.read.lock().unwrap().clear();
+        let now = Instant::now();
+        let mut generated = self.generated.lock().unwrap();
+        self.expiries.lock().unwrap().retain(|api_path, expiry| {
+            let has_expired = expiry.at <= now;
+            if has_expired {
+                generated.remove(api_path);
+            }
+            !has_expired
+        });
+    }
+
+    /// The secrets (keyed by path) that we've fetched from Vault which are
+    /// safe to cache. Only secrets from KV stores are; anything else may be
+    /// generated afresh (and leased) each time it's asked for.
+    pub fn cacheable_secrets(&self) -> HashMap<String,Vec<(String,String)>> {
+        self.cacheable.lock().unwrap().clone()
+    }
+
+    /// The client that this store uses to make requests.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Given some path, obtain the secrets pointed to. The values are never logged.
     pub async fn get(&self, original_path: &str) -> Result<Vec<(String,String)>> {
+        let secret = self.read_once(original_path).await?;
+        for (_, value) in &secret {
+            logging::redact(value);
+        }
+        Ok(secret)
+    }
+
+    /// Mappings often point to the same path (to pick out different keys from it).
+    /// Each path is only asked for once, and every read of it (including those
+    /// made while it's being asked for) shares the result, until the secrets are
+    /// forgotten with 'forget_cached_secrets'. Failed reads are tried again.
+    async fn read_once(&self, original_path: &str) -> Result<Vec<(String,String)>> {
+        let entry = self.read
+            .lock()
+            .unwrap()
+            .entry(original_path.trim_start_matches('/').to_owned())
+            .or_default()
+            .clone();
+
+        let mut secret = entry.lock().await;
+        if let Some(secret) = &*secret {
+            return Ok(secret.clone());
+        }
+        let fetched = self.fetch(original_path).await?;
+        *secret = Some(fetched.clone());
+        Ok(fetched)
+    }
+
+    async fn fetch(&self, original_path: &str) -> Result<Vec<(String,String)>> {
         let storage_type_and_path = original_path.trim_start_matches('/');
-        let (storage_type, mount_point, path) = self.split_path(storage_type_and_path)
-            .ok_or_else(|| anyhow!(
-                "The path '/{}' is not supported (no known secret storage is mounted here)"
-                , original_path))?;
+        if let Some(secret) = self.cached.lock().unwrap().get(storage_type_and_path) {
+            debug!("Using the cached secret at '/{}'", storage_type_and_path);
+            return Ok(secret.clone());
+        }
+        // Wrapping tokens ('unwrap:<token>') can only be unwrapped once:
+        if let Some(token) = storage_type_and_path.strip_prefix("unwrap:") {
+            return self.generate_once(storage_type_and_path, share::unwrap(&self.client, token)).await;
+        }
+        // The metadata of KV2 secrets is available at 'meta:<path>':
+        if let Some(path) = storage_type_and_path.strip_prefix("meta:") {
+            return self.metadata(path).await;
+        }
+        let (path_without_version, version) = split_version(storage_type_and_path)?;
+        let (storage_type, mount_point, path) = self.split_path(path_without_version)
+            .ok_or_else(|| unsupported_path(original_path))?;
+        if version.is_some() && storage_type != StorageType::KV {
+            return Err(anyhow!(
+                "The path '/{}' asks for a version, but only secrets in KV2 stores have versions"
+                , original_path));
+        }
+        info!("Reading '/{}' from the {} store mounted at '/{}'", storage_type_and_path, storage_type.name(), mount_point);
 
         match storage_type {
             StorageType::KV => {
                 let api_path = format!("{mount}/data/{path}"
                     , mount = mount_point
                     , path = path );
+                let versioned_api_path = match version {
+                    Some(version) => format!("{}?version={}", api_path, version),
+                    None => api_path.clone()
+                };
 
-                let res: Value = self.client.get(&api_path)
+                let res = self.client.get(&versioned_api_path).await;
+                let res: Value = self.explain_permission_denied(res, &api_path, "read")
                     .await
                     .with_context(|| format!(
-                        "Could not find any secrets at path '/{}' from KV2 store mounted at '/{}'"
+                        "Could not find any secrets at path '/{}'{} from KV2 store mounted at '/{}'"
+                        , &path, version.map(|v| format!(" (version {})", v)).unwrap_or_default(), &mount_point))?;
+
+                let secret = to_keyvalues(&res["data"]["data"], self.json_values)
+                    .with_context(|| format!("Could not read the secret at path '/{}'", storage_type_and_path))?;
+                self.cacheable.lock().unwrap().insert(storage_type_and_path.to_owned(), secret.clone());
+                Ok(secret)
+            },
+            StorageType::KV1 => {
+                let api_path = format!("{mount}/{path}"
+                    , mount = mount_point
+                    , path = path );
+
+                let res = self.client.get(&api_path).await;
+                let res: Value = self.explain_permission_denied(res, &api_path, "read")
+                    .await
+                    .with_context(|| format!(
+                        "Could not find any secrets at path '/{}' from KV1 store mounted at '/{}'"
                         , &path, &mount_point))?;
 
-                let secret = to_keyvalues(&res["data"]["data"])?;
+                let secret = to_keyvalues(&res["data"], self.json_values)
+                    .with_context(|| format!("Could not read the secret at path '/{}'", storage_type_and_path))?;
+                self.cacheable.lock().unwrap().insert(storage_type_and_path.to_owned(), secret.clone());
                 Ok(secret)
             },
             StorageType::Cubbyhole => {
@@ -71,22 +284,598 @@ impl SecretStore {
                     , mount = mount_point
                     , path = path );
 
-                let res: Value = self.client.get(&api_path)
+                let res = self.client.get(&api_path).await;
+                let res: Value = self.explain_permission_denied(res, &api_path, "read")
                     .await
                     .with_context(|| format!(
                         "Could not find any secrets at path '/{}' from Cubbyhole store mounted at '/{}'"
                         , &path, &mount_point))?;
 
-                let secret = to_keyvalues(&res["data"])?;
+                let secret = to_keyvalues(&res["data"], self.json_values)
+                    .with_context(|| format!("Could not read the secret at path '/{}'", storage_type_and_path))?;
                 Ok(secret)
             },
+            StorageType::Transit => {
+                if !path.starts_with("datakey/") {
+                    return Err(anyhow!(
+                        "Only data keys (eg '/{}/datakey/plaintext/<key>') can be obtained from the Transit store mounted at '/{}'"
+                        , &mount_point, &mount_point));
+                }
+                let api_path = format!("{mount}/{path}"
+                    , mount = mount_point
+                    , path = path );
+
+                self.generate_once(&api_path, async {
+                    let res = self.client.post(&api_path, &json!({})).await;
+                    let res: Value = self.explain_permission_denied(res, &api_path, "update")
+                        .await
+                        .with_context(|| format!(
+                            "Could not generate a data key at path '/{}' from Transit store mounted at '/{}'"
+                            , &path, &mount_point))?;
+
+                    let secret = ["plaintext", "ciphertext"]
+                        .iter()
+                        .filter_map(|&key| {
+                            let val = res["data"][key].as_str()?;
+                            Some((key.to_owned(), val.to_owned()))
+                        })
+                        .collect();
+                    Ok(secret)
+                }).await
+            },
+            StorageType::Database => {
+                self.get_dynamic_creds("Database", mount_point, path, &["username", "password"]).await
+            },
+            StorageType::Consul => {
+                self.get_dynamic_creds("Consul", mount_point, path, &["token", "accessor"]).await
+            },
+            StorageType::Nomad => {
+                self.get_dynamic_creds("Nomad", mount_point, path, &["secret_id", "accessor_id"]).await
+            },
+            StorageType::Totp => {
+                if !path.starts_with("code/") {
+                    return Err(anyhow!(
+                        "Only codes (eg '/{}/code/<key>') can be obtained from the TOTP store mounted at '/{}'"
+                        , &mount_point, &mount_point));
+                }
+                let api_path = format!("{mount}/{path}"
+                    , mount = mount_point
+                    , path = path );
+
+                self.generate_once(&api_path, async {
+                    let res = self.client.get(&api_path).await;
+                    let res: Value = self.explain_permission_denied(res, &api_path, "read")
+                        .await
+                        .with_context(|| format!(
+                            "Could not generate a code at path '/{}' from TOTP store mounted at '/{}'"
+                            , &path, &mount_point))?;
+
+                    let code = res["data"]["code"]
+                        .as_str()
+                        .ok_or_else(|| anyhow!("Could not find the code in the response from TOTP store mounted at '/{}'", &mount_point))?;
+                    // Codes are only valid briefly, so we want a new one each time we fetch the secrets:
+                    self.expires_after(&api_path, Duration::from_secs(0), None);
+                    Ok(vec![("code".to_owned(), code.to_owned())])
+                }).await
+            },
+            StorageType::Ssh => {
+                if !path.starts_with("sign/") {
+                    return Err(anyhow!(
+                        "Only signed keys (eg '/{}/sign/<role>') can be obtained from the SSH store mounted at '/{}'"
+                        , &mount_point, &mount_point));
+                }
+                let public_key = self.ssh_public_key.as_ref().ok_or_else(|| anyhow!(
+                    "A public key is needed to obtain a signed key from the SSH store mounted at '/{}' (see '--ssh-public-key')"
+                    , &mount_point))?;
+                let api_path = format!("{mount}/{path}"
+                    , mount = mount_point
+                    , path = path );
+
+                self.generate_once(&api_path, async {
+                    let res = self.client.post(&api_path, &json!({ "public_key": public_key })).await;
+                    let res: Value = self.explain_permission_denied(res, &api_path, "update")
+                        .await
+                        .with_context(|| format!(
+                            "Could not sign the public key at path '/{}' from SSH store mounted at '/{}'"
+                            , &path, &mount_point))?;
+
+                    if let Some(ttl) = res["data"]["signed_key"].as_str().and_then(ssh_certificate_ttl) {
+                        self.expires_after(&api_path, ttl, None);
+                    }
+                    let secret = ["signed_key", "serial_number"]
+                        .iter()
+                        .filter_map(|&key| {
+                            let val = res["data"][key].as_str()?;
+                            Some((key.to_owned(), val.to_owned()))
+                        })
+                        .collect();
+                    Ok(secret)
+                }).await
+            },
+            StorageType::Pki => {
+                if !path.starts_with("issue/") {
+                    return Err(anyhow!(
+                        "Only issued certificates (eg '/{}/issue/<role>') can be obtained from the PKI store mounted at '/{}'"
+                        , &mount_point, &mount_point));
+                }
+                let certificate = self.pki_certificate.as_ref().ok_or_else(|| anyhow!(
+                    "A common name is needed to issue a certificate from the PKI store mounted at '/{}' (see '--pki-common-name')"
+                    , &mount_point))?;
+                let api_path = format!("{mount}/{path}"
+                    , mount = mount_point
+                    , path = path );
+
+                let mut body = json!({ "common_name": certificate.common_name });
+                if let Some(ttl) = certificate.ttl {
+                    body["ttl"] = json!(format!("{}s", ttl.as_secs()));
+                }
+
+                // The certificate and private key need to be from the same request:
+                self.generate_once(&api_path, async {
+                    let res = self.client.post(&api_path, &body).await;
+                    let res: Value = self.explain_permission_denied(res, &api_path, "update")
+                        .await
+                        .with_context(|| format!(
+                            "Could not issue a certificate at path '/{}' from PKI store mounted at '/{}'"
+                            , &path, &mount_point))?;
+
+                    if let Some(ttl) = res["data"]["expiration"].as_u64().and_then(ttl_until) {
+                        self.expires_after(&api_path, ttl, None);
+                    }
+                    let mut secret: Vec<(String,String)> = ["certificate", "private_key", "private_key_type", "issuing_ca", "serial_number"]
+                        .iter()
+                        .filter_map(|&key| {
+                            let val = res["data"][key].as_str()?;
+                            Some((key.to_owned(), val.to_owned()))
+                        })
+                        .collect();
+                    if let Some(ca_chain) = to_strings(&res["data"]["ca_chain"]) {
+                        secret.push(("ca_chain".to_owned(), ca_chain.join("\n")));
+                    }
+                    Ok(secret)
+                }).await
+            },
+        }
+    }
+
+    /// Obtain credentials from stores like the Database store, where reading
+    /// '<mount>/creds/<role>' creates a new set of credentials (with a lease).
+    async fn get_dynamic_creds(&self, store_name: &str, mount_point: &str, path: &str, keys: &[&str]) -> Result<Vec<(String,String)>> {
+        if !path.starts_with("creds/") && !path.starts_with("static-creds/") {
+            return Err(anyhow!(
+                "Only credentials (eg '/{}/creds/<role>') can be obtained from the {} store mounted at '/{}'"
+                , mount_point, store_name, mount_point));
+        }
+        let api_path = format!("{mount}/{path}"
+            , mount = mount_point
+            , path = path );
+
+        // Each request creates new credentials (and a lease), so we want just the one:
+        self.generate_once(&api_path, async {
+            let res = self.client.get(&api_path).await;
+            let res: Value = self.explain_permission_denied(res, &api_path, "read")
+                .await
+                .with_context(|| format!(
+                    "Could not obtain credentials at path '/{}' from {} store mounted at '/{}'"
+                    , path, store_name, mount_point))?;
+
+            // Dynamic credentials last as long as their lease, and static ones until they're rotated:
+            match to_lease(&res) {
+                Some(lease) => {
+                    if lease.ttl > Duration::from_secs(0) {
+                        self.expires_after(&api_path, lease.ttl, Some(lease.id.clone()));
+                    }
+                    self.leases.lock().unwrap().push(lease);
+                },
+                None => if let Some(ttl) = res["data"]["ttl"].as_u64().filter(|&ttl| ttl > 0) {
+                    self.expires_after(&api_path, Duration::from_secs(ttl), None);
+                }
+            }
+            let secret = keys
+                .iter()
+                .filter_map(|&key| {
+                    let val = res["data"][key].as_str()?;
+                    Some((key.to_owned(), val.to_owned()))
+                })
+                .collect();
+            Ok(secret)
+        }).await
+    }
+
+    /// The leases on the dynamic secrets that we've obtained so far.
+    pub fn leases(&self) -> Vec<Lease> {
+        self.leases.lock().unwrap().clone()
+    }
+
+    /// Ask for a lease to be extended by its original TTL. Vault may give us less
+    /// than this (for instance if the lease would outlive its maximum TTL).
+    pub async fn renew_lease(&self, lease: &Lease) -> Result<Lease> {
+        let body = json!({ "lease_id": lease.id, "increment": lease.ttl.as_secs() });
+        let res: Value = self.client.post("sys/leases/renew", &body)
+            .await
+            .with_context(|| format!("Could not renew the lease '{}'", lease.id))?;
+        let renewed = to_lease(&res).ok_or_else(|| anyhow!("Could not find the renewed lease '{}' in Vault's response", lease.id))?;
+        // The secrets that came with the lease now last as long as it does:
+        let expires_at = Instant::now() + renewed.ttl;
+        for expiry in self.expiries.lock().unwrap().values_mut().filter(|e| e.lease_id.as_deref() == Some(&*renewed.id)) {
+            expiry.at = expires_at;
         }
+        Ok(renewed)
+    }
+
+    /// Revoke the leases on every dynamic secret that we've obtained, so that
+    /// the secrets stop working. Every lease is tried, even if some fail.
+    pub async fn revoke_leases(&self) -> Result<()> {
+        let leases = std::mem::take(&mut *self.leases.lock().unwrap());
+        let results = future::join_all(leases.iter().map(|lease| async move {
+            self.client.post::<Value,_,_>("sys/leases/revoke", &json!({ "lease_id": lease.id }))
+                .await
+                .with_context(|| format!("Could not revoke the lease '{}'", lease.id))
+        })).await;
+        results.into_iter().collect::<Result<Vec<_>>>()?;
+        Ok(())
+    }
+
+    /// List the entries under some path. Entries ending in '/' contain more entries.
+    pub async fn list(&self, original_path: &str) -> Result<Vec<String>> {
+        let (storage_type, mount_point, path) = self.split_path(original_path)
+            .ok_or_else(|| unsupported_path(original_path))?;
+
+        let api_path = match storage_type {
+            StorageType::KV => format!("{mount}/metadata/{path}", mount = mount_point, path = path),
+            StorageType::KV1 |
+            StorageType::Cubbyhole => format!("{mount}/{path}", mount = mount_point, path = path),
+            StorageType::Transit => return Err(anyhow!("Secrets in the Transit store mounted at '/{}' can't be listed", mount_point)),
+            StorageType::Totp => return Err(anyhow!("Secrets in the TOTP store mounted at '/{}' can't be listed", mount_point)),
+            StorageType::Ssh => return Err(anyhow!("Secrets in the SSH store mounted at '/{}' can't be listed", mount_point)),
+            StorageType::Pki => return Err(anyhow!("Secrets in the PKI store mounted at '/{}' can't be listed", mount_point)),
+            StorageType::Database |
+            StorageType::Consul |
+            StorageType::Nomad => return Err(anyhow!("Credentials from the store mounted at '/{}' can't be listed", mount_point))
+        };
+        let res: Value = self.client.list(&api_path)
+            .await
+            .with_context(|| format!("Could not list the secrets at '{}'", original_path))?;
+
+        to_strings(&res["data"]["keys"])
+            .ok_or_else(|| anyhow!("Could not find the list of secrets at '{}' in Vault's response", original_path))
+    }
+
+    /// List every secret under a path, looking inside each of the paths under it
+    /// in turn. The secrets are returned relative to the path given, in order.
+    pub async fn list_recursive(&self, original_path: &str) -> Result<Vec<String>> {
+        let root = format!("{}/", original_path.trim_end_matches('/'));
+        let mut dirs = vec![String::new()];
+        let mut secrets = Vec::new();
+        while let Some(dir) = dirs.pop() {
+            for entry in self.list(&format!("{}{}", root, dir)).await? {
+                if entry.ends_with('/') {
+                    dirs.push(format!("{}{}", dir, entry));
+                } else {
+                    secrets.push(format!("{}{}", dir, entry));
+                }
+            }
+        }
+        secrets.sort();
+        Ok(secrets)
+    }
+
+    /// Describe where the secrets at some path would come from, without
+    /// fetching them, failing if no known secret storage is mounted there.
+    pub fn describe(&self, original_path: &str) -> Result<String> {
+        let storage_type_and_path = original_path.trim_start_matches('/');
+        if storage_type_and_path.starts_with("unwrap:") {
+            return Ok("a response-wrapping token".to_owned());
+        }
+        if let Some(path) = storage_type_and_path.strip_prefix("meta:") {
+            let (mount_point, _) = self.split_kv_path(path)?;
+            return Ok(format!("the metadata in the KV2 store mounted at '/{}'", mount_point));
+        }
+        let (path_without_version, version) = split_version(storage_type_and_path)?;
+        let (storage_type, mount_point, _) = self.split_path(path_without_version)
+            .ok_or_else(|| unsupported_path(storage_type_and_path))?;
+        if version.is_some() && storage_type != StorageType::KV {
+            return Err(anyhow!(
+                "The path '/{}' asks for a version, but only secrets in KV2 stores have versions"
+                , storage_type_and_path));
+        }
+        Ok(format!("the {} store mounted at '/{}'", storage_type.name(), mount_point))
+    }
+
+    /// Create a new SecretStore instance that knows about the secret stores
+    /// given (as engine prefixes and mount points, eg from 'mount_table'),
+    /// rather than asking Vault which are mounted.
+    pub fn with_mount_table(client: Client, mount_table: &[(String,String)]) -> SecretStore {
+        let mount_points = mount_table.iter()
+            .filter_map(|(prefix, mount)| Some((StorageType::from_prefix(prefix)?, mount.clone())))
+            .collect();
+        SecretStore { mount_points, ..SecretStore::without_mounts(client) }
+    }
+
+    /// The secret stores that we know about, as engine prefixes (eg 'kv2') and
+    /// mount points, so that they can be handed back to 'with_mount_table'.
+    pub fn mount_table(&self) -> Vec<(String,String)> {
+        self.mount_points.iter().map(|(ty, mount)| (ty.prefix().to_owned(), mount.clone())).collect()
+    }
+
+    /// The paths that secret stores we know about are mounted at.
+    pub fn mount_points(&self) -> Vec<&str> {
+        self.mount_points.iter().map(|(_, mount)| &**mount).collect()
+    }
+
+    /// The API path that obtaining the secret at some path asks Vault for, and the
+    /// capability that our token needs on it. This is None if we won't ask Vault
+    /// (eg the secret is cached), or if the path isn't one that we can obtain at all.
+    fn access_needed(&self, original_path: &str) -> Option<(String,&'static str)> {
+        let storage_type_and_path = original_path.trim_start_matches('/');
+        if self.cached.lock().unwrap().contains_key(storage_type_and_path) || storage_type_and_path.starts_with("unwrap:") {
+            return None;
+        }
+        if let Some(path) = storage_type_and_path.strip_prefix("meta:") {
+            let (mount_point, path) = self.split_kv_path(path).ok()?;
+            return Some((format!("{}/metadata/{}", mount_point, path), "read"));
+        }
+        let (path_without_version, _) = split_version(storage_type_and_path).ok()?;
+        let (storage_type, mount_point, path) = self.split_path(path_without_version)?;
+        match storage_type {
+            StorageType::KV => Some((format!("{}/data/{}", mount_point, path), "read")),
+            StorageType::Transit |
+            StorageType::Ssh |
+            StorageType::Pki => Some((format!("{}/{}", mount_point, path), "update")),
+            _ => Some((format!("{}/{}", mount_point, path), "read"))
+        }
+    }
+
+    /// Ask Vault (in one go) what our token can do at each of the paths given,
+    /// returning those paths that it lacks the capability needed to obtain.
+    pub async fn check_access(&self, original_paths: &[&str]) -> Result<Vec<AccessDenied>> {
+        let needed: Vec<(&str,String,&'static str)> = original_paths.iter()
+            .filter_map(|&path| {
+                let (api_path, capability) = self.access_needed(path)?;
+                Some((path, api_path, capability))
+            })
+            .collect();
+        if needed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut api_paths: Vec<&str> = needed.iter().map(|(_, api_path, _)| &**api_path).collect();
+        api_paths.sort_unstable();
+        api_paths.dedup();
+        let res: Value = self.client.post("sys/capabilities-self", &json!({ "paths": api_paths }))
+            .await
+            .context("Could not check what the token is allowed to do")?;
+
+        Ok(needed.into_iter()
+            .filter_map(|(path, api_path, capability)| {
+                let capabilities = capabilities_at(&res, &api_path);
+                if has_capability(&capabilities, capability) {
+                    return None;
+                }
+                Some(AccessDenied { path: path.to_owned(), api_path, capability, capabilities })
+            })
+            .collect())
+    }
+
+    /// Vault's 'permission denied' errors don't say why, so if that's what we got
+    /// back, find out which policies our token has and what it's allowed to do at
+    /// the path, and add that to the error. This is best effort; if we can't find
+    /// these things out, the error is returned as-is.
+    async fn explain_permission_denied<T>(&self, res: Result<T>, api_path: &str, capability: &str) -> Result<T> {
+        let err = match res {
+            Err(e) if client::is_permission_denied(&e) => e,
+            res => return res
+        };
+
+        let (token, capabilities) = future::join(
+            self.client.get::<Value,_>("auth/token/lookup-self"),
+            self.client.post::<Value,_,_>("sys/capabilities-self", &json!({ "paths": [api_path] }))
+        ).await;
+
+        let policies = match token.ok().and_then(|t| to_strings(&t["data"]["policies"])) {
+            Some(policies) => policies,
+            None => return Err(err)
+        };
+        let mut msg = format!("The token (policies: {}) lacks the '{}' capability on '{}'"
+            , policies.join(", "), capability, api_path);
+        if let Some(capabilities) = capabilities.ok().and_then(|c| to_strings(&c["capabilities"])) {
+            msg.push_str(&format!(" (it has: {})", capabilities.join(", ")));
+        }
+        Err(err.context(msg))
+    }
+
+    /// Write the data from an earlier version of a KV2 secret back as the newest
+    /// version, returning the version number that it was written as.
+    pub async fn rollback(&self, original_path: &str, version: u64) -> Result<u64> {
+        let (mount_point, path) = self.split_kv_path(original_path)?;
+        let current_version = self.current_version(mount_point, path).await?;
+        if version >= current_version {
+            return Err(anyhow!(
+                "Can't roll back the secret at '{}' to version {} (the current version is {})"
+                , original_path, version, current_version));
+        }
+
+        let api_path = format!("{mount}/data/{path}", mount = mount_point, path = path);
+        let res: Value = self.client.get(format!("{}?version={}", api_path, version))
+            .await
+            .with_context(|| format!(
+                "Could not find version {} of the secret at '{}'", version, original_path))?;
+        let data = &res["data"]["data"];
+        if !data.is_object() {
+            return Err(anyhow!(
+                "Version {} of the secret at '{}' has been deleted or destroyed (try undeleting it first)"
+                , version, original_path));
+        }
+
+        // Only write if nothing else has written a new version in the meantime:
+        let body = json!({
+            "options": { "cas": current_version },
+            "data": data
+        });
+        let res: Value = self.client.post(&api_path, &body)
+            .await
+            .with_context(|| format!(
+                "Could not write version {} of the secret at '{}' as the newest version", version, original_path))?;
+        let new_version = res["data"]["version"].as_u64().unwrap_or(current_version + 1);
+        Ok(new_version)
+    }
+
+    /// Write key/values to the secret at a path in a KV (v1 or v2) or Cubbyhole store,
+    /// replacing whatever was there before. Returns the new version of KV2 secrets.
+    pub async fn put(&self, original_path: &str, data: &[(String,String)]) -> Result<Option<u64>> {
+        let storage_type_and_path = original_path.trim_start_matches('/');
+        let (storage_type, mount_point, path) = self.split_path(storage_type_and_path)
+            .ok_or_else(|| unsupported_path(storage_type_and_path))?;
+        let data: serde_json::Map<String,Value> = data.iter()
+            .map(|(k,v)| (k.clone(), Value::from(&**v)))
+            .collect();
+
+        let (api_path, body) = match storage_type {
+            StorageType::KV => (format!("{}/data/{}", mount_point, path), json!({ "data": data })),
+            StorageType::KV1 |
+            StorageType::Cubbyhole => (format!("{}/{}", mount_point, path), Value::Object(data)),
+            _ => return Err(anyhow!("Secrets can only be written to KV and Cubbyhole stores, not the store mounted at '/{}'", mount_point))
+        };
+
+        let res = self.client.post(&api_path, &body).await;
+        let res: Value = self.explain_permission_denied(res, &api_path, "update")
+            .await
+            .with_context(|| format!("Could not write the secret at '/{}'", storage_type_and_path))?;
+        Ok(res["data"]["version"].as_u64())
+    }
+
+    /// Restore deleted versions of a KV2 secret. If no versions are given, the
+    /// current version is restored. Returns the versions that were restored.
+    pub async fn undelete(&self, original_path: &str, versions: &[u64]) -> Result<Vec<u64>> {
+        let (mount_point, path) = self.split_kv_path(original_path)?;
+        let versions = if versions.is_empty() {
+            vec![self.current_version(mount_point, path).await?]
+        } else {
+            versions.to_vec()
+        };
+
+        let api_path = format!("{mount}/undelete/{path}", mount = mount_point, path = path);
+        let _: Value = self.client.post(&api_path, &json!({ "versions": versions }))
+            .await
+            .with_context(|| format!("Could not undelete the secret at '{}'", original_path))?;
+        Ok(versions)
+    }
+
+    /// The metadata of a KV2 secret, flattened into keys like 'created_time',
+    /// 'current_version', 'custom_metadata.<key>' and 'versions.<version>.created_time'.
+    pub async fn metadata(&self, original_path: &str) -> Result<Vec<(String,String)>> {
+        let (mount_point, path) = self.split_kv_path(original_path.trim_start_matches('/'))?;
+        let api_path = format!("{mount}/metadata/{path}", mount = mount_point, path = path);
+        let res = self.client.get(&api_path).await;
+        let res: Value = self.explain_permission_denied(res, &api_path, "read")
+            .await
+            .with_context(|| format!(
+                "Could not find the metadata for the secret at path '/{}' from KV2 store mounted at '/{}'"
+                , path, mount_point))?;
+        Ok(flatten_metadata(&res["data"]))
+    }
+
+    /// The names of the keys in the secret at some path, without asking for their
+    /// values. KV2 stores hand these back from their 'subkeys' endpoint. Other stores
+    /// have no way to list keys without reading (or generating) the secret, so for
+    /// these (and for 'unwrap:' and 'meta:' paths) None is handed back.
+    pub async fn keys(&self, original_path: &str) -> Result<Option<Vec<String>>> {
+        let storage_type_and_path = original_path.trim_start_matches('/');
+        if let Some(secret) = self.cached.lock().unwrap().get(storage_type_and_path) {
+            return Ok(Some(secret.iter().map(|(key, _)| key.clone()).collect()));
+        }
+        if storage_type_and_path.starts_with("unwrap:") || storage_type_and_path.starts_with("meta:") {
+            return Ok(None);
+        }
+        let (path_without_version, version) = split_version(storage_type_and_path)?;
+        let (storage_type, mount_point, path) = self.split_path(path_without_version)
+            .ok_or_else(|| unsupported_path(original_path))?;
+
+        match storage_type {
+            StorageType::KV => {
+                let api_path = format!("{mount}/subkeys/{path}", mount = mount_point, path = path);
+                let versioned_api_path = match version {
+                    Some(version) => format!("{}?depth=1&version={}", api_path, version),
+                    None => format!("{}?depth=1", api_path)
+                };
+                let res = self.client.get(&versioned_api_path).await;
+                let res: Value = self.explain_permission_denied(res, &api_path, "read")
+                    .await
+                    .with_context(|| format!(
+                        "Could not find the keys of the secret at path '/{}' from KV2 store mounted at '/{}'"
+                        , path, mount_point))?;
+                Ok(Some(to_subkeys(&res)))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    /// Find the current version of a KV2 secret from its metadata.
+    async fn current_version(&self, mount_point: &str, path: &str) -> Result<u64> {
+        let api_path = format!("{mount}/metadata/{path}", mount = mount_point, path = path);
+        let res: Value = self.client.get(&api_path)
+            .await
+            .with_context(|| format!(
+                "Could not find the metadata for the secret at path '/{}' from KV2 store mounted at '/{}'"
+                , path, mount_point))?;
+        res["data"]["current_version"]
+            .as_u64()
+            .ok_or_else(|| anyhow!(
+                "Could not find the current version of the secret at path '/{}' from KV2 store mounted at '/{}'"
+                , path, mount_point))
+    }
+
+    /// Resolve a path which must point to a KV2 store into its mount point
+    /// and the remaining path to the secret.
+    fn split_kv_path<'a>(&'a self, original_path: &'a str) -> Result<(&'a str,&'a str)> {
+        match self.split_path(original_path) {
+            Some((StorageType::KV, mount_point, path)) => Ok((mount_point, path)),
+            Some(_) => Err(anyhow!("The path '{}' does not point to a KV2 store", original_path)),
+            None => Err(unsupported_path(original_path))
+        }
+    }
+
+    /// Some secrets are freshly generated each time that we ask for them. This
+    /// ensures that we only ask once for each path, so that every mapping that
+    /// points to the same path is given the same values. Those that expire (see
+    /// 'expires_after') are generated again once they have.
+    async fn generate_once<F>(&self, api_path: &str, generate: F) -> Result<Vec<(String,String)>>
+    where F: Future<Output = Result<Vec<(String,String)>>> {
+        let entry = self.generated
+            .lock()
+            .unwrap()
+            .entry(api_path.to_owned())
+            .or_default()
+            .clone();
+
+        let mut secret = entry.lock().await;
+        if let Some(secret) = &*secret {
+            return Ok(secret.clone());
+        }
+        let generated = generate.await?;
+        *secret = Some(generated.clone());
+        Ok(generated)
+    }
+
+    /// Note that the secret generated at the API path given stops working after
+    /// the TTL given (or once the lease given expires, if it's renewed), so that
+    /// it's generated again if it's asked for after 'forget_cached_secrets'.
+    fn expires_after(&self, api_path: &str, ttl: Duration, lease_id: Option<String>) {
+        let expiry = Expiry { at: Instant::now() + ttl, lease_id };
+        self.expiries.lock().unwrap().insert(api_path.to_owned(), expiry);
     }
 
     /// Resolve a path into the storage type used for it and the remaining
-    /// path to the secret. The remaining path has no leading '/'.
-    fn split_path<'s,'a>(&'s self, path: &'a str) -> Option<(StorageType,&'s str,&'a str)> {
+    /// path to the secret. The remaining path has no leading '/'. A path with
+    /// an engine prefix (eg 'kv2:secret/foo/bar') names its storage type, and
+    /// the store is taken to be mounted at its first part.
+    fn split_path<'a>(&'a self, path: &'a str) -> Option<(StorageType,&'a str,&'a str)> {
         let path = path.trim_start_matches('/');
+        if let Some((ty, path)) = split_engine_prefix(path) {
+            let path = path.trim_start_matches('/');
+            let (mount_path, path) = path.split_once('/').unwrap_or((path, ""));
+            return Some((ty, mount_path, path.trim_start_matches('/')));
+        }
         for (ty,mount_path) in &self.mount_points {
             if path.starts_with(mount_path) {
                 let path = path[mount_path.len()..].trim_start_matches('/');
@@ -97,14 +886,186 @@ impl SecretStore {
     }
 }
 
-fn to_keyvalues(value: &Value) -> Result<Vec<(String,String)>> {
+/// The error given for a path that isn't in any secret store that we know is
+/// mounted, which can be picked out with 'is_unsupported_path'.
+#[derive(Debug)]
+struct UnsupportedPath(String);
+
+impl std::fmt::Display for UnsupportedPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "The path '/{}' is not supported (no known secret storage is mounted here)", self.0.trim_start_matches('/'))
+    }
+}
+
+impl std::error::Error for UnsupportedPath {}
+
+fn unsupported_path(path: &str) -> anyhow::Error {
+    anyhow::Error::new(UnsupportedPath(path.to_owned()))
+}
+
+/// Was this error caused by a path not being in any secret store that we
+/// know is mounted (which may mean that we need to find out about mounts again)?
+pub fn is_unsupported_path(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| e.is::<UnsupportedPath>())
+}
+
+/// Split an engine prefix, which names the type of store that a path is in
+/// (eg 'kv2:secret/foo/bar'), off a path.
+pub fn split_engine_prefix(path: &str) -> Option<(StorageType, &str)> {
+    let (prefix, rest) = path.trim_start_matches('/').split_once(':')?;
+    Some((StorageType::from_prefix(prefix)?, rest))
+}
+
+/// Split a '?version=N' suffix, which pins the version of a KV2 secret, off a path.
+pub fn split_version(path: &str) -> Result<(&str, Option<u64>)> {
+    let idx = match path.rfind("?version=") {
+        Some(idx) => idx,
+        None => return Ok((path, None))
+    };
+    let version_str = &path[idx + "?version=".len()..];
+    match version_str.parse() {
+        Ok(version) if version > 0 => Ok((&path[0..idx], Some(version))),
+        _ => Err(anyhow!("Expected a version number (starting at 1) after '?version=' in '{}' but got '{}'", path, version_str))
+    }
+}
+
+/// Flatten KV2 metadata into keys and values. Versions are listed in order.
+fn flatten_metadata(data: &Value) -> Vec<(String,String)> {
+    let to_string = |value: &Value| match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string()
+    };
+    let mut out = Vec::new();
+    let fields = ["created_time", "updated_time", "current_version", "oldest_version", "max_versions", "cas_required", "delete_version_after"];
+    for field in &fields {
+        if let Some(value) = data.get(field).filter(|v| !v.is_null()) {
+            out.push((field.to_string(), to_string(value)));
+        }
+    }
+    if let Some(custom) = data["custom_metadata"].as_object() {
+        for (key, value) in custom {
+            out.push((format!("custom_metadata.{}", key), to_string(value)));
+        }
+    }
+    if let Some(versions) = data["versions"].as_object() {
+        let mut versions: Vec<(u64,&Value)> = versions.iter()
+            .filter_map(|(version, details)| Some((version.parse().ok()?, details)))
+            .collect();
+        versions.sort_by_key(|(version, _)| *version);
+        for (version, details) in versions {
+            for field in &["created_time", "deletion_time", "destroyed"] {
+                if let Some(value) = details.get(field) {
+                    out.push((format!("versions.{}.{}", version, field), to_string(value)));
+                }
+            }
+        }
+    }
+    out
+}
+
+fn to_strings(value: &Value) -> Option<Vec<String>> {
+    value.as_array()?
+        .iter()
+        .map(|v| v.as_str().map(|s| s.to_owned()))
+        .collect()
+}
+
+/// The names of the top level keys in a response from a KV2 'subkeys' endpoint.
+fn to_subkeys(res: &Value) -> Vec<String> {
+    res["data"]["subkeys"]
+        .as_object()
+        .map(|obj| obj.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// The capabilities on some path in a response from 'sys/capabilities-self'.
+/// Newer versions of Vault put these under 'data' as well as at the top level.
+fn capabilities_at(res: &Value, api_path: &str) -> Vec<String> {
+    to_strings(&res["data"][api_path])
+        .or_else(|| to_strings(&res[api_path]))
+        .unwrap_or_default()
+}
+
+/// Whether the capabilities given include the one needed ('root' allows anything).
+fn has_capability(capabilities: &[String], needed: &str) -> bool {
+    capabilities.iter().any(|c| c == needed || c == "root")
+}
+
+fn to_lease(value: &Value) -> Option<Lease> {
+    let id = value["lease_id"].as_str().filter(|id| !id.is_empty())?;
+    Some(Lease {
+        id: id.to_owned(),
+        ttl: Duration::from_secs(value["lease_duration"].as_u64().unwrap_or(0)),
+        renewable: value["renewable"].as_bool().unwrap_or(false)
+    })
+}
+
+/// How long it is until the time given (in seconds since the Unix epoch), if it's
+/// one that we can represent. Times that have passed are no time away.
+fn ttl_until(unix_secs: u64) -> Option<Duration> {
+    let at = SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(unix_secs))?;
+    Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// How long an OpenSSH certificate (as found in an 'authorized_keys' line, which
+/// is how Vault hands back signed keys) is valid for, from its 'valid before' time.
+fn ssh_certificate_ttl(signed_key: &str) -> Option<Duration> {
+    let blob = base64::decode(signed_key.split_whitespace().nth(1)?).ok()?;
+    let mut data = &blob[..];
+    // The public key in the certificate has a different number of fields for each type of key:
+    let key_fields = match std::str::from_utf8(take_ssh_string(&mut data)?).ok()? {
+        "ssh-rsa-cert-v01@openssh.com" => 2,
+        "ssh-dss-cert-v01@openssh.com" => 4,
+        "ssh-ed25519-cert-v01@openssh.com" => 1,
+        ty if ty.starts_with("ecdsa-sha2-") => 2,
+        _ => return None
+    };
+    // Skip the nonce and public key, then the serial number, type, key ID, principals
+    // and 'valid after' time:
+    for _ in 0..=key_fields {
+        take_ssh_string(&mut data)?;
+    }
+    take_bytes(&mut data, 8 + 4)?;
+    take_ssh_string(&mut data)?;
+    take_ssh_string(&mut data)?;
+    take_bytes(&mut data, 8)?;
+    let valid_before = u64::from_be_bytes(take_bytes(&mut data, 8)?.try_into().ok()?);
+    ttl_until(valid_before)
+}
+
+/// Take a string (a length, then that many bytes) from the front of some SSH wire format data.
+fn take_ssh_string<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u32::from_be_bytes(take_bytes(data, 4)?.try_into().ok()?);
+    take_bytes(data, len as usize)
+}
+
+fn take_bytes<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if data.len() < len {
+        return None
+    }
+    let (bytes, rest) = data.split_at(len);
+    *data = rest;
+    Some(bytes)
+}
+
+/// The keys and values in a secret. Numbers and booleans are handed back in
+/// their string form, null as an empty string, and objects and arrays as compact
+/// JSON if asked for (and otherwise, they're an error).
+fn to_keyvalues(value: &Value, json_values: bool) -> Result<Vec<(String,String)>> {
     let obj = value.as_object()
         .ok_or_else(|| anyhow!("Expected to find an object containing key/value pairs but got '{}'", value))?;
     let mut out = Vec::new();
     for (key, val) in obj {
-        let val_str = val.as_str()
-            .ok_or_else(|| anyhow!("The value for '{}' is not a string; is '{}'", key, val))?;
-        out.push((key.to_owned(), val_str.to_owned()));
+        let val_str = match val {
+            Value::String(s) => s.clone(),
+            Value::Null => String::new(),
+            Value::Bool(_) | Value::Number(_) => val.to_string(),
+            Value::Object(_) | Value::Array(_) if json_values => val.to_string(),
+            Value::Object(_) | Value::Array(_) => return Err(anyhow!(
+                "The value for '{}' is an object or array (use '--json-values' to pass it on as JSON); is '{}'"
+                , key, val))
+        };
+        out.push((key.to_owned(), val_str));
     }
     Ok(out)
 }
@@ -112,8 +1073,72 @@ fn to_keyvalues(value: &Value) -> Result<Vec<(String,String)>> {
 /// The supported secret storage types
 #[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
 pub enum StorageType {
+    /// KV version 2
     KV,
-    Cubbyhole
+    KV1,
+    Cubbyhole,
+    Transit,
+    Database,
+    Totp,
+    Ssh,
+    Pki,
+    Consul,
+    Nomad
+}
+
+impl StorageType {
+    /// The name we use for this type of store in messages.
+    pub fn name(self) -> &'static str {
+        match self {
+            StorageType::KV => "KV2",
+            StorageType::KV1 => "KV1",
+            StorageType::Cubbyhole => "Cubbyhole",
+            StorageType::Transit => "Transit",
+            StorageType::Database => "Database",
+            StorageType::Totp => "TOTP",
+            StorageType::Ssh => "SSH",
+            StorageType::Pki => "PKI",
+            StorageType::Consul => "Consul",
+            StorageType::Nomad => "Nomad"
+        }
+    }
+
+    /// The engine prefix that names this type of store (see 'from_prefix').
+    pub fn prefix(self) -> &'static str {
+        match self {
+            StorageType::KV => "kv2",
+            StorageType::KV1 => "kv1",
+            StorageType::Cubbyhole => "cubbyhole",
+            StorageType::Transit => "transit",
+            StorageType::Database => "database",
+            StorageType::Totp => "totp",
+            StorageType::Ssh => "ssh",
+            StorageType::Pki => "pki",
+            StorageType::Consul => "consul",
+            StorageType::Nomad => "nomad"
+        }
+    }
+
+    /// The storage type named by an engine prefix on a path. KV stores are
+    /// named by their version ('kv1' or 'kv2'), and other stores by their type.
+    fn from_prefix(prefix: &str) -> Option<StorageType> {
+        match prefix {
+            "kv2" => Some(StorageType::KV),
+            "kv1" => Some(StorageType::KV1),
+            "kv" | "generic" => None,
+            ty => StorageType::from_str(ty).ok()
+        }
+    }
+
+    /// The storage type of a mount, given its type and version (from its options).
+    /// Version 1 KV stores (and the older 'generic' stores) have no version option.
+    fn from_mount(ty: &str, version: Option<&str>) -> Result<StorageType> {
+        match (StorageType::from_str(ty)?, version) {
+            (StorageType::KV, Some("2")) => Ok(StorageType::KV),
+            (StorageType::KV, _) => Ok(StorageType::KV1),
+            (ty, _) => Ok(ty)
+        }
+    }
 }
 
 impl FromStr for StorageType {
@@ -121,8 +1146,349 @@ impl FromStr for StorageType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "kv" => Ok(StorageType::KV),
+            "generic" => Ok(StorageType::KV1),
             "cubbyhole" => Ok(StorageType::Cubbyhole),
+            "transit" => Ok(StorageType::Transit),
+            "database" => Ok(StorageType::Database),
+            "totp" => Ok(StorageType::Totp),
+            "ssh" => Ok(StorageType::Ssh),
+            "pki" => Ok(StorageType::Pki),
+            "consul" => Ok(StorageType::Consul),
+            "nomad" => Ok(StorageType::Nomad),
             _ => Err(anyhow!("'{}' is not a supported storage type", s))
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+    use url::Url;
+
+    #[test]
+    fn storage_type_from_mount() {
+        let cases = vec![
+            ("kv", Some("2"), Some(StorageType::KV)),
+            ("kv", Some("1"), Some(StorageType::KV1)),
+            ("kv", None, Some(StorageType::KV1)),
+            ("generic", None, Some(StorageType::KV1)),
+            ("cubbyhole", None, Some(StorageType::Cubbyhole)),
+            ("transit", None, Some(StorageType::Transit)),
+            ("database", None, Some(StorageType::Database)),
+            ("totp", None, Some(StorageType::Totp)),
+            ("ssh", None, Some(StorageType::Ssh)),
+            ("pki", None, Some(StorageType::Pki)),
+            ("consul", None, Some(StorageType::Consul)),
+            ("nomad", None, Some(StorageType::Nomad)),
+            ("aws", None, None),
+        ];
+        for (ty, version, expected) in cases {
+            assert_eq!(StorageType::from_mount(ty, version).ok(), expected, "Unexpected storage type for '{}' (version {:?})", ty, version);
+        }
+    }
+
+    #[test]
+    fn engine_prefixes_from_paths() {
+        let cases = vec![
+            ("kv2:secret/app/db", Some((StorageType::KV, "secret/app/db"))),
+            ("/kv1:legacy/app/db", Some((StorageType::KV1, "legacy/app/db"))),
+            ("database:db/creds/app", Some((StorageType::Database, "db/creds/app"))),
+            ("pki:pki/issue/web", Some((StorageType::Pki, "pki/issue/web"))),
+            // 'kv' alone doesn't say which version of KV store it is:
+            ("kv:secret/app/db", None),
+            ("aws:aws/creds/app", None),
+            ("secret/app/db", None),
+            ("unwrap:s.abc123", None),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(split_engine_prefix(path), expected, "Unexpected engine prefix split of '{}'", path);
+        }
+        // Each type of store can be named by its prefix:
+        let storage_types = [StorageType::KV, StorageType::KV1, StorageType::Cubbyhole, StorageType::Transit, StorageType::Database
+            , StorageType::Totp, StorageType::Ssh, StorageType::Pki, StorageType::Consul, StorageType::Nomad];
+        for ty in storage_types {
+            assert_eq!(StorageType::from_prefix(ty.prefix()), Some(ty), "Unexpected storage type for the prefix '{}'", ty.prefix());
+        }
+    }
+
+    #[test]
+    fn versions_from_paths() {
+        let cases = vec![
+            ("secret/app/db", Some(("secret/app/db", None))),
+            ("secret/app/db?version=3", Some(("secret/app/db", Some(3)))),
+            ("secret/app/db?version=12", Some(("secret/app/db", Some(12)))),
+            ("secret/app/db?version=0", None),
+            ("secret/app/db?version=", None),
+            ("secret/app/db?version=latest", None),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(split_version(path).ok(), expected, "Unexpected version for '{}'", path);
+        }
+    }
+
+    #[test]
+    fn metadata_is_flattened() {
+        let data = json!({
+            "created_time": "2020-03-01T10:00:00Z",
+            "current_version": 10,
+            "max_versions": 0,
+            "cas_required": false,
+            "custom_metadata": { "owner": "platform" },
+            "versions": {
+                "10": { "created_time": "2020-03-10T10:00:00Z", "deletion_time": "", "destroyed": false },
+                "9": { "created_time": "2020-03-09T10:00:00Z", "deletion_time": "2020-03-09T11:00:00Z", "destroyed": true }
+            }
+        });
+        let expected = vec![
+            ("created_time", "2020-03-01T10:00:00Z"),
+            ("current_version", "10"),
+            ("max_versions", "0"),
+            ("cas_required", "false"),
+            ("custom_metadata.owner", "platform"),
+            ("versions.9.created_time", "2020-03-09T10:00:00Z"),
+            ("versions.9.deletion_time", "2020-03-09T11:00:00Z"),
+            ("versions.9.destroyed", "true"),
+            ("versions.10.created_time", "2020-03-10T10:00:00Z"),
+            ("versions.10.deletion_time", ""),
+            ("versions.10.destroyed", "false"),
+        ];
+        let actual = flatten_metadata(&data);
+        let actual: Vec<(&str,&str)> = actual.iter().map(|(k,v)| (&**k, &**v)).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn keyvalues_from_secrets() {
+        let data = json!({
+            "string": "hello",
+            "number": 1.5,
+            "bool": true,
+            "null": null,
+            "object": { "host": "db", "port": 5432 },
+            "array": [1, "two"]
+        });
+        let cases = vec![
+            (json!({ "string": "hello" }), false, Some(vec![("string", "hello")])),
+            (data.clone(), true, Some(vec![
+                ("array", "[1,\"two\"]"),
+                ("bool", "true"),
+                ("null", ""),
+                ("number", "1.5"),
+                ("object", "{\"host\":\"db\",\"port\":5432}"),
+                ("string", "hello")
+            ])),
+            (data, false, None),
+            (json!("not an object"), true, None),
+        ];
+        for (value, json_values, expected) in cases {
+            let actual = to_keyvalues(&value, json_values).ok();
+            let actual: Option<Vec<(&str,&str)>> = actual.as_ref().map(|kvs| kvs.iter().map(|(k,v)| (&**k, &**v)).collect());
+            assert_eq!(actual, expected, "Unexpected key/values for {} (json values: {})", value, json_values);
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_secrets_can_be_forgotten() {
+        // Nothing is listening here, so any secret that isn't cached can't be found:
+        let client = Client::new("http://127.0.0.1:1".parse().unwrap(), reqwest::Client::builder()).unwrap();
+        let mut cached = HashMap::new();
+        cached.insert("secret/app/db".to_owned(), vec![("password".to_owned(), "hunter2".to_owned())]);
+        let store = SecretStore::with_mount_table(client, &[("kv2".to_owned(), "secret".to_owned())])
+            .with_cached_secrets(cached);
+
+        assert_eq!(store.get("/secret/app/db").await.unwrap(), vec![("password".to_owned(), "hunter2".to_owned())]);
+        assert_eq!(store.access_needed("secret/app/db"), None);
+
+        // Once forgotten, secrets are asked for again rather than taken from the cache:
+        store.forget_cached_secrets();
+        assert!(store.get("/secret/app/db").await.is_err());
+        assert_eq!(store.access_needed("secret/app/db"), Some(("secret/data/app/db".to_owned(), "read")));
+    }
+
+    /// Respond to every request with the JSON given, counting them.
+    async fn mock_vault(response: &'static str) -> (Url, Arc<AtomicUsize>) {
+        use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                // Read the whole request (including any body) before responding:
+                let mut req = Vec::new();
+                let mut buf = [0u8; 1024];
+                loop {
+                    let n = socket.read(&mut buf).await.unwrap_or(0);
+                    req.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&req).to_ascii_lowercase();
+                    let header_len = match text.find("\r\n\r\n") {
+                        Some(idx) => idx + 4,
+                        None if n > 0 => continue,
+                        None => break
+                    };
+                    let content_length: usize = text.lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .and_then(|len| len.trim().parse().ok())
+                        .unwrap_or(0);
+                    if n == 0 || req.len() >= header_len + content_length {
+                        break
+                    }
+                }
+                let res = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}"
+                    , response.len(), response);
+                let _ = socket.write_all(res.as_bytes()).await;
+            }
+        });
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn each_path_is_read_once() {
+        let (url, requests) = mock_vault(r#"{ "data": { "password": "hunter2", "username": "admin" } }"#).await;
+        let client = Client::new(url, reqwest::Client::builder()).unwrap();
+        let store = SecretStore::with_mount_table(client, &[("kv1".to_owned(), "legacy".to_owned())]);
+        let expected = vec![("password".to_owned(), "hunter2".to_owned()), ("username".to_owned(), "admin".to_owned())];
+
+        // Reads of the same path at once, and after it's been read, share the one request:
+        let (a, b) = futures::join!(store.get("/legacy/app/db"), store.get("legacy/app/db"));
+        assert_eq!(a.unwrap(), expected);
+        assert_eq!(b.unwrap(), expected);
+        assert_eq!(store.get("/legacy/app/db").await.unwrap(), expected);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Each other path is asked for once too:
+        assert_eq!(store.get("/legacy/app/api").await.unwrap(), expected);
+        assert_eq!(store.get("/legacy/app/api").await.unwrap(), expected);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Once forgotten, secrets are asked for again:
+        store.forget_cached_secrets();
+        assert_eq!(store.get("/legacy/app/db").await.unwrap(), expected);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn generated_secrets_are_kept_until_they_expire() {
+        let cases = vec![
+            // Credentials are kept for as long as their lease lasts:
+            ("database", "db/creds/app", r#"{ "lease_id": "db/creds/app/abc", "lease_duration": 3600, "data": { "username": "u", "password": "p" } }"#, 1),
+            ("database", "db/creds/app", r#"{ "lease_id": "db/creds/app/abc", "lease_duration": 0, "data": { "username": "u", "password": "p" } }"#, 1),
+            // Static credentials are kept until they're rotated:
+            ("database", "db/static-creds/app", r#"{ "data": { "username": "u", "password": "p", "ttl": 3600 } }"#, 1),
+            // Certificates are kept until they expire, which might already have happened:
+            ("pki", "pki/issue/web", r#"{ "data": { "certificate": "c", "private_key": "k", "expiration": 4102444800 } }"#, 1),
+            ("pki", "pki/issue/web", r#"{ "data": { "certificate": "c", "private_key": "k", "expiration": 946684800 } }"#, 2),
+            // TOTP codes are only valid briefly, so are generated afresh each time:
+            ("totp", "totp/code/app", r#"{ "data": { "code": "123456" } }"#, 2),
+        ];
+        for (prefix, path, response, expected) in cases {
+            let (url, requests) = mock_vault(response).await;
+            let client = Client::new(url, reqwest::Client::builder()).unwrap();
+            let mount = path.split('/').next().unwrap();
+            let store = SecretStore::with_mount_table(client, &[(prefix.to_owned(), mount.to_owned())])
+                .with_pki_certificate("example.com".to_owned(), None);
+
+            let first = store.get(path).await.unwrap();
+            store.forget_cached_secrets();
+            let second = store.get(path).await.unwrap();
+            assert_eq!(first, second);
+            assert_eq!(requests.load(Ordering::SeqCst), expected, "Unexpected number of requests for '{}' given {}", path, response);
+        }
+    }
+
+    #[test]
+    fn ssh_certificate_ttls() {
+        // Build a certificate (which is never checked) for a key of the type given:
+        fn ssh_string(bytes: &[u8]) -> Vec<u8> {
+            let mut out = (bytes.len() as u32).to_be_bytes().to_vec();
+            out.extend_from_slice(bytes);
+            out
+        }
+        fn certificate(ty: &str, key_fields: usize, valid_before: u64) -> String {
+            let mut blob = ssh_string(ty.as_bytes());
+            blob.extend(ssh_string(b"nonce"));
+            for _ in 0..key_fields {
+                blob.extend(ssh_string(b"key"));
+            }
+            blob.extend(1u64.to_be_bytes());
+            blob.extend(1u32.to_be_bytes());
+            blob.extend(ssh_string(b"vault-root-abc"));
+            blob.extend(ssh_string(&ssh_string(b"ubuntu")));
+            blob.extend(0u64.to_be_bytes());
+            blob.extend(valid_before.to_be_bytes());
+            blob.extend(ssh_string(b"critical options and the rest"));
+            format!("{} {}\n", ty, base64::encode(&blob))
+        }
+        let in_an_hour = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() + 3600;
+        let about_an_hour = |ttl: Option<Duration>| matches!(ttl, Some(ttl) if ttl > Duration::from_secs(3590) && ttl <= Duration::from_secs(3600));
+
+        let cases = vec![
+            (certificate("ssh-ed25519-cert-v01@openssh.com", 1, in_an_hour), true),
+            (certificate("ssh-rsa-cert-v01@openssh.com", 2, in_an_hour), true),
+            (certificate("ecdsa-sha2-nistp256-cert-v01@openssh.com", 2, in_an_hour), true),
+            // The public key has the wrong number of fields for the type, so it's garbled:
+            (certificate("ssh-rsa-cert-v01@openssh.com", 1, in_an_hour), false),
+            (certificate("ssh-ed25519", 1, in_an_hour), false),
+            ("ssh-ed25519-cert-v01@openssh.com !!!".to_owned(), false),
+        ];
+        for (signed_key, expected) in cases {
+            assert_eq!(about_an_hour(ssh_certificate_ttl(&signed_key)), expected, "Unexpected TTL for '{}'", signed_key);
+        }
+
+        // Certificates that are valid forever never expire, and those that have expired already have:
+        assert_eq!(ssh_certificate_ttl(&certificate("ssh-ed25519-cert-v01@openssh.com", 1, u64::MAX)), None);
+        assert_eq!(ssh_certificate_ttl(&certificate("ssh-ed25519-cert-v01@openssh.com", 1, 1)), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn subkeys_from_responses() {
+        let cases = vec![
+            (json!({ "data": { "subkeys": { "password": null, "username": null }, "metadata": { "version": 3 } } }), vec!["password", "username"]),
+            // Only the top level keys are handed back, whatever is nested in them:
+            (json!({ "data": { "subkeys": { "db": { "host": null, "port": null } } } }), vec!["db"]),
+            (json!({ "data": { "subkeys": {} } }), vec![]),
+            (json!({ "data": {} }), vec![]),
+        ];
+        for (res, expected) in cases {
+            assert_eq!(to_subkeys(&res), expected, "Response: {}", res);
+        }
+    }
+
+    #[test]
+    fn leases_from_responses() {
+        let cases = vec![
+            (json!({ "lease_id": "database/creds/app/abc", "lease_duration": 3600, "renewable": true }),
+                Some(Lease { id: "database/creds/app/abc".to_owned(), ttl: Duration::from_secs(3600), renewable: true })),
+            (json!({ "lease_id": "consul/creds/app/abc", "lease_duration": 60 }),
+                Some(Lease { id: "consul/creds/app/abc".to_owned(), ttl: Duration::from_secs(60), renewable: false })),
+            // Static credentials aren't leased:
+            (json!({ "lease_id": "", "lease_duration": 0, "renewable": false }), None),
+            (json!({ "data": {} }), None),
+        ];
+        for (res, expected) in cases {
+            assert_eq!(to_lease(&res), expected, "Unexpected lease for {}", res);
+        }
+    }
+
+    #[test]
+    fn capabilities_from_responses() {
+        let cases = vec![
+            (json!({ "secret/data/app": ["read", "list"], "data": { "secret/data/app": ["read", "list"] } }), "read", true),
+            (json!({ "secret/data/app": ["read"] }), "read", true),
+            (json!({ "data": { "secret/data/app": ["root"] } }), "read", true),
+            (json!({ "data": { "secret/data/app": ["list"] } }), "read", false),
+            (json!({ "data": { "secret/data/app": ["deny"] } }), "read", false),
+            (json!({ "data": { "secret/data/app": ["read"] } }), "update", false),
+            (json!({ "data": { "secret/data/other": ["read"] } }), "read", false),
+        ];
+        for (res, needed, expected) in cases {
+            let capabilities = capabilities_at(&res, "secret/data/app");
+            assert_eq!(has_capability(&capabilities, needed), expected, "Unexpected '{}' capability in {}", needed, res);
+        }
+    }
+
+}
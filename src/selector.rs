@@ -0,0 +1,141 @@
+use std::str::FromStr;
+use anyhow::{ anyhow, Result, Context };
+use serde_json::Value;
+
+/// Picks a field out of a secret value which is a JSON document. Selectors
+/// are written like jq paths (eg '.database.hosts[0]' or '.["some.key"]'),
+/// or as JSON pointers (eg '/database/hosts/0').
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Selector {
+    original: String,
+    // The selector as a JSON pointer, which is how we look things up:
+    pointer: String
+}
+
+impl Selector {
+
+    /// Parse the value as JSON and select the field from it. Strings are
+    /// handed back as they are, and anything else as compact JSON.
+    pub fn select(&self, value: &str) -> Result<String> {
+        let json: Value = serde_json::from_str(value)
+            .context("The secret is not a JSON document")?;
+        match json.pointer(&self.pointer) {
+            Some(Value::String(s)) => Ok(s.clone()),
+            Some(other) => Ok(other.to_string()),
+            None => Err(anyhow!("The field '{}' could not be found", self.original))
+        }
+    }
+
+}
+
+impl std::fmt::Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.original)
+    }
+}
+
+impl FromStr for Selector {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Selector> {
+        let pointer = if s.starts_with('/') {
+            s.to_owned()
+        } else if s.starts_with('.') || s.starts_with('[') {
+            jq_path_to_pointer(s)?
+        } else {
+            return Err(anyhow!("Expected a field selector like '.field.other[0]' or '/field/other/0' but got '{}'", s));
+        };
+        Ok(Selector { original: s.to_owned(), pointer })
+    }
+}
+
+/// Convert a jq style path like '.a["b.c"][0]' into a JSON pointer like '/a/b.c/0'.
+fn jq_path_to_pointer(s: &str) -> Result<String> {
+    let invalid = || anyhow!("'{}' is not a valid field selector (try something like '.field.other[0]')", s);
+    let mut pointer = String::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+        let segment;
+        if let Some(after) = rest.strip_prefix("[\"") {
+            let end = after.find("\"]").ok_or_else(invalid)?;
+            segment = &after[..end];
+            rest = &after[end+2..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(invalid)?;
+            segment = &after[..end];
+            if segment.is_empty() || !segment.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            rest = &after[end+1..];
+        } else if let Some(after) = rest.strip_prefix('.') {
+            // '.["key"]' and '.[0]' are the same as '["key"]' and '[0]':
+            if after.starts_with('[') {
+                rest = after;
+                continue
+            }
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            segment = &after[..end];
+            // A lone '.' selects the whole document:
+            if segment.is_empty() && !(after.is_empty() && pointer.is_empty()) {
+                return Err(invalid());
+            }
+            rest = &after[end..];
+            if segment.is_empty() {
+                continue
+            }
+        } else {
+            return Err(invalid());
+        }
+        pointer.push('/');
+        pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    }
+    Ok(pointer)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parse_selectors() {
+        let cases = vec![
+            (".", Some("")),
+            (".database", Some("/database")),
+            (".database.host", Some("/database/host")),
+            (".hosts[0]", Some("/hosts/0")),
+            (".hosts[0].name", Some("/hosts/0/name")),
+            ("[1]", Some("/1")),
+            (".[\"some.key\"].value", Some("/some.key/value")),
+            (".a/b", Some("/a~1b")),
+            ("/database/hosts/0", Some("/database/hosts/0")),
+            ("database", None),
+            (".a..b", None),
+            (".hosts[x]", None),
+            (".hosts[0", None),
+            (".[\"unterminated", None),
+        ];
+        for (s, expected) in cases {
+            let actual = Selector::from_str(s).ok().map(|sel| sel.pointer);
+            assert_eq!(actual.as_deref(), expected, "Unexpected pointer for '{}'", s);
+        }
+    }
+
+    #[test]
+    fn select_fields() {
+        let doc = r#"{ "database": { "host": "db.local", "port": 5432, "hosts": ["a", "b"] } }"#;
+        let cases = vec![
+            (".database.host", doc, Some("db.local")),
+            (".database.port", doc, Some("5432")),
+            (".database.hosts", doc, Some("[\"a\",\"b\"]")),
+            (".database.hosts[1]", doc, Some("b")),
+            ("/database/hosts/0", doc, Some("a")),
+            (".database.user", doc, None),
+            (".database", "not json", None),
+        ];
+        for (selector, value, expected) in cases {
+            let actual = Selector::from_str(selector).unwrap().select(value).ok();
+            assert_eq!(actual.as_deref(), expected, "Unexpected value for '{}'", selector);
+        }
+    }
+
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+use anyhow::{ anyhow, Result, Context };
+use serde_json::{ Value, json };
+use crate::client::Client;
+use crate::secret_store::SecretStore;
+
+/// Copy the secret at some 'path/to/secret/key' into a response-wrapped
+/// cubbyhole which can be unwrapped once within the TTL given, and return
+/// the wrapping token needed to do so.
+pub async fn share(store: &SecretStore, original_path: &str, ttl: Duration) -> Result<String> {
+    let idx = original_path.rfind('/').unwrap_or(0);
+    let (path, key) = (&original_path[0..idx], &original_path[idx+1..]);
+
+    let value = store.get(path)
+        .await?
+        .into_iter()
+        .find(|(k,_)| k == key)
+        .map(|(_,v)| v)
+        .ok_or_else(|| anyhow!("The secret '{}' could not be found", original_path))?;
+
+    wrap(store.client(), &[(key.to_owned(), value)], ttl)
+        .await
+        .with_context(|| format!("Could not wrap the secret '{}'", original_path))
+}
+
+/// Wrap some keys and values in a single-use token which lasts for the TTL
+/// given, returning the wrapping token.
+pub async fn wrap(client: &Client, secrets: &[(String,String)], ttl: Duration) -> Result<String> {
+    let data: serde_json::Map<String,Value> = secrets.iter()
+        .map(|(k,v)| (k.clone(), Value::String(v.clone())))
+        .collect();
+    let res: Value = client
+        .with_wrap_ttl(ttl)
+        .post("sys/wrapping/wrap", &data)
+        .await?;
+
+    res["wrap_info"]["token"]
+        .as_str()
+        .map(|t| t.to_owned())
+        .ok_or_else(|| anyhow!("Could not find the wrapping token in Vault's response"))
+}
+
+/// Unwrap a secret shared using a wrapping token, returning its key and value.
+/// This doesn't need us to be logged in; the wrapping token is enough.
+pub async fn receive(client: &Client, wrapping_token: &str) -> Result<(String,String)> {
+    unwrap(client, wrapping_token)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("Could not find the secret in Vault's response"))
+}
+
+/// Unwrap the keys and values in a wrapping token. A token can only be unwrapped once.
+pub async fn unwrap(client: &Client, wrapping_token: &str) -> Result<Vec<(String,String)>> {
+    let res: Value = client.with_token(wrapping_token.to_owned())
+        .post("sys/wrapping/unwrap", &json!({}))
+        .await
+        .context("Could not unwrap the secret (has it already been received, or expired?)")?;
+
+    let data = res["data"]
+        .as_object()
+        .ok_or_else(|| anyhow!("Could not find the secret in Vault's response"))?;
+    data.iter()
+        .map(|(key, value)| {
+            let value = value.as_str()
+                .ok_or_else(|| anyhow!("The value for '{}' is not a string; is '{}'", key, value))?;
+            Ok((key.to_owned(), value.to_owned()))
+        })
+        .collect()
+}
@@ -0,0 +1,81 @@
+use std::process::Command;
+use std::str::FromStr;
+use anyhow::{ anyhow, Result };
+
+/// The shell that we run commands with (the main command, '--each' commands,
+/// processors and so on), and the argument that it's given commands after.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Shell {
+    program: String,
+    command_arg: &'static str
+}
+
+impl Shell {
+
+    /// The program that runs commands.
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// Prepare a command to be run via this shell.
+    pub fn command(&self, cmd_str: &str) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.arg(self.command_arg).arg(cmd_str);
+        cmd
+    }
+
+}
+
+impl Default for Shell {
+    fn default() -> Shell {
+        if cfg!(windows) {
+            Shell { program: "cmd".to_owned(), command_arg: "/C" }
+        } else {
+            Shell { program: "sh".to_owned(), command_arg: "-c" }
+        }
+    }
+}
+
+impl FromStr for Shell {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Shells on Windows are often given as paths with backslashes:
+        let name = s.rsplit(['/', '\\']).next().unwrap_or(s).to_ascii_lowercase();
+        let name = name.trim_end_matches(".exe");
+        if name.is_empty() {
+            return Err(anyhow!("'{}' is not a valid shell", s));
+        }
+        // Most shells take '-c', but those on Windows do things differently:
+        let command_arg = match name {
+            "cmd" => "/C",
+            "powershell" | "pwsh" => "-Command",
+            _ => "-c"
+        };
+        Ok(Shell { program: s.to_owned(), command_arg })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parse_shells() {
+        let cases = vec![
+            ("sh", "-c"),
+            ("/bin/bash", "-c"),
+            ("zsh", "-c"),
+            ("cmd", "/C"),
+            ("C:\\Windows\\System32\\cmd.exe", "/C"),
+            ("powershell.exe", "-Command"),
+            ("pwsh", "-Command"),
+        ];
+        for (s, command_arg) in cases {
+            let shell: Shell = s.parse().unwrap();
+            assert_eq!(shell.program(), s);
+            assert_eq!(shell.command_arg, command_arg, "Unexpected argument for '{}'", s);
+        }
+    }
+
+}
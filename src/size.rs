@@ -0,0 +1,83 @@
+use std::fmt;
+use std::str::FromStr;
+use anyhow::{ anyhow, Result };
+
+/// A number of bytes, written like '512', '64KiB', '4MiB' or '1MB'.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub struct ByteSize(pub usize);
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = [("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)];
+        for &(unit, size) in &units {
+            // Each unit is a power of two, so this checks for a whole number of them:
+            if self.0 >= size && self.0 & (size - 1) == 0 {
+                return write!(f, "{}{}", self.0 / size, unit);
+            }
+        }
+        write!(f, "{}B", self.0)
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<ByteSize> {
+        let s = s.trim();
+        let idx = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (num_str, unit) = (&s[0..idx], s[idx..].trim());
+
+        let num: usize = num_str.parse()
+            .map_err(|_| anyhow!("Expected a size like '512KiB' or '4MiB' but got '{}'", s))?;
+
+        let multiplier: usize = match unit.to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" => 1000,
+            "kib" => 1 << 10,
+            "m" | "mb" => 1000 * 1000,
+            "mib" => 1 << 20,
+            "g" | "gb" => 1000 * 1000 * 1000,
+            "gib" => 1 << 30,
+            _ => return Err(anyhow!("'{}' is not a valid size unit in '{}' (try 'B', 'KiB', 'MiB' or 'GiB')", unit, s))
+        };
+        num.checked_mul(multiplier)
+            .map(ByteSize)
+            .ok_or_else(|| anyhow!("The size '{}' is too large", s))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+
+        let cases = vec![
+            ("512", Some(512)),
+            ("512B", Some(512)),
+            ("64KiB", Some(64 * 1024)),
+            ("4MiB", Some(4 * 1024 * 1024)),
+            ("4 mib", Some(4 * 1024 * 1024)),
+            ("1MB", Some(1000 * 1000)),
+            ("1GiB", Some(1024 * 1024 * 1024)),
+            // Invalid units or numbers:
+            ("4XB", None),
+            ("MiB", None),
+            ("", None),
+        ];
+
+        for (s, expected) in cases {
+            assert_eq!(ByteSize::from_str(s).ok(), expected.map(ByteSize), "Unexpected result parsing size '{}'", s);
+        }
+
+    }
+
+    #[test]
+    fn test_display_size() {
+        assert_eq!(ByteSize(4 * 1024 * 1024).to_string(), "4MiB");
+        assert_eq!(ByteSize(1536).to_string(), "1536B");
+        assert_eq!(ByteSize(2048).to_string(), "2KiB");
+    }
+
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+use anyhow::{ Result, Context };
+use serde_json::Value;
+use vault_inject::client::Client;
+
+/// Ask the Vault node we're talking to how it is: whether it's initialized and
+/// unsealed, and whether it's the active node. Vault normally responds with an
+/// error status for anything but an active node, so we ask it not to.
+pub async fn health(client: &Client) -> Result<Vec<(String,String)>> {
+    let res: Value = client
+        .get("sys/health?standbycode=200&perfstandbycode=200&drsecondarycode=200&performancestandbycode=200&sealedcode=200&uninitcode=200")
+        .await
+        .context("Could not check the health of Vault")?;
+    Ok(describe_health(&res))
+}
+
+/// Look up the token that the client uses: who it belongs to, how long it has left and its policies.
+pub async fn token(client: &Client) -> Result<Vec<(String,String)>> {
+    let res: Value = client.get("auth/token/lookup-self")
+        .await
+        .context("Could not look up the token")?;
+    Ok(describe_token(&res["data"]))
+}
+
+fn describe_health(health: &Value) -> Vec<(String,String)> {
+    let mode = if health["replication_dr_mode"].as_str() == Some("secondary") {
+        "DR secondary"
+    } else if health["performance_standby"].as_bool() == Some(true) {
+        "performance standby"
+    } else if health["standby"].as_bool() == Some(true) {
+        "standby"
+    } else {
+        "active"
+    };
+    let mut status = vec![
+        ("initialized".to_owned(), health["initialized"].as_bool().unwrap_or(false).to_string()),
+        ("sealed".to_owned(), health["sealed"].as_bool().unwrap_or(false).to_string()),
+        ("mode".to_owned(), mode.to_owned()),
+    ];
+    for (key, name) in &[("version", "version"), ("cluster_name", "cluster-name")] {
+        if let Some(value) = health[key].as_str() {
+            status.push((name.to_string(), value.to_owned()));
+        }
+    }
+    status
+}
+
+fn describe_token(token: &Value) -> Vec<(String,String)> {
+    let mut status = Vec::new();
+    if let Some(name) = token["display_name"].as_str() {
+        status.push(("token-name".to_owned(), name.to_owned()));
+    }
+    if let Some(entity_id) = token["entity_id"].as_str().filter(|id| !id.is_empty()) {
+        status.push(("token-entity".to_owned(), entity_id.to_owned()));
+    }
+    let ttl = match token["ttl"].as_u64() {
+        // Tokens like the root token never expire:
+        Some(0) => "never expires".to_owned(),
+        Some(secs) => format!("{:?}", Duration::from_secs(secs)),
+        None => "unknown".to_owned()
+    };
+    status.push(("token-ttl".to_owned(), ttl));
+    status.push(("token-renewable".to_owned(), token["renewable"].as_bool().unwrap_or(false).to_string()));
+    let policies: Vec<&str> = token["policies"].as_array()
+        .map(|policies| policies.iter().filter_map(|p| p.as_str()).collect())
+        .unwrap_or_default();
+    status.push(("token-policies".to_owned(), policies.join(", ")));
+    status
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn describe_health_responses() {
+        let cases = vec![
+            (json!({ "initialized": true, "sealed": false, "standby": false, "version": "1.4.0", "cluster_name": "prod" }),
+                vec![("initialized", "true"), ("sealed", "false"), ("mode", "active"), ("version", "1.4.0"), ("cluster-name", "prod")]),
+            (json!({ "initialized": true, "sealed": false, "standby": true, "performance_standby": true }),
+                vec![("initialized", "true"), ("sealed", "false"), ("mode", "performance standby")]),
+            (json!({ "initialized": true, "sealed": true, "standby": true }),
+                vec![("initialized", "true"), ("sealed", "true"), ("mode", "standby")]),
+            (json!({ "initialized": true, "sealed": false, "standby": true, "replication_dr_mode": "secondary" }),
+                vec![("initialized", "true"), ("sealed", "false"), ("mode", "DR secondary")]),
+        ];
+        for (health, expected) in cases {
+            let expected: Vec<(String,String)> = expected.into_iter().map(|(k,v)| (k.to_owned(), v.to_owned())).collect();
+            assert_eq!(describe_health(&health), expected, "Unexpected description of {}", health);
+        }
+    }
+
+    #[test]
+    fn describe_tokens() {
+        let cases = vec![
+            (json!({ "display_name": "ldap-alice", "entity_id": "e-123", "ttl": 3600, "renewable": true, "policies": ["default", "app"] }),
+                vec![("token-name", "ldap-alice"), ("token-entity", "e-123"), ("token-ttl", "3600s"), ("token-renewable", "true"), ("token-policies", "default, app")]),
+            (json!({ "display_name": "root", "entity_id": "", "ttl": 0, "renewable": false, "policies": ["root"] }),
+                vec![("token-name", "root"), ("token-ttl", "never expires"), ("token-renewable", "false"), ("token-policies", "root")]),
+        ];
+        for (token, expected) in cases {
+            let expected: Vec<(String,String)> = expected.into_iter().map(|(k,v)| (k.to_owned(), v.to_owned())).collect();
+            assert_eq!(describe_token(&token), expected, "Unexpected description of {}", token);
+        }
+    }
+
+}
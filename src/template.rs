@@ -21,7 +21,7 @@ impl Template {
     /// Given a string, attempt to match this template. If we
     /// succeed, return those matches. If not, return None
     pub fn matches<'t>(&self, s: &'t str) -> Option<Matches<'t>> {
-        self.re.captures(s).map(|caps| Matches(caps))
+        self.re.captures(s).map(Matches)
     }
 
     /// Convert this template into a string using the matches obtained
@@ -43,6 +43,39 @@ impl Template {
         out
     }
 
+    /// If this template contains no {params}, return the
+    /// string that it will always stringify to
+    pub fn as_literal(&self) -> Option<String> {
+        let mut out = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Str(s) => out.push_str(s),
+                Piece::Param(_) => return None
+            }
+        }
+        Some(out)
+    }
+
+    /// Fill in one of the {params} with a value, leaving any others as they are.
+    pub fn fill(&self, name: &str, value: &str) -> Template {
+        let mut pieces: Vec<Piece> = Vec::new();
+        for piece in &self.pieces {
+            let s = match piece {
+                Piece::Param(param) if param == name => value,
+                Piece::Str(s) => s,
+                Piece::Param(_) => {
+                    pieces.push(piece.clone());
+                    continue
+                }
+            };
+            match pieces.last_mut() {
+                Some(Piece::Str(last)) => last.push_str(s),
+                _ => pieces.push(Piece::Str(s.to_owned()))
+            }
+        }
+        Template::from_pieces(pieces)
+    }
+
     /// Is it possible to stringify this template from the one
     /// provided without leaving gaps? In order for this to be true,
     /// the other template must contain all of the named {params}
@@ -68,6 +101,18 @@ impl Template {
 
 }
 
+impl std::fmt::Display for Template {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for piece in &self.pieces {
+            match piece {
+                Piece::Str(s) => write!(f, "{}", s)?,
+                Piece::Param(name) => write!(f, "{{{}}}", name)?
+            }
+        }
+        Ok(())
+    }
+}
+
 impl PartialEq for Template {
     fn eq(&self, other: &Self) -> bool {
         // if pieces are the same, the regex will be also:
@@ -113,6 +158,12 @@ impl FromStr for Template {
         // Remember to push the rest of the string into the regex:
         out_pieces.push(Piece::Str(s[last_idx..].to_owned()));
 
+        Ok(Template::from_pieces(out_pieces))
+    }
+}
+
+impl Template {
+    fn from_pieces(out_pieces: Vec<Piece>) -> Template {
         // Build up a regular expression from the pieces that we can match with:
         let mut out_regex = String::new();
         out_regex.push('^');
@@ -128,10 +179,10 @@ impl FromStr for Template {
         }
         out_regex.push('$');
 
-        Ok(Template {
+        Template {
             pieces: out_pieces,
             re: Regex::from_str(&out_regex).unwrap()
-        })
+        }
     }
 }
 
@@ -153,18 +204,18 @@ impl <'t> Matcher for Matches<'t> {
     }
 }
 
+// Allow providing a vec of keyvalue pairs to substitute templates with:
+impl Matcher for Vec<(&str,&str)> {
+    fn get_match(&self, key: &str) -> Option<&str> {
+        self.iter().find(|(k,_v)| *k == key).map(|(_k,v)| *v)
+    }
+}
+
 #[cfg(test)]
 mod test {
 
     use super::*;
 
-    // Allow providing a vec of keyvalue pairs to substitute templates with:
-    impl Matcher for Vec<(&str,&str)> {
-        fn get_match(&self, key: &str) -> Option<&str> {
-            self.iter().find(|(k,_v)| *k == key).map(|(_k,v)| *v)
-        }
-    }
-
     #[test]
     fn stringify_template() {
 
@@ -186,6 +237,44 @@ mod test {
 
     }
 
+    #[test]
+    fn display_template() {
+
+        let cases = vec![
+            ("foo_{bar}", "foo_{bar}", None),
+            ("foo_{ bar }", "foo_{bar}", None),
+            ("{a},{b}", "{a},{b}", None),
+            ("FOO", "FOO", Some("FOO")),
+        ];
+
+        for (tmpl_str, expected, expected_literal) in cases {
+            let tmpl = Template::new(tmpl_str).expect("Could not instantiate template");
+            assert_eq!(tmpl.to_string(), expected, "Displayed template does not match expected");
+            assert_eq!(tmpl.as_literal().as_deref(), expected_literal, "Template literal does not match expected");
+        }
+
+    }
+
+    #[test]
+    fn fill_template() {
+
+        let cases = vec![
+            ("DB_{name}", "name", "main", "DB_main", Some("DB_main")),
+            ("DB_{name}_{key}", "name", "main", "DB_main_{key}", None),
+            ("{key}_{name}", "name", "main", "{key}_main", None),
+            ("DB_{key}", "name", "main", "DB_{key}", None),
+            // Values are never taken to be params:
+            ("DB_{name}", "name", "{x}", "DB_{x}", Some("DB_{x}")),
+        ];
+
+        for (tmpl_str, name, value, expected, expected_literal) in cases {
+            let tmpl = Template::new(tmpl_str).expect("Could not instantiate template").fill(name, value);
+            assert_eq!(tmpl.to_string(), expected, "Filled in template does not match expected");
+            assert_eq!(tmpl.as_literal().as_deref(), expected_literal, "Filled in template literal does not match expected");
+        }
+
+    }
+
     #[test]
     fn match_template() {
 
@@ -0,0 +1,167 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::str::FromStr;
+use anyhow::{ anyhow, Result, Context };
+use directories::BaseDirs;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use crate::files::{ self, FileMode, FileOptions };
+
+/// Where to look for a token that we've already been given, and where to
+/// keep the tokens that we obtain.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum TokenSource {
+    /// Use the token that the Vault CLI has (eg from 'vault login').
+    VaultCli,
+    /// Use our own token cache.
+    Cache,
+    /// Don't reuse any token; always login.
+    Prompt
+}
+
+impl std::fmt::Display for TokenSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenSource::VaultCli => write!(f, "vault-cli"),
+            TokenSource::Cache => write!(f, "cache"),
+            TokenSource::Prompt => write!(f, "prompt")
+        }
+    }
+}
+
+impl FromStr for TokenSource {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "vault-cli" => Ok(TokenSource::VaultCli),
+            "cache" => Ok(TokenSource::Cache),
+            "prompt" => Ok(TokenSource::Prompt),
+            _ => Err(anyhow!("'{}' is not a valid token source (try 'vault-cli', 'cache' or 'prompt').", s))
+        }
+    }
+}
+
+/// The Vault CLI's token helper: an external program if one is configured
+/// in the CLI's config file, or else the '~/.vault-token' file.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum TokenHelper {
+    File(PathBuf),
+    Command(PathBuf)
+}
+
+impl TokenHelper {
+
+    /// Find the token helper that the Vault CLI would use.
+    pub async fn from_vault_config() -> Result<TokenHelper> {
+        let home_dir = BaseDirs::new()
+            .ok_or_else(|| anyhow!("Could not find your home directory to look for the Vault CLI's token"))?
+            .home_dir()
+            .to_owned();
+        let config_path = std::env::var_os("VAULT_CONFIG_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home_dir.join(".vault"));
+        let helper = match fs::read_to_string(&config_path).await {
+            Ok(config) => parse_token_helper(&config),
+            Err(_) => None
+        };
+        Ok(match helper {
+            Some(helper) => TokenHelper::Command(helper),
+            None => TokenHelper::File(home_dir.join(".vault-token"))
+        })
+    }
+
+    /// Get the token, if there is one.
+    pub async fn get(&self) -> Result<Option<String>> {
+        let token = match self {
+            TokenHelper::File(path) => match fs::read_to_string(path).await {
+                Ok(token) => token,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e).with_context(|| format!("Failed to read the Vault token from '{}'", path.display()))
+            },
+            TokenHelper::Command(path) => {
+                let output = Command::new(path)
+                    .arg("get")
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .await
+                    .with_context(|| format!("Failed to run the Vault token helper '{}'", path.display()))?;
+                if !output.status.success() {
+                    return Err(anyhow!("The Vault token helper '{}' failed to get the token ({})", path.display(), output.status));
+                }
+                String::from_utf8(output.stdout)
+                    .with_context(|| format!("The Vault token helper '{}' returned an invalid token", path.display()))?
+            }
+        };
+        let token = token.trim();
+        Ok(if token.is_empty() { None } else { Some(token.to_owned()) })
+    }
+
+    /// Store a token, replacing any existing one.
+    pub async fn store(&self, token: &str) -> Result<()> {
+        match self {
+            TokenHelper::File(path) => {
+                let file_opts = FileOptions { mode: Some(FileMode(0o600)), owner: None };
+                files::write_file(path, token.as_bytes(), &file_opts)
+                    .await
+                    .with_context(|| format!("Failed to write the Vault token to '{}'", path.display()))?;
+            },
+            TokenHelper::Command(path) => {
+                let mut child = Command::new(path)
+                    .arg("store")
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("Failed to run the Vault token helper '{}'", path.display()))?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(token.as_bytes()).await?;
+                }
+                let status = child.await?;
+                if !status.success() {
+                    return Err(anyhow!("The Vault token helper '{}' failed to store the token ({})", path.display(), status));
+                }
+            }
+        }
+        Ok(())
+    }
+
+}
+
+/// Find the 'token_helper' setting in a Vault CLI (HCL) config file.
+fn parse_token_helper(config: &str) -> Option<PathBuf> {
+    // The last setting wins, so look from the end:
+    config.lines()
+        .rev()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with('#') && !line.starts_with("//"))
+        .filter_map(|line| {
+            let idx = line.find('=')?;
+            if line[..idx].trim() != "token_helper" {
+                return None
+            }
+            let value = line[idx+1..].trim().trim_matches('"').trim();
+            if value.is_empty() { None } else { Some(PathBuf::from(value)) }
+        })
+        .next()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parse_token_helpers() {
+        let cases = vec![
+            ("", None),
+            ("token_helper = \"/usr/local/bin/helper\"", Some("/usr/local/bin/helper")),
+            ("  token_helper=\"/opt/helper\"\n", Some("/opt/helper")),
+            ("# token_helper = \"/commented/out\"", None),
+            ("other_helper = \"/not/this\"\ntoken_helper = \"/this\"", Some("/this")),
+            ("token_helper = \"\"", None),
+        ];
+        for (config, expected) in cases {
+            assert_eq!(parse_token_helper(config), expected.map(PathBuf::from), "Unexpected helper for {:?}", config);
+        }
+    }
+
+}
@@ -0,0 +1,219 @@
+use anyhow::{ anyhow, Result, Context };
+use reqwest::{ Method, StatusCode, header::HeaderMap };
+use url::Url;
+
+/// How requests get to Vault: over TCP (and TLS, if the URL asks for it)
+/// using reqwest, or over a unix socket such as a Vault agent's listener.
+#[derive(Clone)]
+pub struct Transport {
+    base_url: Url,
+    inner: Inner
+}
+
+#[derive(Clone)]
+enum Inner {
+    Http(reqwest::Client),
+    #[cfg(unix)]
+    Unix(hyper::Client<unix::UnixConnector>)
+}
+
+impl Transport {
+
+    /// Talk to each of the Vault URLs given. 'unix://' URLs point at a socket to send
+    /// requests over; the rest share a reqwest client built from the builder.
+    pub fn for_urls(vault_urls: Vec<Url>, builder: reqwest::ClientBuilder) -> Result<Vec<Transport>> {
+        let client = match vault_urls.iter().any(|url| url.scheme() != "unix") {
+            // We follow redirects ourselves, since reqwest would drop the token and body:
+            true => Some(builder
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .context("Failed to configure the HTTP client")?),
+            false => None
+        };
+        vault_urls.into_iter().map(|vault_url| match &client {
+            Some(client) if vault_url.scheme() != "unix" => {
+                Ok(Transport { base_url: vault_url, inner: Inner::Http(client.clone()) })
+            },
+            _ => Transport::unix(&vault_url)
+        }).collect()
+    }
+
+    #[cfg(unix)]
+    fn unix(vault_url: &Url) -> Result<Transport> {
+        let socket = vault_url.path();
+        if socket.is_empty() || socket == "/" {
+            return Err(anyhow!("'{}' does not give the path to a unix socket (eg 'unix:///run/vault/agent.sock')", vault_url));
+        }
+        let client = hyper::Client::builder().build(unix::UnixConnector::new(socket.into()));
+        // Requests need a host, but it's ignored once we're connected:
+        let base_url = "http://localhost".parse().expect("valid URL");
+        Ok(Transport { base_url, inner: Inner::Unix(client) })
+    }
+
+    #[cfg(not(unix))]
+    fn unix(vault_url: &Url) -> Result<Transport> {
+        Err(anyhow!("Cannot connect to '{}': unix sockets are not supported on this platform", vault_url))
+    }
+
+    /// The URL that API paths are relative to.
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Send a request, with a JSON body if one is given.
+    pub async fn send(&self, method: Method, url: Url, headers: &[(&str, String)], body: Option<Vec<u8>>) -> Result<Response> {
+        match &self.inner {
+            Inner::Http(client) => {
+                let mut builder = client.request(method, url);
+                for (name, value) in headers {
+                    builder = builder.header(*name, value);
+                }
+                if let Some(body) = body {
+                    builder = builder.header("Content-Type", "application/json").body(body);
+                }
+                Ok(Response::Http(builder.send().await?))
+            },
+            #[cfg(unix)]
+            Inner::Unix(client) => {
+                let mut builder = hyper::Request::builder()
+                    .method(method)
+                    .uri(url.as_str());
+                for (name, value) in headers {
+                    builder = builder.header(*name, value);
+                }
+                let req = match body {
+                    Some(body) => builder.header("Content-Type", "application/json").body(body.into())?,
+                    None => builder.body(hyper::Body::empty())?
+                };
+                Ok(Response::Unix(client.request(req).await?))
+            }
+        }
+    }
+
+}
+
+/// A response from Vault, whichever way it got to us.
+pub enum Response {
+    Http(reqwest::Response),
+    #[cfg(unix)]
+    Unix(hyper::Response<hyper::Body>)
+}
+
+impl Response {
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Response::Http(res) => res.status(),
+            #[cfg(unix)]
+            Response::Unix(res) => res.status()
+        }
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        match self {
+            Response::Http(res) => res.headers(),
+            #[cfg(unix)]
+            Response::Unix(res) => res.headers()
+        }
+    }
+
+    /// The length of the body, if we've been told it up front.
+    pub fn content_length(&self) -> Option<u64> {
+        match self {
+            Response::Http(res) => res.content_length(),
+            #[cfg(unix)]
+            Response::Unix(res) => res.headers()
+                .get(reqwest::header::CONTENT_LENGTH)?
+                .to_str()
+                .ok()?
+                .parse()
+                .ok()
+        }
+    }
+
+    /// The next chunk of the body, or None once it's all been read.
+    pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        match self {
+            Response::Http(res) => Ok(res.chunk().await?.map(|c| c.to_vec())),
+            #[cfg(unix)]
+            Response::Unix(res) => {
+                use hyper::body::HttpBody;
+                match res.body_mut().data().await {
+                    Some(chunk) => Ok(Some(chunk?.to_vec())),
+                    None => Ok(None)
+                }
+            }
+        }
+    }
+
+}
+
+#[cfg(unix)]
+mod unix {
+
+    use std::future::Future;
+    use std::io;
+    use std::path::PathBuf;
+    use std::pin::Pin;
+    use std::task::{ Context, Poll };
+    use hyper::client::connect::{ Connected, Connection };
+    use tokio::io::{ AsyncRead, AsyncWrite };
+    use tokio::net::UnixStream;
+
+    /// Hands hyper a connection to the unix socket, whatever the URI.
+    #[derive(Clone)]
+    pub struct UnixConnector {
+        socket: PathBuf
+    }
+
+    impl UnixConnector {
+        pub fn new(socket: PathBuf) -> UnixConnector {
+            UnixConnector { socket }
+        }
+    }
+
+    impl hyper::service::Service<hyper::Uri> for UnixConnector {
+        type Response = UnixConnection;
+        type Error = io::Error;
+        type Future = Pin<Box<dyn Future<Output = io::Result<UnixConnection>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: hyper::Uri) -> Self::Future {
+            let socket = self.socket.clone();
+            Box::pin(async move {
+                UnixStream::connect(&socket).await.map(UnixConnection)
+            })
+        }
+    }
+
+    /// hyper needs its connections to implement [`Connection`].
+    pub struct UnixConnection(UnixStream);
+
+    impl Connection for UnixConnection {
+        fn connected(&self) -> Connected {
+            Connected::new()
+        }
+    }
+
+    impl AsyncRead for UnixConnection {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for UnixConnection {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+        }
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_flush(cx)
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+        }
+    }
+
+}